@@ -0,0 +1,69 @@
+use augr_core::{
+    store::patch::{AddAttribute, RemoveAttribute},
+    EventRef, Patch, Timesheet,
+};
+use snafu::Snafu;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {
+    /// The id of the event to modify
+    event: EventRef,
+
+    /// A list of `key=value` attributes to set on the event. Any previous
+    /// values of the same keys are replaced.
+    #[structopt(required = true, parse(try_from_str = parse_key_value))]
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown event reference: {}", event_ref))]
+    UnknownEventRef { event_ref: EventRef },
+}
+impl Cmd {
+    pub fn exec(&self, timesheet: &Timesheet) -> Result<Vec<Patch>, Error> {
+        let event = timesheet
+            .get_patched_timesheet()
+            .events
+            .get(&self.event)
+            .filter(|event| !event.is_removed())
+            .ok_or(Error::UnknownEventRef {
+                event_ref: self.event.clone(),
+            })?;
+        let parent_patches = event.latest_patches();
+        let mut patch = Patch::new();
+        for (patch_ref, key, value) in event.attributes() {
+            if self.attributes.iter().any(|(k, _v)| *k == key) {
+                patch.insert_remove_attribute(RemoveAttribute {
+                    parents: Some(parent_patches.clone()),
+                    patch: patch_ref,
+                    event: self.event.clone(),
+                    key,
+                    value,
+                });
+            }
+        }
+        for (key, value) in self.attributes.iter().cloned() {
+            patch.insert_add_attribute(AddAttribute {
+                parents: parent_patches.clone(),
+                event: self.event.clone(),
+                key,
+                value,
+            });
+        }
+        Ok(vec![patch])
+    }
+}
+
+/// Parses an attribute written as `key=value`
+pub fn parse_key_value(text: &str) -> Result<(String, String), String> {
+    let mut parts = text.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(
+            "Expected an attribute like `key=value`, found {}",
+            text
+        )),
+    }
+}
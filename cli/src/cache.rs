@@ -0,0 +1,164 @@
+use augr_core::{repository::snapshot::Snapshot, Repository, Store};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+use std::{
+    fs::{create_dir_all, read_to_string, remove_file, rename, write},
+    io,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+/// Starts the first line of a snapshot file, followed by the hash of the rest
+/// of the file. A file that was cut short no longer matches its hash.
+const CHECKSUM_PREFIX: &str = "# sha256 ";
+
+/// A snapshot of the repository kept outside of the sync folder, so that it
+/// is never shared with other devices
+pub struct SnapshotCache {
+    path: PathBuf,
+
+    /// The snapshot that is currently on disk
+    snapshot: Option<Snapshot>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to serialize snapshot: {}", source))]
+    SerializeSnapshot { source: toml::ser::Error },
+
+    #[snafu(display("Unable to write snapshot to {}: {}", path.display(), source))]
+    WriteSnapshot { source: io::Error, path: PathBuf },
+}
+
+/// The default location of the snapshot for a sync folder and device. The
+/// sync folder is part of the name so that switching between sync folders
+/// doesn't invalidate the snapshot each time. The name is a SHA-256 hash of
+/// the folder's path, so that it stays the same across builds of augr.
+pub fn default_path(sync_folder: &Path, device_id: &str) -> Option<PathBuf> {
+    let proj_dirs = directories::ProjectDirs::from("xyz", "geemili", "augr")?;
+    let sync_folder = sync_folder
+        .canonicalize()
+        .unwrap_or_else(|_| sync_folder.to_path_buf());
+    let hash = checksum(&sync_folder.to_string_lossy());
+    Some(
+        proj_dirs
+            .cache_dir()
+            .join(format!("{}-{}.toml", device_id, &hash[..16])),
+    )
+}
+
+impl SnapshotCache {
+    /// Read the snapshot at `path`. A snapshot that is missing, can't be read
+    /// or doesn't match its checksum is treated as a cache miss.
+    pub fn load(path: PathBuf) -> (Self, Option<Snapshot>) {
+        let snapshot: Option<Snapshot> = read_to_string(&path).ok().and_then(|contents| {
+            let newline = contents.find('\n')?;
+            let (header, contents) = (&contents[..newline], &contents[newline + 1..]);
+            if header != format!("{}{}", CHECKSUM_PREFIX, checksum(contents)) {
+                return None;
+            }
+            toml::from_str(contents).ok()
+        });
+        (
+            Self {
+                path,
+                snapshot: snapshot.clone(),
+            },
+            snapshot,
+        )
+    }
+
+    /// Write a snapshot of the repository, unless the one on disk is already
+    /// up to date
+    pub fn save<S: Store>(&mut self, repo: &Repository<S>) -> Result<(), Error>
+    where
+        <S as Store>::Error: 'static,
+    {
+        let snapshot = repo.snapshot();
+        if self.snapshot.as_ref() == Some(&snapshot) {
+            return Ok(());
+        }
+
+        let contents = toml::to_string(&snapshot).context(SerializeSnapshot {})?;
+        let contents = format!("{}{}\n{}", CHECKSUM_PREFIX, checksum(&contents), contents);
+        write_atomically(&self.path, &contents).context(WriteSnapshot { path: &self.path })?;
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+}
+
+fn checksum(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Write to a temporary file next to `path`, and rename it into place, so
+/// that a crash or another augr process never leaves half a snapshot behind
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let folder = path.parent().unwrap_or_else(|| Path::new("."));
+    create_dir_all(folder)?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = folder.join(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+
+    let result = write(&temp_path, contents).and_then(|()| rename(&temp_path, path));
+    if result.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{event, TempFolder};
+    use augr_core::store::MemoryStore;
+
+    fn saved_cache(folder: &TempFolder) -> (PathBuf, Snapshot) {
+        let mut repo = Repository::from_store(MemoryStore::new("laptop".to_string())).unwrap();
+        repo.add_patch(event("a", 12)).unwrap();
+        repo.add_patch(event("b", 13)).unwrap();
+
+        let path = folder.0.join("laptop.toml");
+        let (mut cache, snapshot) = SnapshotCache::load(path.clone());
+        assert_eq!(snapshot, None);
+        cache.save(&repo).unwrap();
+        (path, repo.snapshot())
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let folder = TempFolder::new();
+        let (path, snapshot) = saved_cache(&folder);
+
+        assert_eq!(SnapshotCache::load(path).1, Some(snapshot));
+    }
+
+    #[test]
+    fn default_path_is_stable() {
+        let path = default_path(Path::new("/nonexistent/augr"), "laptop").unwrap();
+        assert_eq!(
+            path.file_name().unwrap().to_string_lossy(),
+            "laptop-7bb5b77aa4d94c0e.toml"
+        );
+    }
+
+    #[test]
+    fn truncated_snapshot_is_a_cache_miss() {
+        let folder = TempFolder::new();
+        let (path, _snapshot) = saved_cache(&folder);
+
+        // Cut the file off before the second event, which still parses
+        let contents = read_to_string(&path).unwrap();
+        let end = contents.find("\n[events.b]").unwrap();
+        let header_end = contents.find('\n').unwrap();
+        assert!(toml::from_str::<Snapshot>(&contents[header_end + 1..end]).is_ok());
+        write(&path, &contents[..end]).unwrap();
+
+        assert_eq!(SnapshotCache::load(path).1, None);
+    }
+}
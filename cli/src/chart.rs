@@ -1,4 +1,5 @@
-use augr_core::{Tag, Timesheet};
+use crate::attr::parse_key_value;
+use augr_core::{timesheet::has_attribute, Tag, Timesheet};
 use chrono::{offset::TimeZone, Local, NaiveDate, Utc};
 use std::collections::BTreeSet;
 use structopt::StructOpt;
@@ -9,6 +10,10 @@ pub struct Cmd {
     /// A list of tags to filter against
     tags: Vec<String>,
 
+    /// Only chart events with the given `key=value` attribute. May be repeated.
+    #[structopt(long = "attr", number_of_values = 1, parse(try_from_str = parse_key_value))]
+    attributes: Vec<(String, String)>,
+
     /// The date to start charting from. Defaults to 7 days ago.
     #[structopt(long = "start")]
     start: Option<NaiveDate>,
@@ -50,6 +55,15 @@ impl Cmd {
                 let matches = cur_tags
                     .map(|x| tags.is_subset(&x) && !x.is_empty())
                     .unwrap_or(false);
+                let matches = matches
+                    && timesheet
+                        .attributes_at_time(&cur_datetime.with_timezone(&Utc))
+                        .map(|x| {
+                            self.attributes
+                                .iter()
+                                .all(|(key, value)| has_attribute(&x, key, value))
+                        })
+                        .unwrap_or(false);
 
                 // Avoid highlighting the entire day
                 let in_past = cur_datetime <= now;
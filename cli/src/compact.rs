@@ -0,0 +1,51 @@
+use augr_core::{repository::Error as RepositoryError, Patch, Repository, Store, SyncStore};
+use snafu::Snafu;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to retire patches: {}", errors.join("; ")))]
+    Retire { errors: Vec<String> },
+
+    #[snafu(display("Unable to create checkpoint: {}", errors.join("; ")))]
+    Checkpoint { errors: Vec<String> },
+}
+
+impl Cmd {
+    /// Retires patches that every device has moved past, and returns a new
+    /// checkpoint if there are patches to fold
+    pub fn exec<S>(&self, repo: &mut Repository<S>) -> Result<Option<Patch>, Error>
+    where
+        S: SyncStore,
+        <S as Store>::Error: 'static,
+    {
+        let retired = repo.retire_patches().map_err(|errors| Error::Retire {
+            errors: to_strings(errors),
+        })?;
+        if !retired.is_empty() {
+            println!("Retired {} patches", retired.len());
+        }
+
+        let checkpoint = repo.checkpoint().map_err(|errors| Error::Checkpoint {
+            errors: to_strings(errors),
+        })?;
+        match &checkpoint {
+            Some(patch) => {
+                let folded = patch.checkpoint.as_ref().map_or(0, |c| c.patches().len());
+                println!(
+                    "Created a checkpoint folding {} patches. They will be retired once every device has synchronized.",
+                    folded
+                );
+            }
+            None => println!("No new patches have been seen by every device"),
+        }
+        Ok(checkpoint)
+    }
+}
+
+fn to_strings<E: std::error::Error>(errors: Vec<RepositoryError<E>>) -> Vec<String> {
+    errors.iter().map(ToString::to_string).collect()
+}
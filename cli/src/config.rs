@@ -10,6 +10,49 @@ use std::{
 pub struct Conf {
     pub sync_folder: PathBuf,
     pub device_id: String,
+
+    /// How patches and metas are kept
+    #[serde(default)]
+    pub store: StoreKind,
+
+    /// The database used by the `sqlite` store. Defaults to `augr.sqlite` in
+    /// the user's data directory, since a database that is synced while it is
+    /// open can be corrupted
+    #[serde(default)]
+    pub database: Option<PathBuf>,
+
+    /// Automatically pick a winner when concurrent edits give an event more
+    /// than one start time, instead of refusing to run
+    #[serde(default)]
+    pub resolve_start_conflicts: bool,
+
+    /// Where to keep a snapshot of the repository that speeds up loading.
+    /// Defaults to a file in the user's cache directory.
+    #[serde(default)]
+    pub snapshot_cache: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreKind {
+    /// A TOML file for each patch and meta in the sync folder
+    #[default]
+    SyncFolder,
+
+    /// A single SQLite database. Requires augr to be built with the `sqlite`
+    /// feature
+    Sqlite,
+}
+
+impl Conf {
+    /// The database used by the `sqlite` store, if it is set or the user has
+    /// a data directory
+    pub fn database(&self) -> Option<PathBuf> {
+        self.database.clone().or_else(|| {
+            let proj_dirs = directories::ProjectDirs::from("xyz", "geemili", "augr")?;
+            Some(proj_dirs.data_dir().join("augr.sqlite"))
+        })
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -0,0 +1,765 @@
+use crate::store::CliStore;
+use augr_core::{
+    repository::{timesheet::Error as Conflict, Error as RepositoryError},
+    Meta, Patch, PatchRef, Repository, Store,
+};
+use snafu::{ResultExt, Snafu};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error as StdError,
+    fmt, fs, io,
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {
+    /// Fix the problems that can be fixed without losing data. Patches that
+    /// no meta lists are added to this device's meta, and temporary files
+    /// left behind by interrupted writes are deleted; everything else is
+    /// only reported
+    #[structopt(long = "repair")]
+    repair: bool,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to read the store: {}", source))]
+    ReadStore { source: Box<dyn StdError> },
+
+    #[snafu(display("Unable to repair the store: {}", source))]
+    Repair { source: Box<dyn StdError> },
+
+    #[snafu(display("Unable to remove {}: {}", path.display(), source))]
+    RemoveFile { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The store has problems that weren't repaired"))]
+    ProblemsLeft,
+}
+
+/// Something wrong with the patches or metas in a store
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// A patch that couldn't be read, because it doesn't parse or doesn't
+    /// match its content-addressed id
+    UnreadablePatch { patch: PatchRef, error: String },
+
+    /// A patch that is stored under a different id than its own
+    MismatchedId { stored: PatchRef, id: PatchRef },
+
+    /// A meta that couldn't be read
+    UnreadableMeta { device: String, error: String },
+
+    /// A patch that a device lists in its meta, but that isn't in the store
+    MissingPatch { device: String, patch: PatchRef },
+
+    /// A patch that no meta lists, either directly or as an ancestor of a
+    /// listed patch
+    OrphanPatch { patch: PatchRef },
+
+    /// A patch that depends on a patch that isn't in the store
+    DanglingParent { patch: PatchRef, parent: PatchRef },
+
+    /// Patches that depend on each other
+    PatchCycle { patches: Vec<PatchRef> },
+
+    /// A patch that conflicts with the patches it was applied on top of
+    InapplicablePatch {
+        patch: PatchRef,
+        conflicts: Vec<Conflict>,
+    },
+
+    /// An event that can't be flattened, or two events that start at the
+    /// same time
+    Conflict(Conflict),
+
+    /// A temporary file left behind when augr was stopped partway through
+    /// writing a patch
+    StaleTemporaryFile { path: PathBuf },
+
+    /// A copy of a patch that Syncthing made, which isn't read
+    PatchConflictCopy { path: PathBuf },
+
+    /// A file in the patch folder that isn't named after a patch, which isn't
+    /// read
+    UnrecognisedFile { path: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnreadablePatch { patch, error } => {
+                write!(f, "Patch {} can't be read: {}", patch, error)
+            }
+            Problem::MismatchedId { stored, id } => {
+                write!(f, "Patch {} is stored under the id {}", id, stored)
+            }
+            Problem::UnreadableMeta { device, error } => {
+                write!(f, "The meta of device {} can't be read: {}", device, error)
+            }
+            Problem::MissingPatch { device, patch } => write!(
+                f,
+                "Device {} lists patch {}, which is missing",
+                device, patch
+            ),
+            Problem::OrphanPatch { patch } => {
+                write!(f, "Patch {} isn't listed in any meta", patch)
+            }
+            Problem::DanglingParent { patch, parent } => write!(
+                f,
+                "Patch {} depends on patch {}, which is missing",
+                patch, parent
+            ),
+            Problem::PatchCycle { patches } => write!(
+                f,
+                "Patches depend on each other in a cycle: {}",
+                join(patches)
+            ),
+            Problem::InapplicablePatch { patch, conflicts } => write!(
+                f,
+                "Patch {} can't be applied to the timesheet: {}",
+                patch,
+                join(conflicts)
+            ),
+            Problem::Conflict(conflict) => write!(f, "{}", conflict),
+            Problem::StaleTemporaryFile { path } => write!(
+                f,
+                "Temporary file {} was left behind by an interrupted write",
+                path.display()
+            ),
+            Problem::PatchConflictCopy { path } => write!(
+                f,
+                "Patch file {} is a conflict copy made by Syncthing, and isn't read",
+                path.display()
+            ),
+            Problem::UnrecognisedFile { path } => write!(
+                f,
+                "File {} isn't named after a patch, and isn't read",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Cmd {
+    pub fn exec<S>(&self, store: S, device_id: &str) -> Result<(), Error>
+    where
+        S: CliStore,
+        <S as Store>::Error: 'static,
+    {
+        let mut problems = check_store(&store, device_id)?;
+        let orphans = orphans(&problems);
+        let (timesheet_problems, mut store) = check_timesheet(store, &orphans)?;
+        problems.extend(timesheet_problems);
+        let (repaired, removed) = if self.repair {
+            (
+                repair(&mut store, &problems)?,
+                remove_stale_files(&problems)?,
+            )
+        } else {
+            (BTreeSet::new(), BTreeSet::new())
+        };
+
+        for problem in problems.iter() {
+            println!("{}", problem);
+        }
+        match problems.len() {
+            0 => println!("No problems found"),
+            1 => println!("Found 1 problem"),
+            count => println!("Found {} problems", count),
+        }
+        match repaired.len() {
+            0 => {}
+            1 => println!("Added 1 orphan patch to the meta of this device"),
+            count => println!("Added {} orphan patches to the meta of this device", count),
+        }
+        match removed.len() {
+            0 => {}
+            1 => println!("Removed 1 temporary file"),
+            count => println!("Removed {} temporary files", count),
+        }
+        if !self.repair && problems.iter().any(is_repairable) {
+            println!(
+                "Run `augr fsck --repair` to add orphan patches to the meta of this device \
+                 and remove stale temporary files"
+            );
+        }
+
+        let is_repaired = |problem: &Problem| match problem {
+            Problem::OrphanPatch { patch } => repaired.contains(patch),
+            Problem::StaleTemporaryFile { path } => removed.contains(path),
+            _ => false,
+        };
+        if problems.iter().all(is_repaired) {
+            Ok(())
+        } else {
+            Err(Error::ProblemsLeft)
+        }
+    }
+}
+
+/// Read every patch and meta in the store, and check that they fit together
+fn check_store<S>(store: &S, device_id: &str) -> Result<Vec<Problem>, Error>
+where
+    S: CliStore,
+    <S as Store>::Error: 'static,
+{
+    let mut problems = Vec::new();
+
+    // Files that aren't patches are skipped when the patches are read
+    let report = store.patch_folder_report().map_err(|e| Error::ReadStore {
+        source: Box::new(e),
+    })?;
+    problems.extend(
+        report
+            .stale_temporary
+            .into_iter()
+            .map(|path| Problem::StaleTemporaryFile { path }),
+    );
+    problems.extend(
+        report
+            .conflict_copies
+            .into_iter()
+            .map(|path| Problem::PatchConflictCopy { path }),
+    );
+    problems.extend(
+        report
+            .unrecognised
+            .into_iter()
+            .map(|path| Problem::UnrecognisedFile { path }),
+    );
+
+    let patch_refs = store.patch_refs().map_err(|e| Error::ReadStore {
+        source: Box::new(e),
+    })?;
+    let mut patches: BTreeMap<PatchRef, Patch> = BTreeMap::new();
+    for patch_ref in patch_refs.iter() {
+        match store.get_patch(patch_ref) {
+            Ok(patch) if patch.patch_ref() != patch_ref => problems.push(Problem::MismatchedId {
+                stored: *patch_ref,
+                id: *patch.patch_ref(),
+            }),
+            Ok(patch) => {
+                patches.insert(*patch_ref, patch);
+            }
+            Err(e) => problems.push(Problem::UnreadablePatch {
+                patch: *patch_ref,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    // Checkpoints fold their patches together, and the folded patches are
+    // deleted once every device has seen the checkpoint
+    let folded: BTreeSet<PatchRef> = patches
+        .values()
+        .filter_map(|patch| patch.checkpoint.as_ref())
+        .flat_map(|checkpoint| checkpoint.patches().iter().cloned())
+        .collect();
+    let exists =
+        |patch_ref: &PatchRef| patch_refs.contains(patch_ref) || folded.contains(patch_ref);
+
+    let mut metas: BTreeMap<String, Meta> = BTreeMap::new();
+    let mut read_meta = |device: String, meta: Result<Meta, <S as Store>::Error>| match meta {
+        Ok(meta) => {
+            metas.insert(device, meta);
+        }
+        Err(e) => problems.push(Problem::UnreadableMeta {
+            device,
+            error: e.to_string(),
+        }),
+    };
+    let other_metas = store.get_other_metas().map_err(|e| Error::ReadStore {
+        source: Box::new(e),
+    })?;
+    for (device, meta) in other_metas {
+        read_meta(device, meta);
+    }
+    read_meta(device_id.to_string(), store.get_meta());
+
+    for (device, meta) in metas.iter() {
+        for patch_ref in meta.patches() {
+            if !exists(patch_ref) {
+                problems.push(Problem::MissingPatch {
+                    device: device.clone(),
+                    patch: *patch_ref,
+                });
+            }
+        }
+    }
+
+    for (patch_ref, patch) in patches.iter() {
+        for parent in patch.parents() {
+            if !exists(&parent) {
+                problems.push(Problem::DanglingParent {
+                    patch: *patch_ref,
+                    parent,
+                });
+            }
+        }
+    }
+
+    // A patch is used if a meta lists it or one of its descendants
+    let mut used = BTreeSet::new();
+    let mut to_visit: Vec<PatchRef> = metas
+        .values()
+        .flat_map(|meta| meta.patches().cloned())
+        .collect();
+    while let Some(patch_ref) = to_visit.pop() {
+        if !used.insert(patch_ref) {
+            continue;
+        }
+        if let Some(patch) = patches.get(&patch_ref) {
+            to_visit.extend(patch.parents());
+            if let Some(checkpoint) = &patch.checkpoint {
+                to_visit.extend(checkpoint.patches().iter().cloned());
+            }
+        }
+    }
+    for patch_ref in patches.keys() {
+        if !used.contains(patch_ref) {
+            problems.push(Problem::OrphanPatch { patch: *patch_ref });
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Add the orphan patches to this device's meta. Orphans that can't be
+/// applied, or that descend from a patch that is missing, broken or can't be
+/// applied, are left alone. Only the orphans that no other orphan depends on
+/// are listed, since their ancestors are loaded along with them, and nothing
+/// else in the store changes. Returns the orphans that were added.
+fn repair<S>(store: &mut S, problems: &[Problem]) -> Result<BTreeSet<PatchRef>, Error>
+where
+    S: CliStore,
+    <S as Store>::Error: 'static,
+{
+    let orphans = orphans(problems);
+    let broken: BTreeSet<PatchRef> = problems
+        .iter()
+        .flat_map(|problem| match problem {
+            Problem::UnreadablePatch { patch, .. }
+            | Problem::DanglingParent { patch, .. }
+            | Problem::InapplicablePatch { patch, .. } => vec![*patch],
+            Problem::PatchCycle { patches } => patches.clone(),
+            _ => Vec::new(),
+        })
+        .collect();
+
+    let mut usable = BTreeSet::new();
+    let mut repaired = BTreeSet::new();
+    for orphan in orphans.iter() {
+        if is_usable(store, *orphan, &broken, &mut usable) {
+            repaired.insert(*orphan);
+        }
+    }
+
+    let mut heads = repaired.clone();
+    for orphan in repaired.iter() {
+        let patch = store.get_patch(orphan).map_err(|e| Error::Repair {
+            source: Box::new(e),
+        })?;
+        for parent in patch.parents() {
+            heads.remove(&parent);
+        }
+    }
+
+    if !heads.is_empty() {
+        store
+            .update_meta(|mut meta| {
+                for head in heads.iter() {
+                    meta.add_patch(*head);
+                }
+                meta
+            })
+            .map_err(|e| Error::Repair {
+                source: Box::new(e),
+            })?;
+    }
+    Ok(repaired)
+}
+
+/// Delete the temporary files that interrupted writes left behind. Files that
+/// are already gone count as removed. Returns the files that were removed.
+fn remove_stale_files(problems: &[Problem]) -> Result<BTreeSet<PathBuf>, Error> {
+    let mut removed = BTreeSet::new();
+    for problem in problems {
+        if let Problem::StaleTemporaryFile { path } = problem {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).context(RemoveFile { path });
+                }
+                _ => {
+                    removed.insert(path.clone());
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Whether none of a patch and its ancestors are `broken`. Patches found to
+/// be usable are added to `usable`, so that shared ancestors are only
+/// checked once.
+fn is_usable<S: Store>(
+    store: &S,
+    patch_ref: PatchRef,
+    broken: &BTreeSet<PatchRef>,
+    usable: &mut BTreeSet<PatchRef>,
+) -> bool {
+    let mut visited = BTreeSet::new();
+    let mut to_visit = vec![patch_ref];
+    while let Some(patch_ref) = to_visit.pop() {
+        if broken.contains(&patch_ref) {
+            return false;
+        }
+        if usable.contains(&patch_ref) || !visited.insert(patch_ref) {
+            continue;
+        }
+        // Patches that can't be read have either been folded into a
+        // checkpoint and deleted, or are already in `broken`
+        if let Ok(patch) = store.get_patch(&patch_ref) {
+            to_visit.extend(patch.parents());
+        }
+    }
+    usable.extend(visited);
+    true
+}
+
+/// Load every patch the metas list, and flatten the timesheet they make.
+/// Then apply the orphan patches on top, to find the ones that can't be
+/// applied. Gives back the store.
+fn check_timesheet<S>(store: S, orphans: &BTreeSet<PatchRef>) -> Result<(Vec<Problem>, S), Error>
+where
+    S: CliStore,
+    <S as Store>::Error: 'static,
+{
+    // Orphans that can't be read are already reported by `check_store`
+    let mut orphans: Vec<Patch> = orphans
+        .iter()
+        .filter_map(|patch_ref| store.get_patch(patch_ref).ok())
+        .collect();
+
+    let read_store = |e: RepositoryError<<S as Store>::Error>| Error::ReadStore {
+        source: Box::new(e),
+    };
+    let (mut repo, report) = Repository::from_store_lenient(store).map_err(read_store)?;
+    let mut errors = report.errors;
+    errors.extend(repo.try_sync_data().map_err(read_store)?.into_errors());
+
+    // Patches that can't be read are already reported by `check_store`
+    let mut problems = Vec::new();
+    for error in errors {
+        let problem = match error {
+            RepositoryError::PatchCycle { patches } => Problem::PatchCycle { patches },
+            RepositoryError::PatchingTimesheet { conflicts, patch } => {
+                Problem::InapplicablePatch { patch, conflicts }
+            }
+            _ => continue,
+        };
+        // Devices that share patches run into the same errors
+        if !problems.contains(&problem) {
+            problems.push(problem);
+        }
+    }
+
+    if let Err(conflicts) = repo.timesheet().flatten() {
+        problems.extend(conflicts.into_iter().map(Problem::Conflict));
+    }
+
+    // Orphans are applied once their parents have been. Those whose parents
+    // never are have an ancestor that is missing or can't be applied.
+    loop {
+        let loaded: BTreeSet<PatchRef> = repo.patches_loaded().cloned().collect();
+        let (ready, waiting): (Vec<Patch>, Vec<Patch>) = orphans
+            .into_iter()
+            .partition(|patch| patch.parents().iter().all(|parent| loaded.contains(parent)));
+        if ready.is_empty() {
+            break;
+        }
+        for patch in ready {
+            if let Err(RepositoryError::PatchingTimesheet { conflicts, patch }) =
+                repo.load_patch(patch)
+            {
+                problems.push(Problem::InapplicablePatch { patch, conflicts });
+            }
+        }
+        orphans = waiting;
+    }
+
+    Ok((problems, repo.into_store()))
+}
+
+fn orphans(problems: &[Problem]) -> BTreeSet<PatchRef> {
+    problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::OrphanPatch { patch } => Some(*patch),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_repairable(problem: &Problem) -> bool {
+    matches!(
+        problem,
+        Problem::OrphanPatch { .. } | Problem::StaleTemporaryFile { .. }
+    )
+}
+
+fn join<T: ToString>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{add, event, TempFolder};
+    use augr_core::store::{sync_folder_store::STALE_AFTER, SyncFolderStore};
+    use chrono::{TimeZone, Utc};
+    use std::{fs, time::SystemTime};
+    use uuid::Uuid;
+
+    fn store(folder: &TempFolder, device: &str) -> SyncFolderStore {
+        <SyncFolderStore as CliStore>::open(&folder.0, device.to_string()).unwrap()
+    }
+
+    /// Write a file to `patches/` without going through the store
+    fn write_patch_file(folder: &TempFolder, patch_ref: PatchRef, contents: &str) {
+        let path = folder.0.join("patches").join(format!("{}.toml", patch_ref));
+        fs::write(path, contents).unwrap();
+    }
+
+    fn check(folder: &TempFolder, device: &str) -> Vec<Problem> {
+        let mut problems = check_store(&store(folder, device), device).unwrap();
+        let orphans = orphans(&problems);
+        problems.extend(check_timesheet(store(folder, device), &orphans).unwrap().0);
+        problems
+    }
+
+    #[test]
+    fn healthy_store_has_no_problems() {
+        let folder = TempFolder::new();
+        let patch = event("a", 12);
+        add(&mut store(&folder, "laptop"), &patch);
+        add(
+            &mut store(&folder, "phone"),
+            &Patch::new().add_tag(*patch.patch_ref(), "a".to_string(), "coding".to_string()),
+        );
+
+        assert_eq!(check(&folder, "laptop"), vec![]);
+        let cmd = Cmd { repair: false };
+        assert!(cmd.exec(store(&folder, "laptop"), "laptop").is_ok());
+    }
+
+    #[test]
+    fn broken_patches_and_metas_are_found() {
+        let folder = TempFolder::new();
+        let mut laptop = store(&folder, "laptop");
+        let mut phone = store(&folder, "phone");
+        add(&mut laptop, &event("a", 12));
+
+        let orphan = event("b", 13);
+        laptop.add_patch(&orphan).unwrap();
+
+        let garbage = Uuid::new_v4();
+        write_patch_file(&folder, garbage, "not a patch");
+
+        let misplaced = event("c", 14);
+        let stored = Uuid::new_v4();
+        write_patch_file(&folder, stored, &toml::ser::to_string(&misplaced).unwrap());
+
+        let missing = Uuid::new_v4();
+        phone
+            .update_meta(|mut meta| {
+                meta.add_patch(missing);
+                meta
+            })
+            .unwrap();
+
+        let lost_parent = Uuid::new_v4();
+        let dangling = Patch::new().add_tag(lost_parent, "d".to_string(), "lunch".to_string());
+        add(&mut phone, &dangling);
+
+        let problems = check(&folder, "laptop");
+        assert!(problems.iter().any(|problem| match problem {
+            Problem::UnreadablePatch { patch, .. } => *patch == garbage,
+            _ => false,
+        }));
+        assert!(problems.contains(&Problem::MismatchedId {
+            stored,
+            id: *misplaced.patch_ref(),
+        }));
+        assert!(problems.contains(&Problem::MissingPatch {
+            device: "phone".to_string(),
+            patch: missing,
+        }));
+        assert!(problems.contains(&Problem::DanglingParent {
+            patch: *dangling.patch_ref(),
+            parent: lost_parent,
+        }));
+        assert!(problems.contains(&Problem::OrphanPatch {
+            patch: *orphan.patch_ref(),
+        }));
+        assert_eq!(problems.len(), 5);
+
+        let repaired = repair(&mut laptop, &problems).unwrap();
+        assert_eq!(repaired, Some(*orphan.patch_ref()).into_iter().collect());
+        assert!(laptop
+            .get_meta()
+            .unwrap()
+            .patches()
+            .any(|patch| patch == orphan.patch_ref()));
+        assert!(!check(&folder, "laptop").iter().any(is_repairable));
+
+        // The rest can't be repaired
+        let cmd = Cmd { repair: true };
+        assert!(matches!(
+            cmd.exec(store(&folder, "laptop"), "laptop"),
+            Err(Error::ProblemsLeft)
+        ));
+    }
+
+    #[test]
+    fn stray_files_in_the_patch_folder_are_found() {
+        let folder = TempFolder::new();
+        let patch = event("a", 12);
+        add(&mut store(&folder, "laptop"), &patch);
+
+        let patches = folder.0.join("patches");
+        let conflict_copy = patches.join(format!(
+            "{}.sync-conflict-20190901-120000-ABCDEFG.toml",
+            patch.patch_ref()
+        ));
+        let unrecognised = patches.join("notes.txt");
+        let stale = patches.join(format!(".{}.toml.{}.tmp", Uuid::new_v4(), Uuid::new_v4()));
+        let fresh = patches.join(format!(".{}.toml.{}.tmp", Uuid::new_v4(), Uuid::new_v4()));
+        for path in [&conflict_copy, &unrecognised, &stale, &fresh].iter() {
+            fs::write(path, "").unwrap();
+        }
+        fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(SystemTime::now() - STALE_AFTER * 2)
+            .unwrap();
+
+        // The temporary file that may still be being written is left alone
+        let problems = check(&folder, "laptop");
+        assert_eq!(
+            problems,
+            vec![
+                Problem::StaleTemporaryFile {
+                    path: stale.clone()
+                },
+                Problem::PatchConflictCopy {
+                    path: conflict_copy.clone()
+                },
+                Problem::UnrecognisedFile {
+                    path: unrecognised.clone()
+                },
+            ]
+        );
+
+        let cmd = Cmd { repair: true };
+        assert!(matches!(
+            cmd.exec(store(&folder, "laptop"), "laptop"),
+            Err(Error::ProblemsLeft)
+        ));
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(conflict_copy.exists());
+        assert!(unrecognised.exists());
+    }
+
+    #[test]
+    fn only_orphans_that_apply_are_repaired() {
+        let folder = TempFolder::new();
+        let mut laptop = store(&folder, "laptop");
+        add(&mut laptop, &event("a", 12));
+
+        // Creates event `a` a second time
+        let inapplicable = event("a", 13);
+        let descendant = Patch::new().add_tag(
+            *inapplicable.patch_ref(),
+            "a".to_string(),
+            "lunch".to_string(),
+        );
+        let dangling = Patch::new().add_tag(Uuid::new_v4(), "d".to_string(), "lunch".to_string());
+        let parent = event("b", 14);
+        let child = Patch::new().add_tag(*parent.patch_ref(), "b".to_string(), "lunch".to_string());
+        for patch in [&inapplicable, &descendant, &dangling, &parent, &child].iter() {
+            laptop.add_patch(patch).unwrap();
+        }
+
+        let problems = check(&folder, "laptop");
+        assert!(problems.iter().any(|problem| match problem {
+            Problem::InapplicablePatch { patch, .. } => patch == inapplicable.patch_ref(),
+            _ => false,
+        }));
+
+        let repaired = repair(&mut laptop, &problems).unwrap();
+        assert_eq!(
+            repaired,
+            vec![*parent.patch_ref(), *child.patch_ref()]
+                .into_iter()
+                .collect()
+        );
+        let meta = laptop.get_meta().unwrap();
+        assert!(meta.patches().any(|patch| patch == child.patch_ref()));
+        assert!(!meta.patches().any(|patch| patch == parent.patch_ref()));
+
+        let problems = check(&folder, "laptop");
+        assert_eq!(orphans(&problems).len(), 3);
+        assert!(!problems.iter().any(|problem| matches!(
+            problem,
+            Problem::InapplicablePatch { patch, .. } if patch == child.patch_ref()
+        )));
+    }
+
+    #[test]
+    fn conflicts_are_found() {
+        let folder = TempFolder::new();
+        let mut laptop = store(&folder, "laptop");
+        let mut phone = store(&folder, "phone");
+        let at = |hour| Utc.ymd(2019, 7, 23).and_hms(hour, 0, 0);
+
+        let patch = event("a", 12);
+        add(&mut laptop, &patch);
+        add(&mut laptop, &event("b", 15));
+
+        // Both devices move the start of the same event
+        let a = "a".to_string();
+        add(
+            &mut laptop,
+            &Patch::new()
+                .remove_start(*patch.patch_ref(), a.clone(), at(12))
+                .add_start(*patch.patch_ref(), a.clone(), at(13)),
+        );
+        add(
+            &mut phone,
+            &Patch::new()
+                .remove_start(*patch.patch_ref(), a.clone(), at(12))
+                .add_start(*patch.patch_ref(), a.clone(), at(14)),
+        );
+
+        // Another event is created at the same time as an existing one
+        add(&mut phone, &event("c", 15));
+
+        let problems = check(&folder, "laptop");
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().any(|problem| match problem {
+            Problem::Conflict(Conflict::FlattenEventError { event, .. }) => *event == a,
+            _ => false,
+        }));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            Problem::Conflict(Conflict::DuplicateEventTime { .. })
+        )));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::time_input::parse_default_local;
+use augr_core::{EventRef, Patch, Repository, Store, Tag};
+use chrono::{DateTime, Local, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {
+    /// Only show the history of this event
+    event: Option<EventRef>,
+
+    /// Only show changes to events with this tag. May be repeated.
+    #[structopt(long = "tag", number_of_values = 1)]
+    tags: Vec<String>,
+
+    /// Only show patches created at or after this datetime
+    #[structopt(long = "start", parse(try_from_os_str = parse_default_local))]
+    start: Option<DateTime<Local>>,
+
+    /// Only show patches created at or before this datetime
+    #[structopt(long = "end", parse(try_from_os_str = parse_default_local))]
+    end: Option<DateTime<Local>>,
+}
+
+impl Cmd {
+    pub fn exec<S: Store>(&self, repo: &Repository<S>) -> Result<(), Box<dyn std::error::Error>>
+    where
+        <S as Store>::Error: 'static,
+    {
+        let patches = match &self.event {
+            Some(event_ref) => repo.event_history(event_ref),
+            None => repo.history(),
+        }
+        .map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            format!("Unable to load history: {}", errors.join("; "))
+        })?;
+
+        let tags: BTreeSet<Tag> = self.tags.iter().cloned().collect();
+        let events = &repo.timesheet().events;
+        let has_tags = |event_ref: &EventRef| {
+            events
+                .get(event_ref)
+                .map(|event| {
+                    let event_tags: BTreeSet<Tag> =
+                        event.tags().into_iter().map(|(_ref, tag)| tag).collect();
+                    event_tags.is_superset(&tags)
+                })
+                .unwrap_or(false)
+        };
+
+        for patch in patches.iter() {
+            // Patches that don't record when they were created can't be in
+            // the range
+            if let Some(start) = self.start {
+                if !matches!(patch.created_at, Some(t) if t >= start.with_timezone(&Utc)) {
+                    continue;
+                }
+            }
+            if let Some(end) = self.end {
+                if !matches!(patch.created_at, Some(t) if t <= end.with_timezone(&Utc)) {
+                    continue;
+                }
+            }
+
+            let mut changes: Vec<String> = describe(patch)
+                .into_iter()
+                .filter(|(event_ref, _)| {
+                    self.event.is_none() || self.event.as_ref() == Some(event_ref)
+                })
+                .filter(|(event_ref, _)| has_tags(event_ref))
+                .map(|(_event_ref, change)| change)
+                .collect();
+            // Checkpoints aren't about any single event
+            if let Some(checkpoint) = &patch.checkpoint {
+                if self.event.is_none() && self.tags.is_empty() {
+                    changes.push(format!(
+                        "checkpoint folding {} earlier patches",
+                        checkpoint.patches().len()
+                    ));
+                }
+            }
+            if changes.is_empty() {
+                continue;
+            }
+
+            println!("patch {}", patch.patch_ref());
+            if let Some(device) = &patch.device {
+                println!("Device: {}", device);
+            }
+            if let Some(created_at) = patch.created_at {
+                println!("Date:   {}", format_time(created_at));
+            }
+            println!();
+            for change in changes {
+                println!("    {}", change);
+            }
+            println!();
+        }
+        Ok(())
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+type Times = Vec<DateTime<Utc>>;
+
+/// Describes each operation in a patch, along with the event it changes
+fn describe(patch: &Patch) -> Vec<(EventRef, String)> {
+    let mut changes = Vec::new();
+
+    for create in patch.create_event.iter() {
+        changes.push((
+            create.event.clone(),
+            format!(
+                "created event {} at {} with tags {}",
+                create.event,
+                format_time(create.start),
+                create.tags.join(" ")
+            ),
+        ));
+    }
+
+    // A start that is removed and added to the same event is a move
+    let mut starts: BTreeMap<&EventRef, (Times, Times)> = BTreeMap::new();
+    for remove in patch.remove_start.iter() {
+        starts.entry(&remove.event).or_default().0.push(remove.time);
+    }
+    for add in patch.add_start.iter() {
+        starts.entry(&add.event).or_default().1.push(add.time);
+    }
+    for (event, (mut removed, mut added)) in starts {
+        removed.sort();
+        added.sort();
+        let removed: Vec<String> = removed.into_iter().map(format_time).collect();
+        let added: Vec<String> = added.into_iter().map(format_time).collect();
+        let change = match (removed.is_empty(), added.is_empty()) {
+            (false, false) => format!(
+                "moved start of event {} from {} to {}",
+                event,
+                removed.join(", "),
+                added.join(", ")
+            ),
+            (true, _) => format!("added start {} to event {}", added.join(", "), event),
+            (_, true) => format!("removed start {} from event {}", removed.join(", "), event),
+        };
+        changes.push((event.clone(), change));
+    }
+
+    for add in patch.add_tag.iter() {
+        changes.push((
+            add.event.clone(),
+            format!("added tag {} to event {}", add.tag, add.event),
+        ));
+    }
+    for remove in patch.remove_tag.iter() {
+        changes.push((
+            remove.event.clone(),
+            format!("removed tag {} from event {}", remove.tag, remove.event),
+        ));
+    }
+    for add in patch.add_note.iter() {
+        changes.push((
+            add.event.clone(),
+            format!("added note \"{}\" to event {}", add.note, add.event),
+        ));
+    }
+    for remove in patch.remove_note.iter() {
+        changes.push((
+            remove.event.clone(),
+            format!(
+                "removed note \"{}\" from event {}",
+                remove.note, remove.event
+            ),
+        ));
+    }
+    for add in patch.add_attribute.iter() {
+        changes.push((
+            add.event.clone(),
+            format!("set {}={} on event {}", add.key, add.value, add.event),
+        ));
+    }
+    for remove in patch.remove_attribute.iter() {
+        changes.push((
+            remove.event.clone(),
+            format!(
+                "removed {}={} from event {}",
+                remove.key, remove.value, remove.event
+            ),
+        ));
+    }
+    for remove in patch.remove_event.iter() {
+        changes.push((
+            remove.event.clone(),
+            format!("deleted event {}", remove.event),
+        ));
+    }
+
+    changes.sort();
+    changes
+}
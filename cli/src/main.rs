@@ -2,22 +2,42 @@
 #[macro_use]
 extern crate flamer;
 
+mod attr;
+mod cache;
 mod chart;
+mod compact;
 mod config;
+mod delete;
+mod fsck;
 mod import;
+mod log;
+mod migrate;
+mod note;
+mod resolve;
 mod set_start;
 mod start;
+mod store;
 mod summary;
 mod tag;
 mod tags;
+#[cfg(test)]
+mod test_util;
 mod time_input;
+mod undo;
 
 use augr_core::{
-    repository::{timesheet::Error as Conflict, Error as RepositoryError, Repository},
-    store::{SyncFolderStore, SyncFolderStoreError},
+    repository::{
+        event::ResolutionPolicy, snapshot::Snapshot, timesheet::Error as Conflict,
+        Error as RepositoryError, Repository,
+    },
+    store::SyncFolderStore,
+    Patch, PatchRef, Store,
 };
+use chrono::Utc;
+use config::StoreKind;
 use snafu::{ErrorCompat, ResultExt, Snafu};
-use std::path::PathBuf;
+use std::{collections::BTreeSet, path::PathBuf};
+use store::CliStore;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -53,13 +73,49 @@ enum Command {
     #[structopt(no_version, name = "tag")]
     Tag(tag::Cmd),
 
+    /// Set `key=value` attributes on an existing event
+    #[structopt(no_version, name = "attr")]
+    Attr(attr::Cmd),
+
+    /// Attach a note to an existing event
+    #[structopt(no_version, name = "note")]
+    Note(note::Cmd),
+
     /// Change when an event started
     #[structopt(no_version, name = "set-start")]
     SetStart(set_start::Cmd),
 
+    /// Delete an existing event
+    #[structopt(no_version, name = "delete")]
+    Delete(delete::Cmd),
+
+    /// Show the history of changes to the timesheet or to a single event
+    #[structopt(no_version, name = "log")]
+    Log(log::Cmd),
+
+    /// Interactively fix conflicts caused by concurrent edits
+    #[structopt(no_version, name = "resolve")]
+    Resolve(resolve::Cmd),
+
+    /// Revert the changes made by a patch; defaults to the latest patch from this device
+    #[structopt(no_version, name = "undo")]
+    Undo(undo::Cmd),
+
+    /// Fold patches every device has seen into a checkpoint, and delete old patches
+    #[structopt(no_version, name = "compact")]
+    Compact(compact::Cmd),
+
+    /// Check the store for broken, missing and orphan patches, and for conflicts
+    #[structopt(no_version, name = "fsck")]
+    Fsck(fsck::Cmd),
+
     /// Import data from version 0.1 of augr
     #[structopt(no_version, name = "import")]
     Import(import::ImportCmd),
+
+    /// Copy every patch and meta from one store to another
+    #[structopt(no_version, name = "migrate")]
+    Migrate(migrate::Cmd),
 }
 
 #[derive(Debug, Snafu)]
@@ -67,12 +123,13 @@ pub enum Error {
     #[snafu(display("Error getting config: {}", source))]
     GetConfig { source: config::Error },
 
-    #[snafu(display("Errors reading repository: {:?}", errors))]
-    ReadRepository {
-        errors: Vec<RepositoryError<SyncFolderStoreError>>,
-    },
+    #[snafu(display("Error reading repository: {}", source))]
+    ReadRepository { source: Box<dyn std::error::Error> },
 
-    #[snafu(display("Conflicts while merging patches: {:?}", conflicts))]
+    #[snafu(display(
+        "Conflicts while merging patches:\n{}\nRun `augr resolve` to fix them",
+        format_conflicts(conflicts)
+    ))]
     MergeConflicts { conflicts: Vec<Conflict> },
 
     #[snafu(display("Error importing data: {}", source))]
@@ -80,9 +137,34 @@ pub enum Error {
 
     #[snafu(display("Errors synchronizing data: {:?}", errors))]
     SyncError {
-        errors: Vec<RepositoryError<SyncFolderStoreError>>,
+        errors: Vec<Box<dyn std::error::Error>>,
     },
 
+    #[snafu(display("{}", source))]
+    SaveMeta { source: Box<dyn std::error::Error> },
+
+    #[snafu(display("Error opening store: {}", source))]
+    OpenStore { source: Box<dyn std::error::Error> },
+
+    #[snafu(display(
+        "The {} store requires augr to be built with the `{}` feature",
+        store,
+        feature
+    ))]
+    UnsupportedStore {
+        store: &'static str,
+        feature: &'static str,
+    },
+
+    #[snafu(display("No database was found for the sqlite store; set the `database` option"))]
+    NoDatabase,
+
+    #[snafu(display("Error migrating: {}", source))]
+    MigrateError { source: migrate::Error },
+
+    #[snafu(display("Error checking the store: {}", source))]
+    FsckError { source: fsck::Error },
+
     #[snafu(display("Error: {}", source))]
     GeneralError { source: Box<dyn std::error::Error> },
 }
@@ -95,6 +177,7 @@ fn main() {
             if let Some(backtrace) = ErrorCompat::backtrace(&e) {
                 eprintln!("{}", backtrace);
             }
+            std::process::exit(1);
         }
     }
 }
@@ -102,6 +185,11 @@ fn main() {
 fn run() -> Result<(), Error> {
     let opt = Opt::from_args();
 
+    // Migrating names its stores explicitly, instead of using the config
+    if let Some(Command::Migrate(subcmd)) = &opt.cmd {
+        return subcmd.exec().context(MigrateError {});
+    }
+
     // Load config
     let conf_file = match opt.config {
         Some(config_path) => config_path,
@@ -116,8 +204,98 @@ fn run() -> Result<(), Error> {
     #[cfg(feature = "flame_it")]
     flame::start("load repository");
 
-    let store = SyncFolderStore::new(conf.sync_folder, conf.device_id).should_init(true);
-    let mut repo = Repository::from_store(store).unwrap();
+    let policy = if conf.resolve_start_conflicts {
+        ResolutionPolicy::Deterministic
+    } else {
+        ResolutionPolicy::Strict
+    };
+
+    // Snapshots are kept separately for each store
+    let store_location = match conf.store {
+        StoreKind::SyncFolder => conf.sync_folder.clone(),
+        StoreKind::Sqlite => conf.database().ok_or(Error::NoDatabase)?,
+    };
+    let snapshot_path = conf
+        .snapshot_cache
+        .clone()
+        .or_else(|| cache::default_path(&store_location, &conf.device_id));
+    let (snapshot_cache, snapshot) = match snapshot_path {
+        Some(path) => {
+            let (cache, snapshot) = cache::SnapshotCache::load(path);
+            (Some(cache), snapshot)
+        }
+        None => (None, None),
+    };
+
+    let cmd = opt.cmd.unwrap_or_default();
+    match conf.store {
+        StoreKind::SyncFolder => {
+            let store =
+                SyncFolderStore::new(conf.sync_folder, conf.device_id.clone()).should_init(true);
+            report_meta_folder(&store);
+            run_with_store(
+                store,
+                snapshot,
+                snapshot_cache,
+                cmd,
+                &conf.device_id,
+                policy,
+            )
+        }
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => {
+            let store = <augr_core::store::SqliteStore as CliStore>::open(
+                &store_location,
+                conf.device_id.clone(),
+            )
+            .context(OpenStore {})?;
+            run_with_store(
+                store,
+                snapshot,
+                snapshot_cache,
+                cmd,
+                &conf.device_id,
+                policy,
+            )
+        }
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => Err(Error::UnsupportedStore {
+            store: "sqlite",
+            feature: "sqlite",
+        }),
+    }
+}
+
+fn run_with_store<S: CliStore>(
+    store: S,
+    snapshot: Option<Snapshot>,
+    mut snapshot_cache: Option<cache::SnapshotCache>,
+    cmd: Command,
+    device_id: &str,
+    policy: ResolutionPolicy,
+) -> Result<(), Error>
+where
+    <S as Store>::Error: 'static,
+{
+    // Checking the store reads it directly, so that patches the repository
+    // would skip are reported too
+    if let Command::Fsck(subcmd) = &cmd {
+        return subcmd.exec(store, device_id).context(FsckError {});
+    }
+
+    let (mut repo, skipped) = match snapshot {
+        Some(snapshot) => Repository::from_store_with_snapshot_lenient(store, snapshot),
+        None => Repository::from_store_lenient(store),
+    }
+    .map_err(|e| Error::ReadRepository {
+        source: Box::new(e),
+    })?;
+    warn_skipped(
+        "while loading the repository",
+        &skipped.missing,
+        &skipped.quarantined,
+        &skipped.errors,
+    );
 
     #[cfg(feature = "flame_it")]
     flame::end("load repository");
@@ -126,32 +304,101 @@ fn run() -> Result<(), Error> {
     #[cfg(feature = "flame_it")]
     flame::start("synchronize data");
 
-    repo.try_sync_data()
-        .map_err(|errors| Error::SyncError { errors })?;
-    repo.save_meta().unwrap();
+    let report = repo.try_sync_data().map_err(|e| Error::SyncError {
+        errors: vec![Box::new(e).into()],
+    })?;
+    for (device, sync) in report.devices.iter() {
+        warn_skipped(
+            &format!("from device {}", device),
+            &sync.missing,
+            &sync.failed,
+            &sync.errors,
+        );
+    }
+    save(&mut repo, &mut snapshot_cache)?;
 
     #[cfg(feature = "flame_it")]
     flame::end("synchronize data");
 
+    // History can be shown even when the timesheet has conflicts
+    if let Command::Log(subcmd) = &cmd {
+        subcmd.exec(&repo).context(GeneralError {})?;
+        return Ok(());
+    }
+
+    // Conflicts are resolved on the patched timesheet, as they prevent it from
+    // being flattened
+    if let Command::Resolve(subcmd) = &cmd {
+        let patches = subcmd
+            .exec(repo.timesheet())
+            .map_err(|e| Box::new(e).into())
+            .context(GeneralError {})?;
+        for patch in patches {
+            let patch = stamp(patch, device_id);
+            println!("{}", patch.patch_ref());
+            repo.add_patch(patch).unwrap();
+        }
+        save(&mut repo, &mut snapshot_cache)?;
+        return Ok(());
+    }
+
+    // Undoing a patch may be the way to get rid of a conflict
+    if let Command::Undo(subcmd) = &cmd {
+        let patch = subcmd
+            .exec(&repo, device_id)
+            .map_err(|e| Box::new(e).into())
+            .context(GeneralError {})?;
+        let patch = stamp(patch, device_id);
+        println!("{}", patch.patch_ref());
+        repo.add_patch(patch).unwrap();
+        save(&mut repo, &mut snapshot_cache)?;
+        return Ok(());
+    }
+
+    // Compacting works on patches, not the flattened timesheet
+    if let Command::Compact(subcmd) = &cmd {
+        let checkpoint = subcmd
+            .exec(&mut repo)
+            .map_err(|e| Box::new(e).into())
+            .context(GeneralError {})?;
+        if let Some(patch) = checkpoint {
+            let patch = stamp(patch, device_id);
+            println!("{}", patch.patch_ref());
+            repo.add_patch(patch).unwrap();
+        }
+        save(&mut repo, &mut snapshot_cache)?;
+        return Ok(());
+    }
+
     // Convert abstract patch data structure into a more conventional format
     #[cfg(feature = "flame_it")]
     flame::start("flatten timesheet");
 
     let eventgraph = repo.timesheet();
     let timesheet = eventgraph
-        .flatten()
+        .flatten_with(policy)
         .map_err(|conflicts| Error::MergeConflicts { conflicts })?;
 
+    for (event_ref, superseded) in timesheet.superseded_starts() {
+        for start in superseded {
+            eprintln!(
+                "Warning: event {} has conflicting start times; using {} from patch {} over {} from patch {}",
+                event_ref, start.winning_time, start.winning_patch, start.time, start.patch
+            );
+        }
+    }
+
     #[cfg(feature = "flame_it")]
     flame::end("flatten timesheet");
 
     // Run command
     #[cfg(feature = "flame_it")]
     flame::start("command");
-    match opt.cmd.unwrap_or_default() {
+    match cmd {
         Command::Start(subcmd) => {
             let patches = subcmd.exec(&timesheet);
             for patch in patches {
+                let patch = stamp(patch, device_id);
                 println!("{}", patch.patch_ref());
                 repo.add_patch(patch).unwrap();
             }
@@ -159,6 +406,7 @@ fn run() -> Result<(), Error> {
         Command::Import(subcmd) => {
             let patches = subcmd.exec(&timesheet).context(ImportError {})?;
             for patch in patches {
+                let patch = stamp(patch, device_id);
                 println!("{}", patch.patch_ref());
                 repo.add_patch(patch).unwrap();
             }
@@ -172,6 +420,7 @@ fn run() -> Result<(), Error> {
                 .map_err(|e| Box::new(e).into())
                 .context(GeneralError {})?;
             for patch in patches {
+                let patch = stamp(patch, device_id);
                 println!("{}", patch.patch_ref());
                 repo.add_patch(patch).unwrap();
             }
@@ -182,6 +431,50 @@ fn run() -> Result<(), Error> {
                 .map_err(|e| Box::new(e).into())
                 .context(GeneralError {})?;
             for patch in patches {
+                let patch = stamp(patch, device_id);
+                println!("{}", patch.patch_ref());
+                repo.add_patch(patch).unwrap();
+            }
+        }
+        Command::Attr(subcmd) => {
+            let patches = subcmd
+                .exec(&timesheet)
+                .map_err(|e| Box::new(e).into())
+                .context(GeneralError {})?;
+            for patch in patches {
+                let patch = stamp(patch, device_id);
+                println!("{}", patch.patch_ref());
+                repo.add_patch(patch).unwrap();
+            }
+        }
+        Command::Note(subcmd) => {
+            let patches = subcmd
+                .exec(&timesheet)
+                .map_err(|e| Box::new(e).into())
+                .context(GeneralError {})?;
+            for patch in patches {
+                let patch = stamp(patch, device_id);
+                println!("{}", patch.patch_ref());
+                repo.add_patch(patch).unwrap();
+            }
+        }
+        Command::Log(_)
+        | Command::Resolve(_)
+        | Command::Undo(_)
+        | Command::Compact(_)
+        | Command::Fsck(_)
+        | Command::Migrate(_) => {
+            unreachable!(
+                "log, resolve, undo, compact, fsck and migrate are handled before flattening"
+            )
+        }
+        Command::Delete(subcmd) => {
+            let patches = subcmd
+                .exec(&timesheet)
+                .map_err(|e| Box::new(e).into())
+                .context(GeneralError {})?;
+            for patch in patches {
+                let patch = stamp(patch, device_id);
                 println!("{}", patch.patch_ref());
                 repo.add_patch(patch).unwrap();
             }
@@ -191,7 +484,7 @@ fn run() -> Result<(), Error> {
     flame::end("command");
 
     // Save which patches this device uses to disk
-    repo.save_meta().unwrap();
+    save(&mut repo, &mut snapshot_cache)?;
 
     #[cfg(feature = "flame_it")]
     flame::dump_html(&mut std::fs::File::create("flame-graph.html").unwrap()).unwrap();
@@ -199,6 +492,88 @@ fn run() -> Result<(), Error> {
     Ok(())
 }
 
+/// Save which patches this device uses to disk, along with a snapshot of the
+/// repository to speed up loading it next time
+fn save<S: Store>(
+    repo: &mut Repository<S>,
+    snapshot_cache: &mut Option<cache::SnapshotCache>,
+) -> Result<(), Error>
+where
+    <S as Store>::Error: 'static,
+{
+    repo.save_meta()
+        .map_err(|e| Box::new(e).into())
+        .context(SaveMeta {})?;
+    if let Some(snapshot_cache) = snapshot_cache {
+        if let Err(e) = snapshot_cache.save(repo) {
+            eprintln!("Warning: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Point out files in the meta folder that Syncthing or an editor left behind
+fn report_meta_folder(store: &SyncFolderStore) {
+    let report = match store.meta_folder_report() {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            return;
+        }
+    };
+    for copy in report.conflict_copies {
+        eprintln!(
+            "Note: merging conflict copy {} into the meta of device {}",
+            copy.path.display(),
+            copy.device_id
+        );
+    }
+    for path in report.ignored {
+        eprintln!("Note: ignoring {}", path.display());
+    }
+}
+
+/// Warn about patches that couldn't be loaded. The rest of the timesheet is
+/// still used, and the skipped patches are tried again the next time augr runs.
+fn warn_skipped<E: std::error::Error>(
+    context: &str,
+    missing: &BTreeSet<PatchRef>,
+    set_aside: &BTreeSet<PatchRef>,
+    errors: &[RepositoryError<E>],
+) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!(
+        "Warning: skipped patches {} ({} missing, {} set aside):",
+        context,
+        missing.len(),
+        set_aside.len()
+    );
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+}
+
+/// Record which device and version of augr created the patch, and when, then
+/// give the patch an id derived from its contents. The id doesn't depend on
+/// the device, time or version.
+fn stamp(patch: Patch, device_id: &str) -> Patch {
+    patch
+        .device(device_id.to_string())
+        .created_at(Utc::now())
+        .augr_version(env!("CARGO_PKG_VERSION").to_string())
+        .content_addressed()
+}
+
+fn format_conflicts(conflicts: &[Conflict]) -> String {
+    conflicts
+        .iter()
+        .map(|conflict| format!("  - {}", conflict))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 fn format_duration(duration: chrono::Duration) -> String {
     let hours = duration.num_hours();
     let mins = duration.num_minutes() - (hours * 60);
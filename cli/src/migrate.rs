@@ -0,0 +1,572 @@
+use crate::{config::StoreKind, store::CliStore};
+use augr_core::{store::SyncFolderStore, PatchRef, Repository, Store};
+use snafu::{ResultExt, Snafu};
+use std::{collections::BTreeSet, error::Error as StdError, path::PathBuf, str::FromStr};
+use structopt::StructOpt;
+use uuid::Uuid;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {
+    /// The store to copy from, such as `folder:~/Sync/augr` or
+    /// `sqlite:~/augr.sqlite`. A plain path is a sync folder. It must exist
+    #[structopt(long = "from")]
+    from: StoreSpec,
+
+    /// The store to copy to. It is created if it doesn't exist
+    #[structopt(long = "to")]
+    to: StoreSpec,
+}
+
+/// Which kind of store to open, and where it is
+#[derive(Clone, Debug)]
+pub struct StoreSpec {
+    kind: StoreKind,
+    location: PathBuf,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Unknown store {:?}; expected `folder:<path>` or `sqlite:<path>`",
+        spec
+    ))]
+    UnknownStore { spec: String },
+
+    #[snafu(display(
+        "The {} store requires augr to be built with the `{}` feature",
+        store,
+        feature
+    ))]
+    UnsupportedStore {
+        store: &'static str,
+        feature: &'static str,
+    },
+
+    #[snafu(display("Unable to open {}: {}", location.display(), source))]
+    OpenStore {
+        source: Box<dyn StdError>,
+        location: PathBuf,
+    },
+
+    #[snafu(display("Unable to read from {}: {}", location.display(), source))]
+    ReadStore {
+        source: Box<dyn StdError>,
+        location: PathBuf,
+    },
+
+    #[snafu(display("Unable to write to {}: {}", location.display(), source))]
+    WriteStore {
+        source: Box<dyn StdError>,
+        location: PathBuf,
+    },
+
+    #[snafu(display(
+        "{} already has different patches with the same ids, refusing to overwrite them: {}",
+        location.display(),
+        format_patches(patches)
+    ))]
+    DifferingPatches {
+        location: PathBuf,
+        patches: Vec<PatchRef>,
+    },
+
+    #[snafu(display(
+        "{} already has patches with the same ids that can't be read, refusing to overwrite them: {}",
+        location.display(),
+        format_patches(patches)
+    ))]
+    UnreadablePatches {
+        location: PathBuf,
+        patches: Vec<PatchRef>,
+    },
+
+    #[snafu(display(
+        "{} already has metas listing patches the copies don't, refusing to overwrite them: {}",
+        location.display(),
+        devices.join(", ")
+    ))]
+    DifferingMetas {
+        location: PathBuf,
+        devices: Vec<String>,
+    },
+
+    #[snafu(display("Unable to load {}: {}", location.display(), errors.join("; ")))]
+    LoadRepository {
+        location: PathBuf,
+        errors: Vec<String>,
+    },
+
+    #[snafu(display(
+        "The timesheet in {} doesn't match the one in {} after migrating",
+        to.display(),
+        from.display()
+    ))]
+    Mismatch { from: PathBuf, to: PathBuf },
+}
+
+impl FromStr for StoreSpec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self, Error> {
+        let (kind, location) = match spec.find(':') {
+            Some(index) => match &spec[..index] {
+                "folder" => (StoreKind::SyncFolder, &spec[index + 1..]),
+                "sqlite" => (StoreKind::Sqlite, &spec[index + 1..]),
+                // Windows paths have a drive letter before a colon
+                prefix if prefix.len() == 1 => (StoreKind::SyncFolder, spec),
+                _ => {
+                    return UnknownStore {
+                        spec: spec.to_string(),
+                    }
+                    .fail()
+                }
+            },
+            None => (StoreKind::SyncFolder, spec),
+        };
+        Ok(StoreSpec {
+            kind,
+            location: PathBuf::from(location),
+        })
+    }
+}
+
+impl Cmd {
+    /// Copy every patch and meta from one store to another, then check that
+    /// both stores give the same timesheet. Patches that were copied by an
+    /// earlier, interrupted run are skipped, so it is safe to run again.
+    pub fn exec(&self) -> Result<(), Error> {
+        match self.from.kind {
+            StoreKind::SyncFolder => self.exec_from::<SyncFolderStore>(),
+            #[cfg(feature = "sqlite")]
+            StoreKind::Sqlite => self.exec_from::<augr_core::store::SqliteStore>(),
+            #[cfg(not(feature = "sqlite"))]
+            StoreKind::Sqlite => unsupported_sqlite(),
+        }
+    }
+
+    fn exec_from<F: CliStore>(&self) -> Result<(), Error>
+    where
+        <F as Store>::Error: 'static,
+    {
+        match self.to.kind {
+            StoreKind::SyncFolder => migrate::<F, SyncFolderStore>(&self.from, &self.to),
+            #[cfg(feature = "sqlite")]
+            StoreKind::Sqlite => migrate::<F, augr_core::store::SqliteStore>(&self.from, &self.to),
+            #[cfg(not(feature = "sqlite"))]
+            StoreKind::Sqlite => unsupported_sqlite(),
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn unsupported_sqlite() -> Result<(), Error> {
+    UnsupportedStore {
+        store: "sqlite",
+        feature: "sqlite",
+    }
+    .fail()
+}
+
+impl StoreSpec {
+    fn open<S: CliStore>(&self, device_id: &str) -> Result<S, Error>
+    where
+        <S as Store>::Error: 'static,
+    {
+        S::open(&self.location, device_id.to_string()).context(OpenStore {
+            location: &self.location,
+        })
+    }
+
+    /// Open a store that must already exist, like the one being migrated from
+    fn open_existing<S: CliStore>(&self, device_id: &str) -> Result<S, Error>
+    where
+        <S as Store>::Error: 'static,
+    {
+        S::open_existing(&self.location, device_id.to_string()).context(OpenStore {
+            location: &self.location,
+        })
+    }
+
+    fn read_error<E: StdError + 'static>(&self, e: E) -> Error {
+        Error::ReadStore {
+            source: Box::new(e),
+            location: self.location.clone(),
+        }
+    }
+
+    fn write_error<E: StdError + 'static>(&self, e: E) -> Error {
+        Error::WriteStore {
+            source: Box::new(e),
+            location: self.location.clone(),
+        }
+    }
+}
+
+fn migrate<F: CliStore, T: CliStore>(from: &StoreSpec, to: &StoreSpec) -> Result<(), Error>
+where
+    <F as Store>::Error: 'static,
+    <T as Store>::Error: 'static,
+{
+    // A device that has never saved a meta, so that nothing is written to
+    // either store while listing and verifying them
+    let migrate_device = format!("augr-migrate-{}", Uuid::new_v4());
+    let source: F = from.open_existing(&migrate_device)?;
+    let mut destination: T = to.open(&migrate_device)?;
+
+    // Check every patch before copying any, so that nothing is copied if the
+    // stores disagree about a patch
+    let mut to_copy = Vec::new();
+    let mut differing = Vec::new();
+    let mut unreadable = Vec::new();
+    let mut already_copied = 0;
+    for patch_ref in source.patch_refs().map_err(|e| from.read_error(e))? {
+        let patch = source
+            .get_patch(&patch_ref)
+            .map_err(|e| from.read_error(e))?;
+        if !destination
+            .has_patch(&patch_ref)
+            .map_err(|e| to.read_error(e))?
+        {
+            to_copy.push(patch);
+            continue;
+        }
+        match destination.get_patch(&patch_ref) {
+            Ok(existing) if existing == patch => already_copied += 1,
+            Ok(_) => differing.push(patch_ref),
+            Err(_) => unreadable.push(patch_ref),
+        }
+    }
+    if !differing.is_empty() {
+        return DifferingPatches {
+            location: &to.location,
+            patches: differing,
+        }
+        .fail();
+    }
+    if !unreadable.is_empty() {
+        return UnreadablePatches {
+            location: &to.location,
+            patches: unreadable,
+        }
+        .fail();
+    }
+
+    // Metas are copied as they are. Reading a device's meta from a sync
+    // folder merges in the conflict copies Syncthing made of it, so their
+    // patches are copied too. A meta that is already in the destination is
+    // only replaced if the copy lists every patch it does, so that no patch
+    // a device has listed is forgotten.
+    let mut metas = Vec::new();
+    let mut differing_metas = Vec::new();
+    for device_id in source.device_ids().map_err(|e| from.read_error(e))? {
+        let meta = from
+            .open_existing::<F>(&device_id)?
+            .get_meta()
+            .map_err(|e| from.read_error(e))?;
+        let existing = to
+            .open::<T>(&device_id)?
+            .get_meta()
+            .map_err(|e| to.read_error(e))?;
+        let copied: BTreeSet<&PatchRef> = meta.patches().collect();
+        if existing
+            .patches()
+            .all(|patch_ref| copied.contains(patch_ref))
+        {
+            metas.push((device_id, meta));
+        } else {
+            differing_metas.push(device_id);
+        }
+    }
+    if !differing_metas.is_empty() {
+        return DifferingMetas {
+            location: &to.location,
+            devices: differing_metas,
+        }
+        .fail();
+    }
+
+    for patch in to_copy.iter() {
+        destination
+            .add_patch(patch)
+            .map_err(|e| to.write_error(e))?;
+    }
+    for (device_id, meta) in metas.iter() {
+        to.open::<T>(device_id)?
+            .save_meta(meta)
+            .map_err(|e| to.write_error(e))?;
+    }
+
+    println!(
+        "Copied {} patches ({} were already copied) and the metas of {} devices",
+        to_copy.len(),
+        already_copied,
+        metas.len()
+    );
+
+    verify(
+        load(from, from.open_existing::<F>(&migrate_device)?)?,
+        load(to, to.open::<T>(&migrate_device)?)?,
+        from,
+        to,
+    )?;
+    println!("Both stores have the same timesheet");
+    Ok(())
+}
+
+/// Load every device's patches from the store
+fn load<S: CliStore>(spec: &StoreSpec, store: S) -> Result<Repository<S>, Error>
+where
+    <S as Store>::Error: 'static,
+{
+    let to_strings = |errors: Vec<augr_core::repository::Error<S::Error>>| Error::LoadRepository {
+        location: spec.location.clone(),
+        errors: errors.iter().map(ToString::to_string).collect(),
+    };
+    let mut repo = Repository::from_store(store).map_err(to_strings)?;
+    let report = repo.try_sync_data().map_err(|e| to_strings(vec![e]))?;
+    if !report.is_complete() {
+        return Err(to_strings(report.into_errors()));
+    }
+    Ok(repo)
+}
+
+fn verify<F: Store, T: Store>(
+    from_repo: Repository<F>,
+    to_repo: Repository<T>,
+    from: &StoreSpec,
+    to: &StoreSpec,
+) -> Result<(), Error>
+where
+    <F as Store>::Error: 'static,
+    <T as Store>::Error: 'static,
+{
+    // The last segment ends at the current time, so only what each segment
+    // starts with is compared
+    let events = |timesheet: augr_core::Timesheet| {
+        timesheet
+            .segments()
+            .into_iter()
+            .map(|segment| {
+                (
+                    segment.event_ref,
+                    segment.start_time,
+                    segment.tags,
+                    segment.notes,
+                    segment.attributes,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let same_patches = from_repo.patches_loaded().eq(to_repo.patches_loaded());
+    let from_segments = from_repo.timesheet().flatten().map(events);
+    let to_segments = to_repo.timesheet().flatten().map(events);
+    if !same_patches || from_segments != to_segments {
+        return Mismatch {
+            from: &from.location,
+            to: &to.location,
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+fn format_patches(patches: &[PatchRef]) -> String {
+    patches
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{self, event, TempFolder};
+    use augr_core::{Meta, Patch};
+    use chrono::{TimeZone, Utc};
+    use std::fs::{create_dir_all, read_to_string, write};
+
+    /// A sync folder in the temporary directory, removed when dropped
+    struct TempSpec {
+        spec: StoreSpec,
+        _folder: TempFolder,
+    }
+
+    impl TempSpec {
+        fn new() -> Self {
+            let folder = TempFolder::new();
+            let spec = StoreSpec {
+                kind: StoreKind::SyncFolder,
+                location: folder.0.clone(),
+            };
+            TempSpec {
+                spec,
+                _folder: folder,
+            }
+        }
+    }
+
+    fn add(spec: &StoreSpec, device: &str, patch: &Patch) {
+        let mut store: SyncFolderStore = spec.open(device).unwrap();
+        test_util::add(&mut store, patch);
+    }
+
+    fn meta(spec: &StoreSpec, device: &str) -> Meta {
+        spec.open::<SyncFolderStore>(device)
+            .unwrap()
+            .get_meta()
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_store_specs() {
+        let spec: StoreSpec = "sqlite:/tmp/augr.sqlite".parse().unwrap();
+        assert_eq!(spec.kind, StoreKind::Sqlite);
+        assert_eq!(spec.location, PathBuf::from("/tmp/augr.sqlite"));
+
+        let spec: StoreSpec = "folder:/tmp/augr".parse().unwrap();
+        assert_eq!(spec.kind, StoreKind::SyncFolder);
+        assert_eq!(spec.location, PathBuf::from("/tmp/augr"));
+
+        let spec: StoreSpec = "/tmp/augr".parse().unwrap();
+        assert_eq!(spec.kind, StoreKind::SyncFolder);
+
+        let spec: StoreSpec = "C:\\augr".parse().unwrap();
+        assert_eq!(spec.location, PathBuf::from("C:\\augr"));
+
+        assert!("ftp:/tmp/augr".parse::<StoreSpec>().is_err());
+    }
+
+    #[test]
+    fn migrate_is_resumable() {
+        let from = TempSpec::new();
+        let to = TempSpec::new();
+        let patch1 = event("a", 12);
+        let patch2 = event("b", 13);
+        add(&from.spec, "laptop", &patch1);
+        add(&from.spec, "phone", &patch2);
+
+        // An earlier run that was interrupted after copying one patch
+        add(&to.spec, "laptop", &patch1);
+
+        migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec).unwrap();
+        assert_eq!(meta(&to.spec, "laptop"), meta(&from.spec, "laptop"));
+        assert_eq!(meta(&to.spec, "phone"), meta(&from.spec, "phone"));
+
+        // Running it again changes nothing
+        migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec).unwrap();
+    }
+
+    #[test]
+    fn differing_patches_are_not_overwritten() {
+        let from = TempSpec::new();
+        let to = TempSpec::new();
+        let patch = event("a", 12);
+        add(&from.spec, "laptop", &patch);
+
+        let imposter = Patch::with_id(*patch.patch_ref()).create_event(
+            "a".to_string(),
+            Utc.ymd(2019, 7, 23).and_hms(8, 0, 0),
+            vec![],
+        );
+        add(&to.spec, "laptop", &imposter);
+
+        match migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec) {
+            Err(Error::DifferingPatches { patches, .. }) => {
+                assert_eq!(patches, vec![*patch.patch_ref()])
+            }
+            other => panic!("expected differing patches, got {:?}", other),
+        }
+        let store: SyncFolderStore = to.spec.open("laptop").unwrap();
+        assert_eq!(store.get_patch(patch.patch_ref()).unwrap(), imposter);
+    }
+
+    #[test]
+    fn metas_are_copied_verbatim() {
+        let from = TempSpec::new();
+        let to = TempSpec::new();
+        let patch1 = event("a", 12);
+        let patch2 = event("b", 13);
+        add(&from.spec, "laptop", &patch1);
+        add(&from.spec, "laptop", &patch2);
+
+        // A conflict copy Syncthing made of the laptop's meta
+        let copy = from
+            .spec
+            .location
+            .join("meta")
+            .join("laptop.sync-conflict-20190723-150000-ABCDEFG.toml");
+        let patch3 = event("c", 14);
+        let mut source: SyncFolderStore = from.spec.open("laptop").unwrap();
+        source.add_patch(&patch3).unwrap();
+        write(&copy, format!("patches = [\"{}\"]", patch3.patch_ref())).unwrap();
+
+        // The destination meta only lists a patch the copy does too
+        add(&to.spec, "laptop", &patch1);
+
+        migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec).unwrap();
+        let copied = meta(&to.spec, "laptop");
+        assert_eq!(copied, meta(&from.spec, "laptop"));
+        assert!(copied.patches().any(|patch| patch == patch3.patch_ref()));
+    }
+
+    #[test]
+    fn differing_metas_are_not_overwritten() {
+        let from = TempSpec::new();
+        let to = TempSpec::new();
+        let patch1 = event("a", 12);
+        let patch2 = event("b", 13);
+        add(&from.spec, "laptop", &patch1);
+        add(&to.spec, "laptop", &patch2);
+
+        match migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec) {
+            Err(Error::DifferingMetas { devices, .. }) => {
+                assert_eq!(devices, vec!["laptop".to_string()])
+            }
+            other => panic!("expected differing metas, got {:?}", other),
+        }
+        let store: SyncFolderStore = to.spec.open("laptop").unwrap();
+        assert!(!store.has_patch(patch1.patch_ref()).unwrap());
+        assert_eq!(
+            meta(&to.spec, "laptop").patches().collect::<Vec<_>>(),
+            vec![patch2.patch_ref()]
+        );
+    }
+
+    #[test]
+    fn missing_source_is_not_created() {
+        let from = TempSpec::new();
+        let to = TempSpec::new();
+
+        match migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec) {
+            Err(Error::OpenStore { location, .. }) => assert_eq!(location, from.spec.location),
+            other => panic!("expected the source to be missing, got {:?}", other),
+        }
+        assert!(!from.spec.location.exists());
+    }
+
+    #[test]
+    fn unreadable_patches_are_not_overwritten() {
+        let from = TempSpec::new();
+        let to = TempSpec::new();
+        let patch = event("a", 12);
+        add(&from.spec, "laptop", &patch);
+
+        let path = to
+            .spec
+            .location
+            .join("patches")
+            .join(patch.patch_ref().to_string())
+            .with_extension("toml");
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, "id = \"c10350e8").unwrap();
+
+        match migrate::<SyncFolderStore, SyncFolderStore>(&from.spec, &to.spec) {
+            Err(Error::UnreadablePatches { patches, .. }) => {
+                assert_eq!(patches, vec![*patch.patch_ref()])
+            }
+            other => panic!("expected unreadable patches, got {:?}", other),
+        }
+        assert_eq!(read_to_string(&path).unwrap(), "id = \"c10350e8");
+    }
+}
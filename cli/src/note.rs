@@ -0,0 +1,38 @@
+use augr_core::{store::patch::AddNote, EventRef, Patch, Timesheet};
+use snafu::Snafu;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {
+    /// The id of the event to modify
+    event: EventRef,
+
+    /// The text of the note to attach to the event
+    #[structopt(required = true)]
+    text: Vec<String>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown event reference: {}", event_ref))]
+    UnknownEventRef { event_ref: EventRef },
+}
+impl Cmd {
+    pub fn exec(&self, timesheet: &Timesheet) -> Result<Vec<Patch>, Error> {
+        let event = timesheet
+            .get_patched_timesheet()
+            .events
+            .get(&self.event)
+            .filter(|event| !event.is_removed())
+            .ok_or(Error::UnknownEventRef {
+                event_ref: self.event.clone(),
+            })?;
+        let mut patch = Patch::new();
+        patch.insert_add_note(AddNote {
+            parents: event.latest_patches(),
+            event: self.event.clone(),
+            note: self.text.join(" "),
+        });
+        Ok(vec![patch])
+    }
+}
@@ -0,0 +1,383 @@
+use crate::time_input::parse_default_local;
+use augr_core::{
+    repository::{
+        event::{Error as EventError, PatchedEvent},
+        timesheet::{Error as Conflict, PatchedTimesheet},
+    },
+    store::patch::{AddStart, RemoveEvent, RemoveStart},
+    EventRef, Patch, PatchRef,
+};
+use chrono::{DateTime, Duration, Local, Utc};
+use snafu::{ResultExt, Snafu};
+use std::{
+    collections::BTreeSet,
+    ffi::OsStr,
+    io::{self, BufRead, Write},
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown event reference: {}", event_ref))]
+    UnknownEventRef { event_ref: EventRef },
+
+    #[snafu(display("Unable to read answer: {}", source))]
+    ReadAnswer { source: io::Error },
+
+    #[snafu(display("Input ended before every conflict was answered"))]
+    NoAnswer,
+
+    #[snafu(display("Unable to apply the fix: {}", format_conflicts(conflicts)))]
+    ApplyFix { conflicts: Vec<Conflict> },
+}
+
+/// One way of resolving a conflict
+enum Choice {
+    /// Apply a patch that has already been created
+    Patch(String, Box<Patch>),
+
+    /// Ask for a new start time for the event
+    PromptStart(String, EventRef),
+}
+
+impl Choice {
+    fn description(&self) -> &str {
+        match self {
+            Choice::Patch(description, _) => description,
+            Choice::PromptStart(description, _) => description,
+        }
+    }
+}
+
+impl Cmd {
+    pub fn exec(&self, timesheet: &PatchedTimesheet) -> Result<Vec<Patch>, Error> {
+        let conflicts = match timesheet.flatten() {
+            Ok(_) => {
+                println!("No conflicts found");
+                return Ok(Vec::new());
+            }
+            Err(conflicts) => conflicts,
+        };
+
+        // Each fix is applied to a copy of the timesheet, so that later fixes
+        // build on top of it
+        let mut timesheet = timesheet.clone();
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        let mut patches = Vec::new();
+        let total = conflicts.len();
+        for (i, conflict) in conflicts.iter().enumerate() {
+            println!();
+            let unresolved = match timesheet.flatten() {
+                Ok(_) => false,
+                Err(conflicts) => conflicts.contains(conflict),
+            };
+            if !unresolved {
+                println!(
+                    "Conflict {} of {} was resolved by an earlier fix",
+                    i + 1,
+                    total
+                );
+                continue;
+            }
+            println!("Conflict {} of {}: {}", i + 1, total, conflict);
+            let choices = choices_for(&timesheet, conflict)?;
+            for (n, choice) in choices.iter().enumerate() {
+                println!("  {}) {}", n + 1, choice.description());
+            }
+            println!("  s) Skip");
+
+            let choice = loop {
+                let answer = prompt(&mut input, "Choice: ")?;
+                if answer == "s" || answer.is_empty() {
+                    break None;
+                }
+                match answer.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= choices.len() => break Some(&choices[n - 1]),
+                    _ => println!(
+                        "Please enter a number between 1 and {}, or s",
+                        choices.len()
+                    ),
+                }
+            };
+
+            let patch = match choice {
+                None => continue,
+                Some(Choice::Patch(_, patch)) => (**patch).clone(),
+                Some(Choice::PromptStart(_, event_ref)) => {
+                    let time = loop {
+                        let answer = prompt(&mut input, "New start time: ")?;
+                        match parse_default_local(OsStr::new(&answer)) {
+                            Ok(time) => break time,
+                            Err(_) => println!("No valid date, time, or duration was found"),
+                        }
+                    };
+                    let event = get_event(&timesheet, event_ref)?;
+                    move_start(event_ref, event, time.with_timezone(&Utc))
+                }
+            };
+            patches.push(apply_fix(&mut timesheet, patch)?);
+        }
+        Ok(patches)
+    }
+}
+
+/// Give the patch its final id and apply it, so that fixes for later
+/// conflicts can name it as a parent
+fn apply_fix(timesheet: &mut PatchedTimesheet, patch: Patch) -> Result<Patch, Error> {
+    let patch = patch.content_addressed();
+    timesheet
+        .apply_patch(&patch)
+        .map_err(|conflicts| Error::ApplyFix { conflicts })?;
+    Ok(patch)
+}
+
+fn prompt<R: BufRead>(input: &mut R, message: &str) -> Result<String, Error> {
+    print!("{}", message);
+    io::stdout().flush().context(ReadAnswer {})?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer).context(ReadAnswer {})? == 0 {
+        return Err(Error::NoAnswer);
+    }
+    Ok(answer.trim().to_string())
+}
+
+fn choices_for(timesheet: &PatchedTimesheet, conflict: &Conflict) -> Result<Vec<Choice>, Error> {
+    let choices = match conflict {
+        Conflict::FlattenEventError {
+            source: EventError::MultipleStartTimes,
+            event: event_ref,
+        } => {
+            let event = get_event(timesheet, event_ref)?;
+            let mut choices: Vec<Choice> = event
+                .starts()
+                .into_iter()
+                .map(|(patch_ref, time)| {
+                    Choice::Patch(
+                        format!("Keep start at {}", format_time(time)),
+                        Box::new(keep_start(event_ref, event, patch_ref)),
+                    )
+                })
+                .collect();
+            choices.push(Choice::PromptStart(
+                String::from("Enter a different start time"),
+                event_ref.clone(),
+            ));
+            choices.push(Choice::Patch(
+                String::from("Delete the event"),
+                Box::new(delete_event(event_ref, event)),
+            ));
+            choices
+        }
+        Conflict::FlattenEventError {
+            source: EventError::NoStartTimes,
+            event: event_ref,
+        } => {
+            let event = get_event(timesheet, event_ref)?;
+            vec![
+                Choice::PromptStart(String::from("Enter a start time"), event_ref.clone()),
+                Choice::Patch(
+                    String::from("Delete the event"),
+                    Box::new(delete_event(event_ref, event)),
+                ),
+            ]
+        }
+        Conflict::DuplicateEventTime { event_a, event_b } => {
+            let a = get_event(timesheet, event_a)?;
+            let b = get_event(timesheet, event_b)?;
+            let mut choices = vec![
+                Choice::Patch(
+                    format!(
+                        "Keep {} and delete {}",
+                        describe(event_a, a),
+                        describe(event_b, b)
+                    ),
+                    Box::new(delete_event(event_b, b)),
+                ),
+                Choice::Patch(
+                    format!(
+                        "Keep {} and delete {}",
+                        describe(event_b, b),
+                        describe(event_a, a)
+                    ),
+                    Box::new(delete_event(event_a, a)),
+                ),
+            ];
+            if let Some((_patch_ref, time)) = b.starts().into_iter().next() {
+                let later = free_time_after(timesheet, time);
+                choices.push(Choice::Patch(
+                    format!("Move {} to {}", describe(event_b, b), format_time(later)),
+                    Box::new(move_start(event_b, b, later)),
+                ));
+            }
+            choices
+        }
+        // Only produced while applying patches, never while flattening
+        _ => Vec::new(),
+    };
+    Ok(choices)
+}
+
+fn get_event<'ts>(
+    timesheet: &'ts PatchedTimesheet,
+    event_ref: &EventRef,
+) -> Result<&'ts PatchedEvent, Error> {
+    timesheet
+        .events
+        .get(event_ref)
+        .ok_or_else(|| Error::UnknownEventRef {
+            event_ref: event_ref.clone(),
+        })
+}
+
+/// The first whole second after `time` that no event starts at
+fn free_time_after(timesheet: &PatchedTimesheet, time: DateTime<Utc>) -> DateTime<Utc> {
+    let taken: BTreeSet<DateTime<Utc>> = timesheet
+        .events
+        .values()
+        .filter(|event| !event.is_removed())
+        .flat_map(|event| event.starts().into_iter().map(|(_patch_ref, start)| start))
+        .collect();
+    let mut later = time + Duration::seconds(1);
+    while taken.contains(&later) {
+        later = later + Duration::seconds(1);
+    }
+    later
+}
+
+fn format_conflicts(conflicts: &[Conflict]) -> String {
+    conflicts
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn describe(event_ref: &EventRef, event: &PatchedEvent) -> String {
+    let tags: Vec<String> = event.tags().into_iter().map(|(_ref, tag)| tag).collect();
+    format!("{} ({})", event_ref, tags.join(" "))
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// Remove every start of the event except the one added by `keep`
+fn keep_start(event_ref: &EventRef, event: &PatchedEvent, keep: PatchRef) -> Patch {
+    let parents = event.latest_patches();
+    let mut patch = Patch::new();
+    for (patch_ref, start) in event.starts() {
+        if patch_ref != keep {
+            patch.insert_remove_start(RemoveStart {
+                parents: Some(parents.clone()),
+                patch: patch_ref,
+                event: event_ref.clone(),
+                time: start,
+            });
+        }
+    }
+    patch
+}
+
+/// Replace every start of the event with `time`
+fn move_start(event_ref: &EventRef, event: &PatchedEvent, time: DateTime<Utc>) -> Patch {
+    let parents = event.latest_patches();
+    let mut patch = Patch::new();
+    for (patch_ref, start) in event.starts() {
+        patch.insert_remove_start(RemoveStart {
+            parents: Some(parents.clone()),
+            patch: patch_ref,
+            event: event_ref.clone(),
+            time: start,
+        });
+    }
+    patch.insert_add_start(AddStart {
+        parents,
+        event: event_ref.clone(),
+        time,
+    });
+    patch
+}
+
+fn delete_event(event_ref: &EventRef, event: &PatchedEvent) -> Patch {
+    let mut patch = Patch::new();
+    patch.insert_remove_event(RemoveEvent {
+        parents: event.latest_patches(),
+        event: event_ref.clone(),
+    });
+    patch
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    #[test]
+    fn keep_start_removes_other_starts() {
+        let dt0 = Utc.ymd(2019, 7, 23).and_hms(12, 15, 0);
+        let dt1 = Utc.ymd(2019, 7, 23).and_hms(12, 30, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt0);
+        event.add_patch_to_latest(patch_ref_a);
+        event.add_start(patch_ref_b, dt1);
+        event.add_patch_to_latest(patch_ref_b);
+
+        let patch = keep_start(&"a".to_string(), &event, patch_ref_b);
+
+        let mut expected = Patch::with_id(*patch.patch_ref());
+        expected.insert_remove_start(RemoveStart {
+            parents: Some(event.latest_patches()),
+            patch: patch_ref_a,
+            event: "a".to_string(),
+            time: dt0,
+        });
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn later_fixes_build_on_earlier_ones() {
+        let at = |minute, second| Utc.ymd(2019, 7, 23).and_hms(12, minute, second);
+        let mut timesheet = PatchedTimesheet::default();
+        let create_a = Patch::new().create_event("a".to_string(), at(0, 0), vec![]);
+        let move_a = Patch::new().add_start(*create_a.patch_ref(), "a".to_string(), at(30, 0));
+        for patch in &[
+            create_a.clone(),
+            move_a.clone(),
+            Patch::new().create_event("b".to_string(), at(30, 0), vec![]),
+            Patch::new().create_event("c".to_string(), at(30, 1), vec![]),
+        ] {
+            timesheet.apply_patch(patch).unwrap();
+        }
+
+        let event = get_event(&timesheet, &"a".to_string()).unwrap();
+        let keep = keep_start(&"a".to_string(), event, *move_a.patch_ref());
+        let keep = apply_fix(&mut timesheet, keep).unwrap();
+
+        // `a` now starts at the same time as `b`
+        let conflict = Conflict::DuplicateEventTime {
+            event_a: "b".to_string(),
+            event_b: "a".to_string(),
+        };
+        let choices = choices_for(&timesheet, &conflict).unwrap();
+        let moved = match choices.last() {
+            Some(Choice::Patch(_, patch)) => patch,
+            _ => panic!("expected a patch moving `a`"),
+        };
+        let add_start = moved.add_start.iter().next().unwrap();
+        assert_eq!(add_start.time, at(30, 2));
+        assert_eq!(
+            add_start.parents,
+            Some(*keep.patch_ref()).into_iter().collect()
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use augr_core::{
+    store::{PatchFolderReport, SyncFolderStore},
+    PatchRef, Store, SyncStore,
+};
+#[cfg(feature = "sqlite")]
+use std::fs::create_dir_all;
+use std::{collections::BTreeSet, error::Error, io, path::Path};
+
+/// The stores the cli can use
+pub trait CliStore: SyncStore + Sized
+where
+    <Self as Store>::Error: 'static,
+{
+    /// Open the store at `location` as the given device
+    fn open(location: &Path, device_id: String) -> Result<Self, Box<dyn Error>>;
+
+    /// Open the store at `location` as the given device, failing instead of
+    /// creating the store if it doesn't exist
+    fn open_existing(location: &Path, device_id: String) -> Result<Self, Box<dyn Error>>;
+
+    /// Every patch in the store
+    fn patch_refs(&self) -> Result<BTreeSet<PatchRef>, Self::Error>;
+
+    /// Every device that has saved a meta to the store
+    fn device_ids(&self) -> Result<BTreeSet<String>, Self::Error>;
+
+    /// The files alongside the patches that aren't patches. Stores that don't
+    /// keep patches in files have none.
+    fn patch_folder_report(&self) -> Result<PatchFolderReport, Self::Error> {
+        Ok(PatchFolderReport::default())
+    }
+}
+
+impl CliStore for SyncFolderStore {
+    fn open(location: &Path, device_id: String) -> Result<Self, Box<dyn Error>> {
+        Ok(SyncFolderStore::new(location.to_path_buf(), device_id).should_init(true))
+    }
+
+    fn open_existing(location: &Path, device_id: String) -> Result<Self, Box<dyn Error>> {
+        // Nothing is written to a sync folder until a patch or meta is saved
+        if !location.is_dir() {
+            return Err(
+                io::Error::new(io::ErrorKind::NotFound, "no sync folder at this location").into(),
+            );
+        }
+        Self::open(location, device_id)
+    }
+
+    fn patch_refs(&self) -> Result<BTreeSet<PatchRef>, Self::Error> {
+        SyncFolderStore::patch_refs(self)
+    }
+
+    fn device_ids(&self) -> Result<BTreeSet<String>, Self::Error> {
+        SyncFolderStore::device_ids(self)
+    }
+
+    fn patch_folder_report(&self) -> Result<PatchFolderReport, Self::Error> {
+        SyncFolderStore::patch_folder_report(self)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl CliStore for augr_core::store::SqliteStore {
+    fn open(location: &Path, device_id: String) -> Result<Self, Box<dyn Error>> {
+        if let Some(parent) = location.parent() {
+            create_dir_all(parent)?;
+        }
+        Ok(augr_core::store::SqliteStore::open(location, device_id)?)
+    }
+
+    fn open_existing(location: &Path, device_id: String) -> Result<Self, Box<dyn Error>> {
+        Ok(augr_core::store::SqliteStore::open_existing(
+            location, device_id,
+        )?)
+    }
+
+    fn patch_refs(&self) -> Result<BTreeSet<PatchRef>, Self::Error> {
+        augr_core::store::SqliteStore::patch_refs(self)
+    }
+
+    fn device_ids(&self) -> Result<BTreeSet<String>, Self::Error> {
+        augr_core::store::SqliteStore::device_ids(self)
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{format_duration, time_input::parse_default_local};
+use crate::{attr::parse_key_value, format_duration, time_input::parse_default_local};
 use augr_core::{Tag, Timesheet};
 use chrono::{DateTime, Local};
 use std::collections::BTreeSet;
@@ -9,6 +9,10 @@ pub struct SummaryCmd {
     /// A list of tags to filter against
     tags: Vec<String>,
 
+    /// Only show events with the given `key=value` attribute. May be repeated.
+    #[structopt(long = "attr", number_of_values = 1, parse(try_from_str = parse_key_value))]
+    attributes: Vec<(String, String)>,
+
     /// Show the time that each event ended
     #[structopt(long = "show-ends")]
     show_ends: bool,
@@ -17,6 +21,10 @@ pub struct SummaryCmd {
     #[structopt(long = "refs")]
     show_refs: bool,
 
+    /// Show the notes attached to each event below it
+    #[structopt(long = "notes")]
+    show_notes: bool,
+
     /// The datetime at which to begin showing events
     #[structopt(long = "start", parse(try_from_os_str = parse_default_local))]
     start: Option<DateTime<Local>>,
@@ -38,7 +46,12 @@ impl SummaryCmd {
             .into_iter()
             .filter(|s| s.start_time.with_timezone(&Local) >= start)
             .filter(|s| s.start_time.with_timezone(&Local) <= end)
-            .filter(|s| s.tags.is_superset(&tags));
+            .filter(|s| s.tags.is_superset(&tags))
+            .filter(|s| {
+                self.attributes
+                    .iter()
+                    .all(|(key, value)| s.has_attribute(key, value))
+            });
 
         let mut total_duration = chrono::Duration::seconds(0);
         let mut current_date = None;
@@ -86,16 +99,24 @@ impl SummaryCmd {
             let duration_str = format_duration(segment.duration);
             let total_duration_str = format_duration(total_duration);
 
-            if !self.show_ends {
+            let notes_indent = if !self.show_ends {
                 println!(
                     "{} {} {: <8} {: <8} {}",
                     date_str, start_time, duration_str, total_duration_str, tags_str
                 );
+                "                              "
             } else {
                 println!(
                     "{} {} {} {: <8} {: <8} {}",
                     date_str, start_time, end_time, duration_str, total_duration_str, tags_str
                 );
+                "                                    "
+            };
+
+            if self.show_notes {
+                for note in segment.notes.iter() {
+                    println!("{}> {}", notes_indent, note);
+                }
             }
         }
     }
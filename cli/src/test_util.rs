@@ -0,0 +1,42 @@
+//! Fixtures shared by the tests of the commands
+
+use augr_core::{Patch, Store};
+use chrono::{TimeZone, Utc};
+use std::{fs::remove_dir_all, path::PathBuf};
+use uuid::Uuid;
+
+/// A folder in the temporary directory, removed when dropped. The folder
+/// itself isn't created
+pub struct TempFolder(pub PathBuf);
+
+impl TempFolder {
+    pub fn new() -> Self {
+        TempFolder(std::env::temp_dir().join(format!("augr-test-{}", Uuid::new_v4())))
+    }
+}
+
+impl Drop for TempFolder {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// A patch creating an event tagged `work`, on 2019-07-23 at `hour`
+pub fn event(name: &str, hour: u32) -> Patch {
+    Patch::new().create_event(
+        name.to_string(),
+        Utc.ymd(2019, 7, 23).and_hms(hour, 0, 0),
+        vec!["work".to_string()],
+    )
+}
+
+/// Store a patch and list it in the store's meta
+pub fn add<S: Store>(store: &mut S, patch: &Patch) {
+    store.add_patch(patch).unwrap();
+    store
+        .update_meta(|mut meta| {
+            meta.add_patch(*patch.patch_ref());
+            meta
+        })
+        .unwrap();
+}
@@ -0,0 +1,75 @@
+use augr_core::{repository::timesheet::Error as Conflict, Patch, PatchRef, Repository, Store};
+use snafu::Snafu;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Cmd {
+    /// The patch to undo. Defaults to the latest patch created on this device
+    patch: Option<PatchRef>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to load history: {}", errors.join("; ")))]
+    LoadHistory { errors: Vec<String> },
+
+    #[snafu(display("No patches have been created on device {}", device))]
+    NoPatches { device: String },
+
+    #[snafu(display("Patch {} has not been applied to the timesheet", patch))]
+    UnknownPatch { patch: PatchRef },
+
+    #[snafu(display("Unable to undo patch {}: {}", patch, format_errors(errors)))]
+    Irreversible {
+        patch: PatchRef,
+        errors: Vec<Conflict>,
+    },
+
+    #[snafu(display("Every change made by patch {} has already been reverted", patch))]
+    AlreadyUndone { patch: PatchRef },
+}
+
+impl Cmd {
+    pub fn exec<S: Store>(&self, repo: &Repository<S>, device: &str) -> Result<Patch, Error>
+    where
+        <S as Store>::Error: 'static,
+    {
+        let history = repo.history().map_err(|errors| Error::LoadHistory {
+            errors: errors.iter().map(ToString::to_string).collect(),
+        })?;
+        let patch = match self.patch {
+            Some(patch_ref) => history
+                .into_iter()
+                .find(|patch| *patch.patch_ref() == patch_ref)
+                .ok_or(Error::UnknownPatch { patch: patch_ref })?,
+            None => history
+                .into_iter()
+                .rev()
+                .find(|patch| patch.device.as_deref() == Some(device))
+                .ok_or_else(|| Error::NoPatches {
+                    device: device.to_string(),
+                })?,
+        };
+        let patch_ref = *patch.patch_ref();
+
+        let inverse =
+            repo.timesheet()
+                .inverse_patch(&patch)
+                .map_err(|errors| Error::Irreversible {
+                    patch: patch_ref,
+                    errors,
+                })?;
+        if inverse.is_empty() {
+            return Err(Error::AlreadyUndone { patch: patch_ref });
+        }
+        Ok(inverse)
+    }
+}
+
+fn format_errors(errors: &[Conflict]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join("; ")
+}
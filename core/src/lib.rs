@@ -3,6 +3,7 @@
 extern crate flamer;
 
 pub mod repository;
+pub mod simulation;
 pub mod store;
 pub mod timesheet;
 
@@ -10,7 +11,7 @@ pub use crate::repository::Repository;
 pub use crate::store::{
     meta::Meta,
     patch::{Patch, PatchRef},
-    Store,
+    Store, SyncStore,
 };
 pub use crate::timesheet::{Event, Timesheet};
 
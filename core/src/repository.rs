@@ -1,9 +1,14 @@
+mod checkpoint;
 pub mod event;
+pub mod history;
+pub mod snapshot;
+pub mod sync;
 pub mod timesheet;
 
-use crate::{Meta, Patch, PatchRef, Store};
+use crate::{store::RemovePatchError, EventRef, Meta, Patch, PatchRef, Store};
 use snafu::{ResultExt, Snafu};
-use std::collections::{BTreeSet, VecDeque};
+use snapshot::Snapshot;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use timesheet::{Error as TimesheetError, PatchedTimesheet};
 
 #[derive(Eq, PartialEq, Debug, Snafu)]
@@ -20,6 +25,12 @@ where
     #[snafu(display("Unable to save patch {} to disk: {}", patch, source))]
     SavePatch { source: IE, patch: PatchRef },
 
+    #[snafu(display("Unable to remove patch {}: {}", patch, source))]
+    RemovePatch {
+        source: RemovePatchError<IE>,
+        patch: PatchRef,
+    },
+
     #[snafu(display("Unable to load patch {}: {}", patch, source))]
     PatchNotFound { source: IE, patch: PatchRef },
 
@@ -32,12 +43,24 @@ where
         parents: Vec<PatchRef>,
     },
 
+    #[snafu(display("Patch {} depends on patches that don't exist: {:?}", patch, parents))]
+    DanglingParents {
+        patch: PatchRef,
+        parents: Vec<PatchRef>,
+    },
+
+    #[snafu(display("Patches depend on each other in a cycle: {:?}", patches))]
+    PatchCycle { patches: Vec<PatchRef> },
+
     #[snafu(display("Patch {} could not be applied to timesheet: {:?}", patch, conflicts))]
     PatchingTimesheet {
         conflicts: Vec<TimesheetError>,
         patch: PatchRef,
     },
 
+    #[snafu(display("Unknown event {}", event))]
+    UnknownEvent { event: EventRef },
+
     #[snafu(display("IOError: {}", source))]
     IOError { source: IE },
 }
@@ -46,9 +69,75 @@ where
 pub struct Repository<S: Store> {
     store: S,
     patches_loaded: BTreeSet<PatchRef>,
+
+    /// The loaded patches that no other loaded patch depends on. Every other
+    /// loaded patch is an ancestor of one of these.
+    heads: BTreeSet<PatchRef>,
+
+    /// The checkpoints that have been loaded, and the patches each of them
+    /// folds together
+    checkpoints: BTreeMap<PatchRef, BTreeSet<PatchRef>>,
     timesheet: PatchedTimesheet,
 }
 
+/// The patches that were skipped while loading a repository leniently
+#[derive(Debug)]
+pub struct LoadReport<IE>
+where
+    IE: std::error::Error + 'static,
+{
+    /// Patches that are listed in the meta, or depended on by another patch,
+    /// but couldn't be read from the store
+    pub missing: BTreeSet<PatchRef>,
+
+    /// Patches that were read, but set aside because they couldn't be
+    /// applied, or depend on patches that are missing or were set aside
+    pub quarantined: BTreeSet<PatchRef>,
+
+    /// Why each patch was skipped
+    pub errors: Vec<Error<IE>>,
+}
+
+/// A leniently loaded repository, along with what was skipped loading it
+type LenientLoad<S> =
+    Result<(Repository<S>, LoadReport<<S as Store>::Error>), Error<<S as Store>::Error>>;
+
+impl<IE> LoadReport<IE>
+where
+    IE: std::error::Error + 'static,
+{
+    fn from_errors(errors: Vec<Error<IE>>) -> Self {
+        let mut report = Self {
+            missing: BTreeSet::new(),
+            quarantined: BTreeSet::new(),
+            errors: Vec::new(),
+        };
+        for error in errors.iter() {
+            match error {
+                Error::PatchNotFound { patch, .. } => {
+                    report.missing.insert(*patch);
+                }
+                Error::MissingParentPatches { patch, .. }
+                | Error::DanglingParents { patch, .. }
+                | Error::PatchingTimesheet { patch, .. } => {
+                    report.quarantined.insert(*patch);
+                }
+                Error::PatchCycle { patches } => {
+                    report.quarantined.extend(patches.iter().cloned());
+                }
+                _ => {}
+            }
+        }
+        report.errors = errors;
+        report
+    }
+
+    /// Whether every patch was loaded
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
 impl<S> Repository<S>
 where
     S: Store,
@@ -59,29 +148,148 @@ where
         let mut repo = Self {
             store,
             patches_loaded: BTreeSet::new(),
+            heads: BTreeSet::new(),
+            checkpoints: BTreeMap::new(),
             timesheet: PatchedTimesheet::new(),
         };
         repo.load_all_patches()?;
         Ok(repo)
     }
 
+    /// Load the repository starting from a snapshot taken earlier, so that
+    /// only patches that are new since then are read and applied. If the
+    /// snapshot is from another version of augr, doesn't match the store, or
+    /// the new patches can't be applied on top of it, the repository is
+    /// loaded from scratch instead.
     #[cfg_attr(feature = "flame_it", flame)]
-    pub fn save_meta(&mut self) -> Result<(), Error<S::Error>> {
-        let mut meta = Meta::new();
-        for p in self.patches_loaded.iter() {
-            meta.add_patch(p.clone());
+    pub fn from_store_with_snapshot(
+        store: S,
+        snapshot: Snapshot,
+    ) -> Result<Self, Vec<Error<S::Error>>> {
+        let mut repo = match Self::resume(store, snapshot) {
+            Ok(repo) => repo,
+            Err(store) => return Self::from_store(store),
+        };
+        match repo.load_all_patches() {
+            Ok(()) => Ok(repo),
+            Err(_) => Self::from_store(repo.store),
         }
-        self.store.save_meta(&meta).context(SaveMeta {})
     }
 
+    /// Start from `snapshot` without loading any new patches. The store is
+    /// handed back if the snapshot can't be used with it.
+    fn resume(store: S, snapshot: Snapshot) -> Result<Self, S> {
+        if !snapshot.is_current_version() || !snapshot_matches_store(&store, &snapshot) {
+            return Err(store);
+        }
+        Ok(Self {
+            store,
+            patches_loaded: snapshot.patches().clone(),
+            heads: snapshot.heads.clone(),
+            checkpoints: snapshot.checkpoints.clone(),
+            timesheet: PatchedTimesheet::from_snapshot(snapshot),
+        })
+    }
+
+    /// Load every patch that can be loaded, instead of failing when any of
+    /// them can't be. Patches that can't be read, can't be applied, or are
+    /// waiting for parents that haven't arrived yet are skipped, and listed in
+    /// the returned report. They stay in the meta when it is saved, so they
+    /// are tried again the next time the repository is loaded.
+    ///
+    /// Only failing to read the meta is an error.
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn from_store_lenient(store: S) -> LenientLoad<S> {
+        let mut repo = Self {
+            store,
+            patches_loaded: BTreeSet::new(),
+            heads: BTreeSet::new(),
+            checkpoints: BTreeMap::new(),
+            timesheet: PatchedTimesheet::new(),
+        };
+        let meta = repo.store.get_meta().context(LoadMeta {})?;
+        let errors = repo.load_patches(meta.patches().cloned()).err();
+        let report = LoadReport::from_errors(errors.unwrap_or_default());
+        Ok((repo, report))
+    }
+
+    /// Like `from_store_with_snapshot`, but loads leniently like
+    /// `from_store_lenient`. Patches that can't be loaded on top of the
+    /// snapshot are skipped and reported, rather than rebuilding the
+    /// repository from scratch.
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn from_store_with_snapshot_lenient(store: S, snapshot: Snapshot) -> LenientLoad<S> {
+        let mut repo = match Self::resume(store, snapshot) {
+            Ok(repo) => repo,
+            Err(store) => return Self::from_store_lenient(store),
+        };
+        let meta = repo.store.get_meta().context(LoadMeta {})?;
+        let errors = repo.load_patches(meta.patches().cloned()).err();
+        let report = LoadReport::from_errors(errors.unwrap_or_default());
+        Ok((repo, report))
+    }
+
+    /// Capture the state of the repository so that it can be loaded quickly
+    /// with `from_store_with_snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = self.timesheet.to_snapshot(&self.patches_loaded);
+        snapshot.heads = self.heads.clone();
+        snapshot.checkpoints = self.checkpoints.clone();
+        snapshot
+    }
+
+    /// Save the heads of the loaded patches as this device's meta. Every
+    /// other loaded patch is either an ancestor of a head, or folded into a
+    /// checkpoint that is, so it will be found when the meta is loaded.
+    ///
+    /// Patches in the saved meta that haven't been loaded were added by
+    /// another process since this repository was loaded, and are kept.
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn save_meta(&mut self) -> Result<(), Error<S::Error>> {
+        let heads = &self.heads;
+        let patches_loaded = &self.patches_loaded;
+        self.store
+            .update_meta(|saved| {
+                let mut meta = Meta::new();
+                for p in heads.iter() {
+                    meta.add_patch(*p);
+                }
+                for p in saved.patches() {
+                    if !patches_loaded.contains(p) {
+                        meta.add_patch(*p);
+                    }
+                }
+                meta
+            })
+            .context(SaveMeta {})
+    }
+
+    /// Load a new patch and save it to the store. The same change may have
+    /// been made on another device already, in which case its content-addressed
+    /// patch is in the store and isn't saved again.
     pub fn add_patch(&mut self, patch: Patch) -> Result<(), Error<S::Error>> {
         self.load_patch(patch.clone())?;
+        if patch.format.is_some() && self.already_stored(&patch) {
+            return Ok(());
+        }
         self.store.add_patch(&patch).context(SavePatch {
             patch: *patch.patch_ref(),
         })?;
         Ok(())
     }
 
+    /// Whether the store has a patch with the same id and contents as `patch`
+    fn already_stored(&self, patch: &Patch) -> bool {
+        let patch_ref = patch.patch_ref();
+        if !matches!(self.store.has_patch(patch_ref), Ok(true)) {
+            return false;
+        }
+        match self.store.get_patch(patch_ref) {
+            Ok(stored) => stored.format.is_some() && stored.verify_id(patch_ref),
+            Err(_) => false,
+        }
+    }
+
     #[cfg_attr(feature = "flame_it", flame)]
     pub fn load_patch(&mut self, patch: Patch) -> Result<(), Error<S::Error>> {
         // Don't apply patches twice
@@ -105,21 +313,135 @@ where
             });
         }
 
-        // Mark patch as loaded
-        self.patches_loaded.insert(patch.patch_ref().clone());
-
-        self.timesheet
+        // Patches that can't be applied aren't marked as loaded, so that
+        // nothing is built on top of them. A checkpoint is applied along
+        // with the patch's own changes, and only kept if both succeed.
+        let mut timesheet = match &patch.checkpoint {
+            Some(checkpoint) => {
+                let mut timesheet = self.timesheet.clone();
+                timesheet.apply_checkpoint(checkpoint, &self.patches_loaded);
+                Some(timesheet)
+            }
+            None => None,
+        };
+        timesheet
+            .as_mut()
+            .unwrap_or(&mut self.timesheet)
             .apply_patch(&patch)
             .map_err(|conflicts| Error::PatchingTimesheet {
                 patch: *patch.patch_ref(),
                 conflicts,
-            })
+            })?;
+
+        // Loading a checkpoint loads every patch it folds together
+        if let (Some(checkpoint), Some(timesheet)) = (&patch.checkpoint, timesheet) {
+            self.timesheet = timesheet;
+            self.patches_loaded.extend(checkpoint.patches());
+            self.heads
+                .retain(|head| !checkpoint.patches().contains(head));
+            self.checkpoints
+                .insert(*patch.patch_ref(), checkpoint.patches().clone());
+        }
+
+        // Mark patch as loaded
+        self.patches_loaded.insert(patch.patch_ref().clone());
+        for parent in patch.parents() {
+            self.heads.remove(&parent);
+        }
+        self.heads.insert(*patch.patch_ref());
+        Ok(())
     }
 
     pub fn timesheet(&self) -> &PatchedTimesheet {
         &self.timesheet
     }
 
+    /// Give back the store the repository was loaded from
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// The patches that have been applied to the timesheet
+    pub fn patches_loaded(&self) -> impl Iterator<Item = &PatchRef> {
+        self.patches_loaded.iter()
+    }
+
+    /// Patches that have been folded into a checkpoint. They are counted as
+    /// loaded, but may no longer be in the store.
+    pub fn folded_patches(&self) -> BTreeSet<PatchRef> {
+        self.checkpoints.values().flatten().cloned().collect()
+    }
+
+    /// Every patch that has been applied to the timesheet, in causal order.
+    /// Patches that have been folded into a checkpoint are left out.
+    pub fn history(&self) -> Result<Vec<Patch>, Vec<Error<S::Error>>> {
+        let folded = self.folded_patches();
+        let patches = self.get_patches(self.patches_loaded.difference(&folded).cloned())?;
+        Ok(history::causal_order(patches))
+    }
+
+    /// The patches that led to the current state of an event, in causal
+    /// order. Found by walking back from the event's latest patches, stopping
+    /// at patches that have been folded into a checkpoint.
+    pub fn event_history(&self, event: &EventRef) -> Result<Vec<Patch>, Vec<Error<S::Error>>> {
+        let patched_event = self.timesheet.events.get(event).ok_or_else(|| {
+            vec![Error::UnknownEvent {
+                event: event.clone(),
+            }]
+        })?;
+
+        let mut errors = Vec::new();
+        let mut seen = self.folded_patches();
+        let mut patches = Vec::new();
+        let mut to_visit: VecDeque<PatchRef> = patched_event.latest_patches().into_iter().collect();
+        while let Some(patch_ref) = to_visit.pop_front() {
+            if !seen.insert(patch_ref) {
+                continue;
+            }
+            match self.store.get_patch(&patch_ref) {
+                Ok(patch) => {
+                    to_visit.extend(patch.parents());
+                    patches.push(patch);
+                }
+                Err(source) => errors.push(Error::PatchNotFound {
+                    source,
+                    patch: patch_ref,
+                }),
+            }
+        }
+
+        if !errors.is_empty() {
+            Err(errors)
+        } else {
+            Ok(history::causal_order(patches))
+        }
+    }
+
+    fn get_patches(
+        &self,
+        patch_refs: impl Iterator<Item = PatchRef>,
+    ) -> Result<Vec<Patch>, Vec<Error<S::Error>>> {
+        let mut errors = Vec::new();
+        let mut patches = Vec::new();
+        for patch_ref in patch_refs {
+            match self.store.get_patch(&patch_ref) {
+                Ok(patch) => patches.push(patch),
+                Err(source) => errors.push(Error::PatchNotFound {
+                    source,
+                    patch: patch_ref,
+                }),
+            }
+        }
+        if !errors.is_empty() {
+            Err(errors)
+        } else {
+            Ok(patches)
+        }
+    }
+
+    /// Load the given patches and any of their ancestors that haven't been
+    /// loaded yet. Each patch is read from the store once, and then applied
+    /// in causal order.
     #[cfg_attr(feature = "flame_it", flame)]
     fn load_patches(
         &mut self,
@@ -127,44 +449,101 @@ where
     ) -> Result<(), Vec<Error<S::Error>>> {
         let mut errors = Vec::new();
 
-        let mut error_on_loading: BTreeSet<PatchRef> = BTreeSet::new();
-
-        let mut patches_to_load: VecDeque<PatchRef> = patches.collect();
-        while let Some(patch_ref) = patches_to_load.pop_front() {
-            // Don't load patches that have already been loaded
-            if self.patches_loaded.contains(&patch_ref) {
+        // Read every patch that needs to be loaded, following parents back to
+        // patches that have already been applied, or that are folded into a
+        // checkpoint
+        let mut fetched: BTreeMap<PatchRef, Patch> = BTreeMap::new();
+        let mut folded: BTreeSet<PatchRef> = BTreeSet::new();
+        let mut unreadable: BTreeMap<PatchRef, S::Error> = BTreeMap::new();
+        let mut to_fetch: Vec<PatchRef> = patches.collect();
+        while let Some(patch_ref) = to_fetch.pop() {
+            if self.patches_loaded.contains(&patch_ref)
+                || fetched.contains_key(&patch_ref)
+                || folded.contains(&patch_ref)
+                || unreadable.contains_key(&patch_ref)
+            {
                 continue;
             }
-
-            let patch = match self.store.get_patch(&patch_ref) {
-                Ok(p) => p,
-                Err(source) => {
-                    errors.push(Error::PatchNotFound {
-                        source,
-                        patch: patch_ref,
-                    });
-                    continue;
-                }
-            };
-
-            match self.load_patch(patch) {
-                Ok(()) => {}
-                Err(Error::MissingParentPatches { parents, .. }) => {
-                    for parent in parents {
-                        if !error_on_loading.contains(&parent) {
-                            patches_to_load.push_back(parent);
-                        }
+            match self.store.get_patch(&patch_ref) {
+                Ok(patch) => {
+                    if let Some(checkpoint) = &patch.checkpoint {
+                        folded.extend(checkpoint.patches());
                     }
-                    patches_to_load.push_back(patch_ref);
+                    to_fetch.extend(patch.parents());
+                    fetched.insert(patch_ref, patch);
                 }
-                Err(Error::PatchAlreadyLoaded { .. }) => {}
-                Err(patch_errors) => {
-                    errors.push(patch_errors);
-                    error_on_loading.insert(patch_ref);
+                Err(source) => {
+                    unreadable.insert(patch_ref, source);
                 }
             }
         }
 
+        // Folded patches may have been read before the checkpoint folding
+        // them was found. Either way, loading the checkpoint takes care of them.
+        fetched.retain(|patch_ref, _patch| !folded.contains(patch_ref));
+        let mut missing: BTreeSet<PatchRef> = BTreeSet::new();
+        for (patch_ref, source) in unreadable {
+            if !folded.contains(&patch_ref) {
+                errors.push(Error::PatchNotFound {
+                    source,
+                    patch: patch_ref,
+                });
+                missing.insert(patch_ref);
+            }
+        }
+
+        // Checkpoints don't have parents, but patches may depend on the
+        // patches they fold together, so they are loaded first
+        let (checkpoints, ordered): (Vec<Patch>, Vec<Patch>) =
+            history::causal_order(fetched.values().cloned())
+                .into_iter()
+                .partition(|patch| patch.checkpoint.is_some());
+        let ordered = checkpoints.into_iter().chain(ordered).collect::<Vec<_>>();
+
+        // Patches in a cycle never become ready, so they are left out of the
+        // causal order along with every patch that depends on them
+        let mut unordered: BTreeSet<PatchRef> = fetched.keys().cloned().collect();
+        for patch in ordered.iter() {
+            unordered.remove(patch.patch_ref());
+        }
+        let cycle = patches_in_cycles(&fetched, &unordered);
+        if !cycle.is_empty() {
+            errors.push(Error::PatchCycle {
+                patches: cycle.iter().cloned().collect(),
+            });
+        }
+
+        for patch in ordered {
+            let dangling: Vec<PatchRef> = patch
+                .parents()
+                .into_iter()
+                .filter(|parent| missing.contains(parent))
+                .collect();
+            if !dangling.is_empty() {
+                errors.push(Error::DanglingParents {
+                    patch: *patch.patch_ref(),
+                    parents: dangling,
+                });
+                continue;
+            }
+            // Patches that depend on a patch that failed to load are reported
+            // as missing their parents
+            if let Err(e) = self.load_patch(patch) {
+                errors.push(e);
+            }
+        }
+
+        for patch_ref in unordered.difference(&cycle) {
+            errors.push(Error::MissingParentPatches {
+                patch: *patch_ref,
+                parents: fetched[patch_ref]
+                    .parents()
+                    .into_iter()
+                    .filter(|parent| !self.patches_loaded.contains(parent))
+                    .collect(),
+            });
+        }
+
         if !errors.is_empty() {
             Err(errors)
         } else {
@@ -184,22 +563,67 @@ where
     }
 }
 
-use crate::store::sync_folder_store::{SyncFolderStore, SyncFolderStoreError};
+/// Whether `snapshot` could have been taken of `store`. A snapshot of another
+/// store, or of this one before it was restored from a backup, has patches
+/// the store doesn't, and would bring back events that aren't there.
+///
+/// Every patch in the snapshot must still be in the store, unless a
+/// checkpoint folded it and it was retired. The heads of the snapshot must
+/// also still be reachable from this device's meta, either directly or
+/// through patches added since the snapshot was taken.
+fn snapshot_matches_store<S: Store>(store: &S, snapshot: &Snapshot) -> bool {
+    let folded: BTreeSet<PatchRef> = snapshot.checkpoints.values().flatten().cloned().collect();
+    let all_stored = snapshot
+        .patches()
+        .iter()
+        .filter(|patch_ref| !folded.contains(patch_ref))
+        .all(|patch_ref| store.has_patch(patch_ref).unwrap_or(false));
+    if !all_stored {
+        return false;
+    }
 
-impl Repository<SyncFolderStore> {
-    #[cfg_attr(feature = "flame_it", flame)]
-    pub fn try_sync_data(&mut self) -> Result<(), Vec<Error<SyncFolderStoreError>>> {
-        let metas = self
-            .store
-            .get_other_metas()
-            .context(IOError {})
-            .map_err(|e| vec![e])?;
+    let meta = match store.get_meta() {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    let mut unreached = snapshot.heads.clone();
+    let mut visited = BTreeSet::new();
+    let mut to_visit: Vec<PatchRef> = meta.patches().cloned().collect();
+    while let Some(patch_ref) = to_visit.pop() {
+        if !visited.insert(patch_ref) {
+            continue;
+        }
+        unreached.remove(&patch_ref);
 
-        let patches_to_load: Vec<PatchRef> = metas
-            .filter_map(|x| x.ok())
-            .flat_map(|meta| meta.patches().copied().collect::<Vec<_>>().into_iter())
-            .collect();
+        // Heads aren't ancestors of other patches in the snapshot, so only
+        // new patches can lead to them
+        if snapshot.patches().contains(&patch_ref) {
+            continue;
+        }
+        if let Ok(patch) = store.get_patch(&patch_ref) {
+            to_visit.extend(patch.parents());
+        }
+    }
+    unreached.is_empty()
+}
 
-        self.load_patches(patches_to_load.into_iter())
+/// Finds the patches in `unordered` that are part of a cycle, rather than
+/// only depending on one. Patches that no other unordered patch depends on
+/// can't be in a cycle, so they are removed until only cycles remain.
+fn patches_in_cycles(
+    patches: &BTreeMap<PatchRef, Patch>,
+    unordered: &BTreeSet<PatchRef>,
+) -> BTreeSet<PatchRef> {
+    let mut remaining = unordered.clone();
+    loop {
+        let depended_on: BTreeSet<PatchRef> = remaining
+            .iter()
+            .flat_map(|patch_ref| patches[patch_ref].parents())
+            .collect();
+        let before = remaining.len();
+        remaining.retain(|patch_ref| depended_on.contains(patch_ref));
+        if remaining.len() == before {
+            return remaining;
+        }
     }
 }
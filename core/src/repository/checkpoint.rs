@@ -0,0 +1,184 @@
+//! Folding old patches into checkpoints, so that the patch folder doesn't grow
+//! without bound.
+//!
+//! Compacting history is done in two steps. First, every patch that all
+//! devices have acknowledged in their `Meta` is folded into a new checkpoint
+//! patch, which holds the state of the timesheet after applying them. Devices
+//! load the checkpoint instead of the patches it folds together, and stop
+//! listing those patches in their `Meta`. Once every device's `Meta`
+//! acknowledges the checkpoint, no device needs the folded patches anymore,
+//! and their files can be retired.
+
+use super::{snapshot::Snapshot, Error, IOError, LoadMeta, RemovePatch, Repository};
+use crate::{store::SyncStore, Meta, Patch, PatchRef, Store};
+use snafu::ResultExt;
+use std::{collections::BTreeSet, mem};
+
+type Errors<S> = Vec<Error<<S as Store>::Error>>;
+
+impl<S> Repository<S>
+where
+    S: SyncStore,
+    <S as Store>::Error: 'static,
+{
+    /// Create a checkpoint patch folding together every patch that all
+    /// devices have acknowledged. Returns `None` if there is nothing that
+    /// hasn't already been folded into a checkpoint.
+    ///
+    /// The checkpoint is not added to the repository; it should be passed to
+    /// `add_patch` like any other patch.
+    pub fn checkpoint(&mut self) -> Result<Option<Patch>, Errors<S>> {
+        let mut acknowledged: Option<BTreeSet<PatchRef>> = None;
+        for meta in self.all_metas()? {
+            let by_device = self.acknowledged_by(&meta)?;
+            acknowledged = Some(match acknowledged {
+                Some(patches) => patches.intersection(&by_device).cloned().collect(),
+                None => by_device,
+            });
+        }
+        let acknowledged = acknowledged.unwrap_or_default();
+
+        let mut already_folded = self.folded_patches();
+        already_folded.extend(self.checkpoints.keys());
+        if acknowledged.is_subset(&already_folded) {
+            return Ok(None);
+        }
+
+        // Every ancestor of an acknowledged patch is acknowledged as well, so
+        // replaying them gives the state the checkpoint should hold
+        let snapshot = self.replay(acknowledged.into_iter())?;
+
+        Ok(Some(Patch::new().checkpoint(snapshot)))
+    }
+
+    /// The state of a repository that has loaded only `patches` and their
+    /// ancestors. The patches are loaded on top of an empty timesheet, and the
+    /// state of this repository is put back afterwards.
+    fn replay(&mut self, patches: impl Iterator<Item = PatchRef>) -> Result<Snapshot, Errors<S>> {
+        let patches_loaded = mem::take(&mut self.patches_loaded);
+        let heads = mem::take(&mut self.heads);
+        let checkpoints = mem::take(&mut self.checkpoints);
+        let timesheet = mem::take(&mut self.timesheet);
+
+        let loaded = self.load_patches(patches);
+        let snapshot = self.timesheet.to_snapshot(&self.patches_loaded);
+
+        self.patches_loaded = patches_loaded;
+        self.heads = heads;
+        self.checkpoints = checkpoints;
+        self.timesheet = timesheet;
+
+        loaded.map(|()| snapshot)
+    }
+
+    /// Patches that can be deleted from the store. These are the patches
+    /// folded into a checkpoint that every device has acknowledged, as those
+    /// devices will load the checkpoint instead.
+    pub fn retirable_patches(&self) -> Result<BTreeSet<PatchRef>, Errors<S>> {
+        let mut acknowledged = Vec::new();
+        for meta in self.all_metas()? {
+            acknowledged.push(self.acknowledged_by(&meta)?);
+        }
+
+        Ok(self
+            .checkpoints
+            .iter()
+            .filter(|(checkpoint, _folded)| {
+                acknowledged
+                    .iter()
+                    .all(|by_device| by_device.contains(checkpoint))
+            })
+            .flat_map(|(_checkpoint, folded)| folded.iter().cloned())
+            .collect())
+    }
+
+    /// Delete every patch returned by `retirable_patches` from the store
+    pub fn retire_patches(&mut self) -> Result<BTreeSet<PatchRef>, Errors<S>> {
+        let retirable = self.retirable_patches()?;
+        let mut errors = Vec::new();
+        for patch_ref in retirable.iter() {
+            if let Err(e) = self
+                .store
+                .remove_patch(patch_ref)
+                .context(RemovePatch { patch: *patch_ref })
+            {
+                errors.push(e);
+            }
+        }
+        if !errors.is_empty() {
+            Err(errors)
+        } else {
+            Ok(retirable)
+        }
+    }
+
+    /// The metas of every device, including this one. It isn't safe to fold
+    /// or retire patches without knowing what every device has seen, so this
+    /// fails if any of them can't be read.
+    fn all_metas(&self) -> Result<Vec<Meta>, Errors<S>> {
+        let mut metas = vec![self
+            .store
+            .get_meta()
+            .context(LoadMeta {})
+            .map_err(|e| vec![e])?];
+
+        let mut errors = Vec::new();
+        let other_metas = self
+            .store
+            .get_other_metas()
+            .context(IOError {})
+            .map_err(|e| vec![e])?;
+        for meta in other_metas.into_values() {
+            match meta.context(LoadMeta {}) {
+                Ok(meta) => metas.push(meta),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if !errors.is_empty() {
+            Err(errors)
+        } else {
+            Ok(metas)
+        }
+    }
+
+    /// The patches listed in `meta`, along with all of their ancestors and
+    /// the patches folded into any checkpoint among them
+    fn acknowledged_by(&self, meta: &Meta) -> Result<BTreeSet<PatchRef>, Errors<S>> {
+        let folded = self.folded_patches();
+        let mut errors = Vec::new();
+        let mut acknowledged = BTreeSet::new();
+        let mut to_visit: Vec<PatchRef> = meta.patches().cloned().collect();
+        while let Some(patch_ref) = to_visit.pop() {
+            if !acknowledged.insert(patch_ref) {
+                continue;
+            }
+            if let Some(folded_by_checkpoint) = self.checkpoints.get(&patch_ref) {
+                acknowledged.extend(folded_by_checkpoint);
+                continue;
+            }
+            // The ancestors of a folded patch have been folded as well
+            if folded.contains(&patch_ref) {
+                continue;
+            }
+            match self.store.get_patch(&patch_ref) {
+                Ok(patch) => {
+                    if let Some(checkpoint) = &patch.checkpoint {
+                        acknowledged.extend(checkpoint.patches());
+                    }
+                    to_visit.extend(patch.parents());
+                }
+                Err(source) => errors.push(Error::PatchNotFound {
+                    source,
+                    patch: patch_ref,
+                }),
+            }
+        }
+
+        if !errors.is_empty() {
+            Err(errors)
+        } else {
+            Ok(acknowledged)
+        }
+    }
+}
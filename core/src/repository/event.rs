@@ -1,7 +1,11 @@
-use crate::{Event, PatchRef, Tag};
+use crate::{
+    repository::snapshot::{AttributeRecord, EventSnapshot, NoteRecord, StartRecord, ValueRecord},
+    timesheet::{AttributeValue, Attributes},
+    Event, PatchRef, Tag,
+};
 use chrono::{DateTime, Utc};
 use snafu::{ensure, Snafu};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Default, Clone, Debug)]
 pub struct PatchedEvent {
@@ -9,6 +13,15 @@ pub struct PatchedEvent {
     starts_removed: BTreeSet<(PatchRef, DateTime<Utc>)>,
     tags_added: BTreeSet<(PatchRef, String)>,
     tags_removed: BTreeSet<(PatchRef, String)>,
+    /// Each note, along with when the patch that added it was created
+    notes_added: BTreeMap<(PatchRef, String), Option<DateTime<Utc>>>,
+    notes_removed: BTreeSet<(PatchRef, String)>,
+    attributes_added: BTreeSet<(PatchRef, String, String)>,
+    attributes_removed: BTreeSet<(PatchRef, String, String)>,
+
+    /// The patches that removed this event. An event with any removals is
+    /// treated as deleted, no matter what else has been applied to it.
+    removed_by: BTreeSet<PatchRef>,
 
     /// Stores the latest patches that have been applied. Will generally be a
     /// single patch, but if multiple patches were created asynchronously, there
@@ -26,6 +39,29 @@ pub enum Error {
     NoStartTimes,
 }
 
+/// Decides what happens when concurrent edits leave an event with more than
+/// one start time.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ResolutionPolicy {
+    /// Report the event as a `MultipleStartTimes` conflict
+    Strict,
+
+    /// Pick one of the starts the same way on every device. Starts whose patch
+    /// has been built upon by a later patch to the event are preferred over
+    /// starts that no other patch has seen, and the greatest `PatchRef` breaks
+    /// any remaining tie.
+    Deterministic,
+}
+
+/// A start time that lost to another start time during resolution
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SupersededStart {
+    pub patch: PatchRef,
+    pub time: DateTime<Utc>,
+    pub winning_patch: PatchRef,
+    pub winning_time: DateTime<Utc>,
+}
+
 impl PatchedEvent {
     pub fn new() -> Self {
         Self {
@@ -33,10 +69,48 @@ impl PatchedEvent {
             starts_removed: BTreeSet::new(),
             tags_added: BTreeSet::new(),
             tags_removed: BTreeSet::new(),
+            notes_added: BTreeMap::new(),
+            notes_removed: BTreeSet::new(),
+            attributes_added: BTreeSet::new(),
+            attributes_removed: BTreeSet::new(),
+            removed_by: BTreeSet::new(),
             latest_patches: BTreeSet::new(),
         }
     }
 
+    /// Add the state of `other`, which was stored in a checkpoint folding
+    /// together the `folded` patches. Patches in `loaded` have already been
+    /// applied to this event.
+    pub fn merge_checkpoint(
+        &mut self,
+        other: PatchedEvent,
+        folded: &BTreeSet<PatchRef>,
+        loaded: &BTreeSet<PatchRef>,
+    ) {
+        // A folded patch that isn't latest in the checkpoint was built upon by
+        // another folded patch. Latest patches that were already loaded are
+        // left alone, as this event knows whether they have been built upon
+        // since.
+        let other_latest = &other.latest_patches;
+        self.latest_patches
+            .retain(|patch_ref| !folded.contains(patch_ref) || other_latest.contains(patch_ref));
+        self.latest_patches.extend(
+            other_latest
+                .iter()
+                .filter(|patch_ref| !loaded.contains(patch_ref)),
+        );
+
+        self.starts_added.extend(other.starts_added);
+        self.starts_removed.extend(other.starts_removed);
+        self.tags_added.extend(other.tags_added);
+        self.tags_removed.extend(other.tags_removed);
+        self.notes_added.extend(other.notes_added);
+        self.notes_removed.extend(other.notes_removed);
+        self.attributes_added.extend(other.attributes_added);
+        self.attributes_removed.extend(other.attributes_removed);
+        self.removed_by.extend(other.removed_by);
+    }
+
     /// Remove patch from latest_patches, meaning that it has been referenced by another
     /// patch.
     pub fn remove_patch_from_latest(&mut self, patch: &PatchRef) {
@@ -56,6 +130,11 @@ impl PatchedEvent {
         self.starts_removed.insert((patch, datetime));
     }
 
+    /// Whether `patch` added the start `datetime` to this event
+    pub fn start_added_by(&self, patch: &PatchRef, datetime: &DateTime<Utc>) -> bool {
+        self.starts_added.contains(&(*patch, *datetime))
+    }
+
     pub fn starts(&self) -> BTreeSet<(PatchRef, DateTime<Utc>)> {
         self.starts_added
             .difference(&self.starts_removed)
@@ -71,6 +150,11 @@ impl PatchedEvent {
         self.tags_removed.insert((patch, tag));
     }
 
+    /// Whether `patch` added `tag` to this event
+    pub fn tag_added_by(&self, patch: &PatchRef, tag: &str) -> bool {
+        self.tags_added.contains(&(*patch, tag.to_string()))
+    }
+
     pub fn tags(&self) -> BTreeSet<(PatchRef, Tag)> {
         self.tags_added
             .difference(&self.tags_removed)
@@ -78,26 +162,222 @@ impl PatchedEvent {
             .collect()
     }
 
+    /// Add a note, written at `written_at` if the patch adding it records
+    /// when it was created
+    pub fn add_note(&mut self, patch: PatchRef, note: String, written_at: Option<DateTime<Utc>>) {
+        self.notes_added.insert((patch, note), written_at);
+    }
+
+    pub fn remove_note(&mut self, patch: PatchRef, note: String) {
+        self.notes_removed.insert((patch, note));
+    }
+
+    /// Whether `patch` added `note` to this event
+    pub fn note_added_by(&self, patch: &PatchRef, note: &str) -> bool {
+        self.notes_added.contains_key(&(*patch, note.to_string()))
+    }
+
+    /// The notes on the event, in the order they were written. Notes from
+    /// patches that don't record when they were created come first, and the
+    /// `PatchRef` breaks ties, so every device lists them in the same order.
+    pub fn notes(&self) -> Vec<(PatchRef, String)> {
+        let mut notes: Vec<_> = self
+            .notes_added
+            .iter()
+            .filter(|(patch_and_note, _written_at)| !self.notes_removed.contains(patch_and_note))
+            .map(|((patch, note), written_at)| (*written_at, *patch, note.clone()))
+            .collect();
+        notes.sort();
+        notes
+            .into_iter()
+            .map(|(_written_at, patch, note)| (patch, note))
+            .collect()
+    }
+
+    pub fn add_attribute(&mut self, patch: PatchRef, key: String, value: String) {
+        self.attributes_added.insert((patch, key, value));
+    }
+
+    pub fn remove_attribute(&mut self, patch: PatchRef, key: String, value: String) {
+        self.attributes_removed.insert((patch, key, value));
+    }
+
+    /// Whether `patch` set the attribute `key` to `value` on this event
+    pub fn attribute_added_by(&self, patch: &PatchRef, key: &str, value: &str) -> bool {
+        self.attributes_added
+            .contains(&(*patch, key.to_string(), value.to_string()))
+    }
+
+    /// Every attribute currently set on the event, along with the patch that
+    /// added it. A key may have more than one value.
+    pub fn attributes(&self) -> BTreeSet<(PatchRef, String, String)> {
+        self.attributes_added
+            .difference(&self.attributes_removed)
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&mut self, patch: PatchRef) {
+        self.removed_by.insert(patch);
+    }
+
+    pub fn is_removed(&self) -> bool {
+        !self.removed_by.is_empty()
+    }
+
     pub fn latest_patches(&self) -> BTreeSet<PatchRef> {
         self.latest_patches.clone()
     }
 
     pub fn flatten(&self) -> Result<Event, Error> {
+        self.flatten_with(ResolutionPolicy::Strict)
+            .map(|(event, _superseded)| event)
+    }
+
+    /// Flatten the event, resolving multiple start times according to `policy`.
+    /// Any starts that were discarded are returned alongside the event.
+    pub fn flatten_with(
+        &self,
+        policy: ResolutionPolicy,
+    ) -> Result<(Event, Vec<SupersededStart>), Error> {
         let starts = self.starts();
-        ensure!(starts.len() < 2, MultipleStartTimes);
         ensure!(!starts.is_empty(), NoStartTimes);
-        let start = starts
+        ensure!(
+            starts.len() < 2 || policy != ResolutionPolicy::Strict,
+            MultipleStartTimes
+        );
+        let (winning_patch, start) = starts
             .iter()
-            .map(|patch_and_dt| patch_and_dt.1)
-            .next()
-            .expect("should be exactly one start");
+            .cloned()
+            .max_by_key(|(patch_ref, _dt)| (!self.latest_patches.contains(patch_ref), *patch_ref))
+            .expect("should be at least one start");
+        let superseded = starts
+            .into_iter()
+            .filter(|(patch_ref, dt)| (*patch_ref, *dt) != (winning_patch, start))
+            .map(|(patch, time)| SupersededStart {
+                patch,
+                time,
+                winning_patch,
+                winning_time: start,
+            })
+            .collect();
         let tags = self
             .tags_added
             .difference(&self.tags_removed)
             .cloned()
             .map(|patch_and_tag| patch_and_tag.1)
             .collect();
-        Ok(Event::new(start, tags))
+        let notes = self
+            .notes()
+            .into_iter()
+            .map(|patch_and_note| patch_and_note.1)
+            .collect();
+        let event = Event::new(start, tags)
+            .with_notes(notes)
+            .with_attributes(attributes_of(self));
+        Ok((event, superseded))
+    }
+}
+
+/// The attributes currently set on `event`, with their values typed
+pub(crate) fn attributes_of(event: &PatchedEvent) -> Attributes {
+    let mut attributes = Attributes::new();
+    for (_patch_ref, key, value) in event.attributes() {
+        let value = AttributeValue::from(value.as_str());
+        attributes.entry(key).or_default().insert(value);
+    }
+    attributes
+}
+
+impl From<&PatchedEvent> for EventSnapshot {
+    fn from(event: &PatchedEvent) -> Self {
+        let starts = |set: &BTreeSet<(PatchRef, DateTime<Utc>)>| {
+            set.iter()
+                .map(|(patch, time)| StartRecord {
+                    patch: *patch,
+                    time: *time,
+                })
+                .collect()
+        };
+        let values = |set: &BTreeSet<(PatchRef, String)>| {
+            set.iter()
+                .map(|(patch, value)| ValueRecord {
+                    patch: *patch,
+                    value: value.clone(),
+                })
+                .collect()
+        };
+        let notes = |map: &BTreeMap<(PatchRef, String), Option<DateTime<Utc>>>| {
+            map.iter()
+                .map(|((patch, value), written_at)| NoteRecord {
+                    patch: *patch,
+                    value: value.clone(),
+                    written_at: *written_at,
+                })
+                .collect()
+        };
+        let attributes = |set: &BTreeSet<(PatchRef, String, String)>| {
+            set.iter()
+                .map(|(patch, key, value)| AttributeRecord {
+                    patch: *patch,
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect()
+        };
+        Self {
+            removed_by: event.removed_by.clone(),
+            latest_patches: event.latest_patches.clone(),
+            starts_added: starts(&event.starts_added),
+            starts_removed: starts(&event.starts_removed),
+            tags_added: values(&event.tags_added),
+            tags_removed: values(&event.tags_removed),
+            notes_added: notes(&event.notes_added),
+            notes_removed: values(&event.notes_removed),
+            attributes_added: attributes(&event.attributes_added),
+            attributes_removed: attributes(&event.attributes_removed),
+        }
+    }
+}
+
+impl From<EventSnapshot> for PatchedEvent {
+    fn from(snapshot: EventSnapshot) -> Self {
+        let starts = |records: Vec<StartRecord>| {
+            records
+                .into_iter()
+                .map(|record| (record.patch, record.time))
+                .collect()
+        };
+        let values = |records: Vec<ValueRecord>| {
+            records
+                .into_iter()
+                .map(|record| (record.patch, record.value))
+                .collect()
+        };
+        let notes = |records: Vec<NoteRecord>| {
+            records
+                .into_iter()
+                .map(|record| ((record.patch, record.value), record.written_at))
+                .collect()
+        };
+        let attributes = |records: Vec<AttributeRecord>| {
+            records
+                .into_iter()
+                .map(|record| (record.patch, record.key, record.value))
+                .collect()
+        };
+        Self {
+            starts_added: starts(snapshot.starts_added),
+            starts_removed: starts(snapshot.starts_removed),
+            tags_added: values(snapshot.tags_added),
+            tags_removed: values(snapshot.tags_removed),
+            notes_added: notes(snapshot.notes_added),
+            notes_removed: values(snapshot.notes_removed),
+            attributes_added: attributes(snapshot.attributes_added),
+            attributes_removed: attributes(snapshot.attributes_removed),
+            removed_by: snapshot.removed_by,
+            latest_patches: snapshot.latest_patches,
+        }
     }
 }
 
@@ -141,4 +421,166 @@ mod test {
                 .collect()
         );
     }
+
+    #[test]
+    fn remove_note_from_event() {
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_note(patch_ref_a, "reviewed PR #412".into(), None);
+        event.add_note(patch_ref_b, "lunch with the team".into(), None);
+        event.remove_note(patch_ref_a, "reviewed PR #412".into());
+
+        assert_eq!(
+            event.notes(),
+            vec![(patch_ref_b, "lunch with the team".to_string())]
+        );
+    }
+
+    #[test]
+    fn notes_are_listed_in_the_order_they_were_written() {
+        let dt0 = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+        let dt1 = Utc.ymd(2019, 7, 23).and_hms(13, 0, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+        let patch_ref_c = Uuid::parse_str("0b6e4d3c-5c1f-4f7e-9a52-3e8c3b1e2f10").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt0);
+        event.add_note(patch_ref_a, "later".into(), Some(dt1));
+        event.add_note(patch_ref_b, "earlier".into(), Some(dt0));
+        event.add_note(patch_ref_c, "later".into(), Some(dt1));
+
+        // Identical notes from different patches are both kept
+        let flattened = event.flatten().unwrap();
+        assert_eq!(flattened.notes(), ["earlier", "later", "later"]);
+    }
+
+    #[test]
+    fn remove_attribute_from_event() {
+        let dt0 = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt0);
+        event.add_attribute(patch_ref_a, "client".into(), "acme".into());
+        event.add_attribute(patch_ref_a, "ticket".into(), "ENG-41".into());
+        event.remove_attribute(patch_ref_a, "ticket".into(), "ENG-41".into());
+        event.add_attribute(patch_ref_b, "ticket".into(), "ENG-42".into());
+
+        let flattened = event.flatten().unwrap();
+        assert!(flattened.has_attribute("client", "acme"));
+        assert!(flattened.has_attribute("ticket", "ENG-42"));
+        assert!(!flattened.has_attribute("ticket", "ENG-41"));
+    }
+
+    #[test]
+    fn attribute_values_are_typed() {
+        let dt0 = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt0);
+        event.add_attribute(patch_ref_a, "billable".into(), "true".into());
+        event.add_attribute(patch_ref_a, "hours".into(), "3".into());
+        event.add_attribute(patch_ref_a, "room".into(), "007".into());
+
+        let flattened = event.flatten().unwrap();
+        let values = |key: &str| {
+            flattened.attributes()[key]
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values("billable"), vec![AttributeValue::Bool(true)]);
+        assert_eq!(values("hours"), vec![AttributeValue::Integer(3)]);
+        assert_eq!(values("room"), vec![AttributeValue::Text("007".into())]);
+        assert!(flattened.has_attribute("hours", "3"));
+        assert!(!flattened.has_attribute("room", "7"));
+    }
+
+    #[test]
+    fn resolve_concurrent_starts() {
+        let dt0 = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+        let dt1 = Utc.ymd(2019, 7, 23).and_hms(12, 15, 0);
+        let dt2 = Utc.ymd(2019, 7, 23).and_hms(12, 30, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+        let patch_ref_c = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt0);
+        event.add_patch_to_latest(patch_ref_a);
+
+        // Two devices move the start at the same time
+        event.remove_start(patch_ref_a, dt0);
+        event.remove_patch_from_latest(&patch_ref_a);
+        event.add_start(patch_ref_b, dt1);
+        event.add_patch_to_latest(patch_ref_b);
+        event.add_start(patch_ref_c, dt2);
+        event.add_patch_to_latest(patch_ref_c);
+
+        assert_eq!(event.flatten().unwrap_err(), Error::MultipleStartTimes);
+
+        let (flattened, superseded) = event.flatten_with(ResolutionPolicy::Deterministic).unwrap();
+        assert_eq!(flattened.start(), &dt1);
+        assert_eq!(
+            superseded,
+            vec![SupersededStart {
+                patch: patch_ref_c,
+                time: dt2,
+                winning_patch: patch_ref_b,
+                winning_time: dt1,
+            }]
+        );
+
+        // A start that a later patch was built on wins over one nobody has seen
+        event.remove_patch_from_latest(&patch_ref_c);
+        let (flattened, _superseded) = event.flatten_with(ResolutionPolicy::Deterministic).unwrap();
+        assert_eq!(flattened.start(), &dt2);
+    }
+
+    #[test]
+    fn remove_event() {
+        let dt0 = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt0);
+        assert!(!event.is_removed());
+
+        event.remove(patch_ref_b);
+        assert!(event.is_removed());
+    }
+
+    #[test]
+    fn merge_checkpoint_into_partially_loaded_event() {
+        let dt = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+        let patch_ref_a = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_b = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+        // Only the first of the folded patches has been loaded
+        let mut event = PatchedEvent::new();
+        event.add_start(patch_ref_a, dt);
+        event.add_patch_to_latest(patch_ref_a);
+
+        let mut folded_event = event.clone();
+        folded_event.add_tag(patch_ref_b, "work".into());
+        folded_event.remove_patch_from_latest(&patch_ref_a);
+        folded_event.add_patch_to_latest(patch_ref_b);
+
+        let folded = [patch_ref_a, patch_ref_b].iter().cloned().collect();
+        let loaded = [patch_ref_a].iter().cloned().collect();
+        event.merge_checkpoint(folded_event.clone(), &folded, &loaded);
+
+        assert_eq!(event.starts(), folded_event.starts());
+        assert_eq!(event.tags(), folded_event.tags());
+        assert_eq!(
+            event.latest_patches(),
+            [patch_ref_b].iter().cloned().collect()
+        );
+    }
 }
@@ -0,0 +1,82 @@
+use crate::{Patch, PatchRef};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Orders patches so that every patch comes after all of its parents. Patches
+/// that don't depend on each other are ordered by when they were created, and
+/// then by their `PatchRef`, so the result is the same on every device.
+/// Parents that are not in `patches` are ignored.
+pub fn causal_order(patches: impl IntoIterator<Item = Patch>) -> Vec<Patch> {
+    let mut patches: BTreeMap<PatchRef, Patch> = patches
+        .into_iter()
+        .map(|patch| (*patch.patch_ref(), patch))
+        .collect();
+
+    let mut waiting_on: BTreeMap<PatchRef, BTreeSet<PatchRef>> = BTreeMap::new();
+    let mut children: BTreeMap<PatchRef, Vec<PatchRef>> = BTreeMap::new();
+    for (patch_ref, patch) in patches.iter() {
+        let parents: BTreeSet<PatchRef> = patch
+            .parents()
+            .into_iter()
+            .filter(|parent| patches.contains_key(parent))
+            .collect();
+        for parent in parents.iter() {
+            children.entry(*parent).or_default().push(*patch_ref);
+        }
+        waiting_on.insert(*patch_ref, parents);
+    }
+
+    let sort_key = |patch: &Patch| -> (Option<DateTime<Utc>>, PatchRef) {
+        (patch.created_at, *patch.patch_ref())
+    };
+
+    let mut ready: BTreeSet<(Option<DateTime<Utc>>, PatchRef)> = waiting_on
+        .iter()
+        .filter(|(_patch_ref, parents)| parents.is_empty())
+        .map(|(patch_ref, _parents)| sort_key(&patches[patch_ref]))
+        .collect();
+
+    let mut ordered = Vec::with_capacity(patches.len());
+    while let Some(next) = ready.iter().next().cloned() {
+        ready.remove(&next);
+        let patch_ref = next.1;
+        for child in children.remove(&patch_ref).unwrap_or_default() {
+            let parents = waiting_on.get_mut(&child).expect("child to be waiting");
+            parents.remove(&patch_ref);
+            if parents.is_empty() {
+                ready.insert(sort_key(&patches[&child]));
+            }
+        }
+        ordered.push(patch_ref);
+    }
+
+    ordered
+        .into_iter()
+        .filter_map(|patch_ref| patches.remove(&patch_ref))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    #[test]
+    fn parents_come_before_children() {
+        let patch_ref_a = Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+        let patch_ref_b = Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+        let patch_ref_c = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+        let dt = Utc.ymd(2019, 7, 23).and_hms(12, 0, 0);
+
+        let a = Patch::with_id(patch_ref_a).create_event("a".into(), dt, vec![]);
+        let b = Patch::with_id(patch_ref_b).add_tag(patch_ref_a, "a".into(), "work".into());
+        let c = Patch::with_id(patch_ref_c).add_tag(patch_ref_b, "a".into(), "coding".into());
+
+        let ordered: Vec<PatchRef> = causal_order(vec![c, a, b])
+            .iter()
+            .map(|patch| *patch.patch_ref())
+            .collect();
+        assert_eq!(ordered, vec![patch_ref_a, patch_ref_b, patch_ref_c]);
+    }
+}
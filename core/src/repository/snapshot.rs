@@ -0,0 +1,96 @@
+use crate::{EventRef, PatchRef};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Changed whenever the layout of a snapshot changes. Snapshots with a
+/// different version are ignored, and rebuilt from the patches.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// The state of a `PatchedTimesheet` after a set of patches has been applied.
+/// Keeping one around locally means a repository can be loaded by applying
+/// only the patches that are new since the snapshot was taken.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Snapshot {
+    pub(crate) version: u32,
+    pub(crate) patches: BTreeSet<PatchRef>,
+
+    /// The patches that no other applied patch depends on
+    #[serde(default)]
+    pub(crate) heads: BTreeSet<PatchRef>,
+
+    #[serde(default)]
+    pub(crate) events: BTreeMap<EventRef, EventSnapshot>,
+
+    /// The checkpoints that had been loaded, and the patches each of them
+    /// folds together
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) checkpoints: BTreeMap<PatchRef, BTreeSet<PatchRef>>,
+}
+
+impl Snapshot {
+    /// The patches that had been applied when the snapshot was taken
+    pub fn patches(&self) -> &BTreeSet<PatchRef> {
+        &self.patches
+    }
+
+    /// Whether the snapshot was written by this version of augr
+    pub fn is_current_version(&self) -> bool {
+        self.version == SNAPSHOT_VERSION
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct EventSnapshot {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub removed_by: BTreeSet<PatchRef>,
+    #[serde(default)]
+    pub latest_patches: BTreeSet<PatchRef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub starts_added: Vec<StartRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub starts_removed: Vec<StartRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags_added: Vec<ValueRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags_removed: Vec<ValueRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes_added: Vec<NoteRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes_removed: Vec<ValueRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributes_added: Vec<AttributeRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributes_removed: Vec<AttributeRecord>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StartRecord {
+    pub patch: PatchRef,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ValueRecord {
+    pub patch: PatchRef,
+    pub value: String,
+}
+
+/// A note, along with when it was written. Checkpoints made before notes were
+/// ordered don't record it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NoteRecord {
+    pub patch: PatchRef,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AttributeRecord {
+    pub patch: PatchRef,
+    pub key: String,
+    pub value: String,
+}
@@ -0,0 +1,117 @@
+//! Loading the patches that other devices have saved to a shared store.
+
+use super::{Error, IOError, LoadReport, Repository};
+use crate::{store::SyncStore, PatchRef, Store};
+use snafu::ResultExt;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// What synchronizing with each of the other devices did
+#[derive(Debug)]
+pub struct SyncReport<IE>
+where
+    IE: std::error::Error + 'static,
+{
+    pub devices: BTreeMap<String, DeviceSync<IE>>,
+}
+
+/// What synchronizing with a single device did
+#[derive(Debug)]
+pub struct DeviceSync<IE>
+where
+    IE: std::error::Error + 'static,
+{
+    /// Patches that were loaded for the first time, including ancestors of
+    /// the patches the device listed
+    pub new: BTreeSet<PatchRef>,
+
+    /// Patches that the device depends on, but that couldn't be read from
+    /// the store
+    pub missing: BTreeSet<PatchRef>,
+
+    /// Patches that were read, but couldn't be applied to the timesheet
+    pub failed: BTreeSet<PatchRef>,
+
+    /// Everything that went wrong, including the device's meta not being
+    /// readable
+    pub errors: Vec<Error<IE>>,
+}
+
+impl<IE> DeviceSync<IE>
+where
+    IE: std::error::Error + 'static,
+{
+    fn new() -> Self {
+        Self {
+            new: BTreeSet::new(),
+            missing: BTreeSet::new(),
+            failed: BTreeSet::new(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl<IE> SyncReport<IE>
+where
+    IE: std::error::Error + 'static,
+{
+    /// Whether every device was synchronized without errors
+    pub fn is_complete(&self) -> bool {
+        self.devices.values().all(|device| device.errors.is_empty())
+    }
+
+    /// Every patch that was loaded for the first time
+    pub fn new_patches(&self) -> BTreeSet<PatchRef> {
+        self.devices
+            .values()
+            .flat_map(|device| device.new.iter().cloned())
+            .collect()
+    }
+
+    /// The errors from every device
+    pub fn into_errors(self) -> Vec<Error<IE>> {
+        self.devices
+            .into_values()
+            .flat_map(|device| device.errors)
+            .collect()
+    }
+}
+
+impl<S> Repository<S>
+where
+    S: SyncStore,
+    <S as Store>::Error: 'static,
+{
+    /// Load the patches listed in the metas of every other device. Devices
+    /// are synchronized one at a time, so that problems can be traced back
+    /// to the device that caused them. Only failing to list the other
+    /// devices is an error; everything else is reported per device.
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn try_sync_data(&mut self) -> Result<SyncReport<S::Error>, Error<S::Error>> {
+        let metas = self.store.get_other_metas().context(IOError {})?;
+
+        let mut devices = BTreeMap::new();
+        for (device_id, meta) in metas {
+            let mut device = DeviceSync::new();
+            match meta {
+                Ok(meta) => {
+                    let loaded_before = self.patches_loaded.clone();
+                    if let Err(errors) = self.load_patches(meta.patches().cloned()) {
+                        let skipped = LoadReport::from_errors(errors);
+                        device.missing = skipped.missing;
+                        device.failed = skipped.quarantined;
+                        device.errors = skipped.errors;
+                    }
+                    device.new = self
+                        .patches_loaded
+                        .difference(&loaded_before)
+                        .cloned()
+                        .collect();
+                }
+                Err(source) => device.errors.push(Error::LoadMeta { source }),
+            }
+            devices.insert(device_id, device);
+        }
+
+        Ok(SyncReport { devices })
+    }
+}
@@ -1,10 +1,17 @@
 use crate::{
-    repository::event::{Error as EventError, PatchedEvent},
-    EventRef, Patch, PatchRef, Timesheet,
+    repository::{
+        event::{Error as EventError, PatchedEvent, ResolutionPolicy},
+        snapshot::{Snapshot, SNAPSHOT_VERSION},
+    },
+    store::patch::{
+        AddAttribute, AddNote, AddStart, AddTag, RemoveAttribute, RemoveEvent, RemoveNote,
+        RemoveStart, RemoveTag,
+    },
+    EventRef, Patch, PatchRef, Tag, Timesheet,
 };
 use chrono::{DateTime, Utc};
 use snafu::Snafu;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// This representation of a timesheet is an intermediate form that allows
 /// an event to have multiple starts
@@ -33,6 +40,67 @@ pub enum Error {
 
     #[snafu(display("Two events were created with the same id {}", id))]
     DuplicateEventId { id: EventRef },
+
+    #[snafu(display("Patch {} deletes event {}, which can't be undone", patch, event))]
+    IrreversibleRemoveEvent { patch: PatchRef, event: EventRef },
+
+    #[snafu(display(
+        "Patch {} removes start {} from event {}, but patch {} did not add it",
+        patch,
+        time,
+        event,
+        added_by
+    ))]
+    UnknownStart {
+        patch: PatchRef,
+        event: EventRef,
+        added_by: PatchRef,
+        time: DateTime<Utc>,
+    },
+
+    #[snafu(display(
+        "Patch {} removes tag {} from event {}, but patch {} did not add it",
+        patch,
+        tag,
+        event,
+        added_by
+    ))]
+    UnknownTag {
+        patch: PatchRef,
+        event: EventRef,
+        added_by: PatchRef,
+        tag: Tag,
+    },
+
+    #[snafu(display(
+        "Patch {} removes note \"{}\" from event {}, but patch {} did not add it",
+        patch,
+        note,
+        event,
+        added_by
+    ))]
+    UnknownNote {
+        patch: PatchRef,
+        event: EventRef,
+        added_by: PatchRef,
+        note: String,
+    },
+
+    #[snafu(display(
+        "Patch {} removes attribute {}={} from event {}, but patch {} did not add it",
+        patch,
+        key,
+        value,
+        event,
+        added_by
+    ))]
+    UnknownAttribute {
+        patch: PatchRef,
+        event: EventRef,
+        added_by: PatchRef,
+        key: String,
+        value: String,
+    },
 }
 
 impl PatchedTimesheet {
@@ -42,6 +110,45 @@ impl PatchedTimesheet {
         }
     }
 
+    /// Capture the current state, recording that `patches` have been applied.
+    /// The heads and checkpoints are left for the repository to fill in.
+    pub(crate) fn to_snapshot(&self, patches: &BTreeSet<PatchRef>) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            patches: patches.clone(),
+            heads: BTreeSet::new(),
+            events: self
+                .events
+                .iter()
+                .map(|(event_ref, event)| (event_ref.clone(), event.into()))
+                .collect(),
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: Snapshot) -> Self {
+        Self {
+            events: snapshot
+                .events
+                .into_iter()
+                .map(|(event_ref, event)| (event_ref, event.into()))
+                .collect(),
+        }
+    }
+
+    /// Add the state stored in a checkpoint. `loaded` holds the patches that
+    /// have already been applied; the checkpoint is expected to agree with
+    /// them, so they are only used to keep track of each event's latest
+    /// patches.
+    pub fn apply_checkpoint(&mut self, checkpoint: &Snapshot, loaded: &BTreeSet<PatchRef>) {
+        for (event_ref, event) in checkpoint.events.iter() {
+            self.events
+                .entry(event_ref.clone())
+                .or_default()
+                .merge_checkpoint(event.clone().into(), checkpoint.patches(), loaded);
+        }
+    }
+
     #[cfg_attr(feature = "flame_it", flame)]
     pub fn apply_patch(&mut self, patch: &Patch) -> Result<(), Vec<Error>> {
         // Verify patch. From this point on, we should have no errors, and `expect("valid patch")` indicates that
@@ -103,6 +210,81 @@ impl PatchedTimesheet {
             event.add_patch_to_latest(patch_ref.clone());
         }
 
+        for note_added in patch.add_note.iter() {
+            let event = self.events.get_mut(&note_added.event).expect("valid patch");
+            event.add_note(*patch_ref, note_added.note.clone(), patch.created_at);
+
+            // Update metadata
+            for parent in note_added.parents() {
+                event.remove_patch_from_latest(parent);
+            }
+            event.add_patch_to_latest(*patch_ref);
+        }
+        for note_removed in patch.remove_note.iter() {
+            let event = self
+                .events
+                .get_mut(&note_removed.event)
+                .expect("valid patch");
+            event.remove_note(note_removed.patch, note_removed.note.clone());
+
+            // Update metadata
+            event.remove_patch_from_latest(&note_removed.patch);
+            for parent in note_removed.parents() {
+                event.remove_patch_from_latest(parent);
+            }
+            event.add_patch_to_latest(*patch_ref);
+        }
+
+        for attribute_added in patch.add_attribute.iter() {
+            let event = self
+                .events
+                .get_mut(&attribute_added.event)
+                .expect("valid patch");
+            event.add_attribute(
+                *patch_ref,
+                attribute_added.key.clone(),
+                attribute_added.value.clone(),
+            );
+
+            // Update metadata
+            for parent in attribute_added.parents() {
+                event.remove_patch_from_latest(parent);
+            }
+            event.add_patch_to_latest(*patch_ref);
+        }
+        for attribute_removed in patch.remove_attribute.iter() {
+            let event = self
+                .events
+                .get_mut(&attribute_removed.event)
+                .expect("valid patch");
+            event.remove_attribute(
+                attribute_removed.patch,
+                attribute_removed.key.clone(),
+                attribute_removed.value.clone(),
+            );
+
+            // Update metadata
+            event.remove_patch_from_latest(&attribute_removed.patch);
+            for parent in attribute_removed.parents() {
+                event.remove_patch_from_latest(parent);
+            }
+            event.add_patch_to_latest(*patch_ref);
+        }
+
+        for event_removed in patch.remove_event.iter() {
+            let event = self
+                .events
+                .get_mut(&event_removed.event)
+                .expect("valid patch");
+            event.remove(*patch_ref);
+
+            // Update metadata
+            for parent in event_removed.parents() {
+                event.remove_patch_from_latest(parent);
+            }
+            event.add_patch_to_latest(*patch_ref);
+        }
+
         for new_event in patch.create_event.iter() {
             let mut event = PatchedEvent::new();
             event.add_start(patch_ref.clone(), new_event.start);
@@ -138,8 +320,8 @@ impl PatchedTimesheet {
             };
         }
         for start_removed in patch.remove_start.iter() {
-            match self.events.get(&start_removed.event) {
-                Some(_event) => {}
+            let event = match self.events.get(&start_removed.event) {
+                Some(event) => event,
                 None => {
                     errors.push(Error::UnknownEvent {
                         patch: *patch_ref,
@@ -148,21 +330,122 @@ impl PatchedTimesheet {
                     continue;
                 }
             };
+            if !event.start_added_by(&start_removed.patch, &start_removed.time) {
+                errors.push(Error::UnknownStart {
+                    patch: *patch_ref,
+                    event: start_removed.event.clone(),
+                    added_by: start_removed.patch,
+                    time: start_removed.time,
+                });
+            }
         }
 
         for tag_added in patch.add_tag.iter() {
-            self.events
-                .get(&tag_added.event)
-                .expect("no event for add-tag");
+            if !self.events.contains_key(&tag_added.event) {
+                errors.push(Error::UnknownEvent {
+                    patch: *patch_ref,
+                    event: tag_added.event.clone(),
+                });
+            }
         }
         for tag_removed in patch.remove_tag.iter() {
-            self.events
-                .get(&tag_removed.event)
-                .expect("no event for remove-tag");
+            let event = match self.events.get(&tag_removed.event) {
+                Some(event) => event,
+                None => {
+                    errors.push(Error::UnknownEvent {
+                        patch: *patch_ref,
+                        event: tag_removed.event.clone(),
+                    });
+                    continue;
+                }
+            };
+            if !event.tag_added_by(&tag_removed.patch, &tag_removed.tag) {
+                errors.push(Error::UnknownTag {
+                    patch: *patch_ref,
+                    event: tag_removed.event.clone(),
+                    added_by: tag_removed.patch,
+                    tag: tag_removed.tag.clone(),
+                });
+            }
         }
 
+        for note_added in patch.add_note.iter() {
+            if !self.events.contains_key(&note_added.event) {
+                errors.push(Error::UnknownEvent {
+                    patch: *patch_ref,
+                    event: note_added.event.clone(),
+                });
+            }
+        }
+        for note_removed in patch.remove_note.iter() {
+            let event = match self.events.get(&note_removed.event) {
+                Some(event) => event,
+                None => {
+                    errors.push(Error::UnknownEvent {
+                        patch: *patch_ref,
+                        event: note_removed.event.clone(),
+                    });
+                    continue;
+                }
+            };
+            if !event.note_added_by(&note_removed.patch, &note_removed.note) {
+                errors.push(Error::UnknownNote {
+                    patch: *patch_ref,
+                    event: note_removed.event.clone(),
+                    added_by: note_removed.patch,
+                    note: note_removed.note.clone(),
+                });
+            }
+        }
+
+        for attribute_added in patch.add_attribute.iter() {
+            if !self.events.contains_key(&attribute_added.event) {
+                errors.push(Error::UnknownEvent {
+                    patch: *patch_ref,
+                    event: attribute_added.event.clone(),
+                });
+            }
+        }
+        for attribute_removed in patch.remove_attribute.iter() {
+            let event = match self.events.get(&attribute_removed.event) {
+                Some(event) => event,
+                None => {
+                    errors.push(Error::UnknownEvent {
+                        patch: *patch_ref,
+                        event: attribute_removed.event.clone(),
+                    });
+                    continue;
+                }
+            };
+            if !event.attribute_added_by(
+                &attribute_removed.patch,
+                &attribute_removed.key,
+                &attribute_removed.value,
+            ) {
+                errors.push(Error::UnknownAttribute {
+                    patch: *patch_ref,
+                    event: attribute_removed.event.clone(),
+                    added_by: attribute_removed.patch,
+                    key: attribute_removed.key.clone(),
+                    value: attribute_removed.value.clone(),
+                });
+            }
+        }
+
+        for event_removed in patch.remove_event.iter() {
+            if !self.events.contains_key(&event_removed.event) {
+                errors.push(Error::UnknownEvent {
+                    patch: *patch_ref,
+                    event: event_removed.event.clone(),
+                });
+            }
+        }
+
+        let mut created_events = BTreeSet::new();
         for new_event in patch.create_event.iter() {
-            if self.events.get(&new_event.event).is_some() {
+            if self.events.contains_key(&new_event.event)
+                || !created_events.insert(&new_event.event)
+            {
                 errors.push(Error::DuplicateEventId {
                     id: new_event.event.clone(),
                 });
@@ -176,13 +459,193 @@ impl PatchedTimesheet {
         }
     }
 
+    /// Create a patch that reverts the changes `patch` made to the timesheet.
+    /// Changes that have since been reverted by other patches are skipped, so
+    /// the resulting patch may be empty.
+    pub fn inverse_patch(&self, patch: &Patch) -> Result<Patch, Vec<Error>> {
+        let mut errors = Vec::new();
+        let mut inverse = Patch::new();
+        let patch_ref = *patch.patch_ref();
+
+        for event_removed in patch.remove_event.iter() {
+            errors.push(Error::IrreversibleRemoveEvent {
+                patch: patch_ref,
+                event: event_removed.event.clone(),
+            });
+        }
+
+        let mut event = |event_ref: &EventRef| match self.events.get(event_ref) {
+            Some(event) => Some(event),
+            None => {
+                errors.push(Error::UnknownEvent {
+                    patch: patch_ref,
+                    event: event_ref.clone(),
+                });
+                None
+            }
+        };
+
+        for start_added in patch.add_start.iter() {
+            if let Some(event) = event(&start_added.event) {
+                if event.starts().contains(&(patch_ref, start_added.time)) {
+                    inverse.insert_remove_start(RemoveStart {
+                        parents: Some(event.latest_patches()),
+                        patch: patch_ref,
+                        event: start_added.event.clone(),
+                        time: start_added.time,
+                    });
+                }
+            }
+        }
+        for start_removed in patch.remove_start.iter() {
+            if let Some(event) = event(&start_removed.event) {
+                let already_added = event
+                    .starts()
+                    .iter()
+                    .any(|(_patch_ref, time)| *time == start_removed.time);
+                if !already_added {
+                    inverse.insert_add_start(AddStart {
+                        parents: event.latest_patches(),
+                        event: start_removed.event.clone(),
+                        time: start_removed.time,
+                    });
+                }
+            }
+        }
+
+        for tag_added in patch.add_tag.iter() {
+            if let Some(event) = event(&tag_added.event) {
+                if event.tags().contains(&(patch_ref, tag_added.tag.clone())) {
+                    inverse.insert_remove_tag(RemoveTag {
+                        parents: Some(event.latest_patches()),
+                        patch: patch_ref,
+                        event: tag_added.event.clone(),
+                        tag: tag_added.tag.clone(),
+                    });
+                }
+            }
+        }
+        for tag_removed in patch.remove_tag.iter() {
+            if let Some(event) = event(&tag_removed.event) {
+                let already_added = event
+                    .tags()
+                    .iter()
+                    .any(|(_patch_ref, tag)| *tag == tag_removed.tag);
+                if !already_added {
+                    inverse.insert_add_tag(AddTag {
+                        parents: event.latest_patches(),
+                        event: tag_removed.event.clone(),
+                        tag: tag_removed.tag.clone(),
+                    });
+                }
+            }
+        }
+
+        for note_added in patch.add_note.iter() {
+            if let Some(event) = event(&note_added.event) {
+                if event
+                    .notes()
+                    .contains(&(patch_ref, note_added.note.clone()))
+                {
+                    inverse.insert_remove_note(RemoveNote {
+                        parents: Some(event.latest_patches()),
+                        patch: patch_ref,
+                        event: note_added.event.clone(),
+                        note: note_added.note.clone(),
+                    });
+                }
+            }
+        }
+        for note_removed in patch.remove_note.iter() {
+            if let Some(event) = event(&note_removed.event) {
+                let already_added = event
+                    .notes()
+                    .iter()
+                    .any(|(_patch_ref, note)| *note == note_removed.note);
+                if !already_added {
+                    inverse.insert_add_note(AddNote {
+                        parents: event.latest_patches(),
+                        event: note_removed.event.clone(),
+                        note: note_removed.note.clone(),
+                    });
+                }
+            }
+        }
+
+        for attribute_added in patch.add_attribute.iter() {
+            if let Some(event) = event(&attribute_added.event) {
+                let attribute = (
+                    patch_ref,
+                    attribute_added.key.clone(),
+                    attribute_added.value.clone(),
+                );
+                if event.attributes().contains(&attribute) {
+                    inverse.insert_remove_attribute(RemoveAttribute {
+                        parents: Some(event.latest_patches()),
+                        patch: patch_ref,
+                        event: attribute_added.event.clone(),
+                        key: attribute_added.key.clone(),
+                        value: attribute_added.value.clone(),
+                    });
+                }
+            }
+        }
+        for attribute_removed in patch.remove_attribute.iter() {
+            if let Some(event) = event(&attribute_removed.event) {
+                let already_added = event.attributes().iter().any(|(_patch_ref, key, value)| {
+                    *key == attribute_removed.key && *value == attribute_removed.value
+                });
+                if !already_added {
+                    inverse.insert_add_attribute(AddAttribute {
+                        parents: event.latest_patches(),
+                        event: attribute_removed.event.clone(),
+                        key: attribute_removed.key.clone(),
+                        value: attribute_removed.value.clone(),
+                    });
+                }
+            }
+        }
+
+        for new_event in patch.create_event.iter() {
+            if let Some(event) = event(&new_event.event) {
+                if !event.is_removed() {
+                    inverse.insert_remove_event(RemoveEvent {
+                        parents: event.latest_patches(),
+                        event: new_event.event.clone(),
+                    });
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            Err(errors)
+        } else {
+            Ok(inverse)
+        }
+    }
+
     pub fn flatten(&self) -> Result<Timesheet<'_>, Vec<Error>> {
+        self.flatten_with(ResolutionPolicy::Strict)
+    }
+
+    /// Flatten the timesheet, resolving events with multiple start times
+    /// according to `policy`. Starts that lost are recorded on the timesheet
+    /// and can be inspected with `Timesheet::superseded_starts`.
+    pub fn flatten_with(&self, policy: ResolutionPolicy) -> Result<Timesheet<'_>, Vec<Error>> {
         let mut timesheet = Timesheet::new(&self);
         let mut errors = Vec::new();
         let mut event_datetimes_to_refs: BTreeMap<DateTime<Utc>, EventRef> = BTreeMap::new();
         for (event_ref, patched_event) in self.events.iter() {
-            match patched_event.flatten() {
-                Ok(event) => {
+            // Removed events are kept around so that later patches can still
+            // reference them, but they are not part of the timesheet
+            if patched_event.is_removed() {
+                continue;
+            }
+            match patched_event.flatten_with(policy) {
+                Ok((event, superseded)) => {
+                    if !superseded.is_empty() {
+                        timesheet.add_superseded_starts(event_ref.clone(), superseded);
+                    }
                     if let Some(_event_a_tags) =
                         timesheet.event_at_time(event.start().clone(), event_ref.clone())
                     {
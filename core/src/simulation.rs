@@ -0,0 +1,898 @@
+//! Checks that devices converge on the same timesheet, no matter which order
+//! their files are synchronized in.
+//!
+//! A `Simulation` gives every device its own `MemoryStore`, standing in for
+//! its copy of the sync folder. Whenever a device saves a patch or its meta,
+//! the file is queued for every other device. A `Schedule` then decides when
+//! each device edits its timesheet, runs augr to synchronize, or receives one
+//! of the files queued for it. Files can arrive in any order, so a meta may
+//! arrive before the patches it lists, and files can be dropped, which delays
+//! them until the end of the schedule.
+//!
+//! Once the schedule has run, every queued and dropped file is delivered,
+//! and every device must flatten its repository to the same result. If they
+//! don't, the schedule is shrunk to the smallest schedule that still fails.
+
+use crate::{
+    repository::{
+        timesheet::{Error as TimesheetError, PatchedTimesheet},
+        Error as RepositoryError,
+    },
+    store::{
+        patch::{AddAttribute, AddNote, AddStart, AddTag, RemoveEvent, RemoveStart},
+        MemoryStore, MemoryStoreError,
+    },
+    timesheet::Attributes,
+    EventRef, Meta, Patch, PatchRef, Repository, Store, Tag,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use snafu::Snafu;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Range,
+};
+
+/// An event as every device should see it once they have converged
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlatEvent {
+    pub event_ref: EventRef,
+    pub start: DateTime<Utc>,
+    pub tags: BTreeSet<Tag>,
+    pub notes: Vec<String>,
+    pub attributes: Attributes,
+}
+
+/// What flattening a device's repository gave: either the events of the
+/// timesheet, or the conflicts that kept it from being flattened
+pub type Outcome = Result<Vec<FlatEvent>, Vec<TimesheetError>>;
+
+/// One thing that happens during a simulation
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    /// `device` starts a new event, `minute` minutes into the simulation
+    Start {
+        device: String,
+        event: EventRef,
+        minute: u32,
+        tag: Tag,
+    },
+
+    /// `device` tags one of the events it knows about. `pick` chooses the
+    /// event; the step does nothing if the device doesn't know of any.
+    Tag {
+        device: String,
+        pick: usize,
+        tag: Tag,
+    },
+
+    /// `device` moves the start of one of the events it knows about
+    SetStart {
+        device: String,
+        pick: usize,
+        minute: u32,
+    },
+
+    /// `device` adds a note to one of the events it knows about
+    Note {
+        device: String,
+        pick: usize,
+        note: String,
+    },
+
+    /// `device` sets an attribute on one of the events it knows about
+    Attribute {
+        device: String,
+        pick: usize,
+        key: String,
+        value: String,
+    },
+
+    /// `device` deletes one of the events it knows about
+    Delete { device: String, pick: usize },
+
+    /// `device` reverts the latest patch it created, like `augr undo`. The
+    /// step does nothing if there is nothing left to revert.
+    Undo { device: String },
+
+    /// `device` retires the patches every device has moved past, and folds
+    /// the ones every device has seen into a checkpoint, like `augr compact`.
+    /// The step does nothing if compacting fails.
+    Checkpoint { device: String },
+
+    /// `device` runs augr without changing anything, which synchronizes it
+    /// and saves its meta
+    Sync { device: String },
+
+    /// One of the files queued for `device` arrives. `pick` chooses the file;
+    /// the step does nothing if none are queued.
+    Deliver { device: String, pick: usize },
+
+    /// One of the files queued for `device` is lost, and only arrives once
+    /// the schedule is over
+    Drop { device: String, pick: usize },
+}
+
+/// The steps of a simulation, in the order they happen
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Schedule {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Failure {
+    #[snafu(display("Devices did not converge: {:?}", outcomes))]
+    Diverged { outcomes: BTreeMap<String, Outcome> },
+
+    #[snafu(display("Device {} could not load its repository: {}", device, errors.join("; ")))]
+    LoadRepository { device: String, errors: Vec<String> },
+
+    #[snafu(display("Device {} could not save its changes: {}", device, errors.join("; ")))]
+    SaveChanges { device: String, errors: Vec<String> },
+}
+
+/// A schedule that keeps devices from converging, shrunk as far as it can be
+#[derive(Debug)]
+pub struct Divergence {
+    /// The seed the failing schedule was generated from
+    pub seed: u64,
+
+    /// The smallest part of that schedule that still fails
+    pub schedule: Schedule,
+
+    /// How the smallest schedule fails
+    pub failure: Failure,
+}
+
+/// Runs schedules against a set of devices
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    devices: Vec<String>,
+    steps: usize,
+    drop_chance: f64,
+}
+
+/// Each run of the simulation starts at the same time, so that replaying a
+/// schedule creates the same patches
+fn epoch() -> DateTime<Utc> {
+    Utc.ymd(2019, 7, 23).and_hms(12, 0, 0)
+}
+
+impl Simulation {
+    pub fn new(devices: Vec<String>) -> Self {
+        Self {
+            devices,
+            steps: 50,
+            drop_chance: 0.1,
+        }
+    }
+
+    /// How many steps generated schedules have
+    pub fn steps(mut self, steps: usize) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// How likely a step in a generated schedule is to drop a file, from 0
+    /// to 1
+    pub fn drop_chance(mut self, drop_chance: f64) -> Self {
+        self.drop_chance = drop_chance;
+        self
+    }
+
+    /// Generate a random schedule. The same seed always gives the same
+    /// schedule.
+    pub fn schedule(&self, seed: u64) -> Schedule {
+        let mut rng = Rng(seed);
+        let mut steps = Vec::with_capacity(self.steps);
+        if self.devices.is_empty() {
+            return Schedule { steps };
+        }
+
+        for index in 0..self.steps {
+            let device = self.devices[rng.below(self.devices.len() as u64) as usize].clone();
+            let pick = rng.next() as usize;
+            let minute = rng.below(120) as u32;
+            let word = rng.below(5);
+            let tag = format!("tag{}", word);
+
+            let step = if rng.chance(self.drop_chance) {
+                Step::Drop { device, pick }
+            } else {
+                match rng.below(25) {
+                    0..=2 => Step::Start {
+                        device,
+                        event: format!("event{}", index),
+                        minute,
+                        tag,
+                    },
+                    3..=4 => Step::Tag { device, pick, tag },
+                    5..=6 => Step::SetStart {
+                        device,
+                        pick,
+                        minute,
+                    },
+                    7 => Step::Note {
+                        device,
+                        pick,
+                        note: format!("note{}", word),
+                    },
+                    8 => Step::Attribute {
+                        device,
+                        pick,
+                        key: "client".to_string(),
+                        value: format!("client{}", word),
+                    },
+                    9 => Step::Delete { device, pick },
+                    10 => Step::Undo { device },
+                    11 => Step::Checkpoint { device },
+                    12..=14 => Step::Sync { device },
+                    _ => Step::Deliver { device, pick },
+                }
+            };
+            steps.push(step);
+        }
+        Schedule { steps }
+    }
+
+    /// Run `schedule`, deliver every file that is still queued or was
+    /// dropped, and check that every device flattens its repository to the
+    /// same result
+    pub fn run(&self, schedule: &Schedule) -> Result<Outcome, Failure> {
+        let mut world = World::new(&self.devices);
+        for (index, step) in schedule.steps.iter().enumerate() {
+            world.step(index, step)?;
+        }
+        world.settle(schedule.steps.len())
+    }
+
+    /// Run the schedule generated from `seed`. If it fails, it is shrunk to
+    /// the smallest schedule that still fails.
+    pub fn check(&self, seed: u64) -> Result<Outcome, Divergence> {
+        let schedule = self.schedule(seed);
+        match self.run(&schedule) {
+            Ok(outcome) => Ok(outcome),
+            Err(failure) => {
+                let (schedule, failure) = self.minimize(schedule, failure);
+                Err(Divergence {
+                    seed,
+                    schedule,
+                    failure,
+                })
+            }
+        }
+    }
+
+    /// Check the schedules generated from each seed, stopping at the first
+    /// one that fails
+    pub fn check_seeds(&self, seeds: Range<u64>) -> Result<(), Divergence> {
+        for seed in seeds {
+            // Conflicts are fine, as long as every device has the same ones
+            let _outcome = self.check(seed)?;
+        }
+        Ok(())
+    }
+
+    /// Remove steps from a failing schedule for as long as it keeps failing.
+    /// Returns the shrunk schedule, along with how it fails.
+    pub fn minimize(&self, schedule: Schedule, failure: Failure) -> (Schedule, Failure) {
+        minimize(schedule, failure, |schedule| self.run(schedule).err())
+    }
+}
+
+/// Removes one step at a time, keeping each removal that leaves the schedule
+/// failing, until no single step can be removed
+fn minimize<E>(
+    mut schedule: Schedule,
+    mut failure: E,
+    mut fails: impl FnMut(&Schedule) -> Option<E>,
+) -> (Schedule, E) {
+    loop {
+        let mut shrunk = false;
+        let mut index = 0;
+        while index < schedule.steps.len() {
+            let mut candidate = schedule.clone();
+            candidate.steps.remove(index);
+            match fails(&candidate) {
+                Some(candidate_failure) => {
+                    schedule = candidate;
+                    failure = candidate_failure;
+                    shrunk = true;
+                }
+                None => index += 1,
+            }
+        }
+        if !shrunk {
+            return (schedule, failure);
+        }
+    }
+}
+
+/// A file waiting to be delivered to a device
+#[derive(Clone, Debug)]
+enum File {
+    Patch(Box<Patch>),
+    Meta { device: String, meta: Meta },
+}
+
+#[derive(Debug)]
+struct Device {
+    /// This device's copy of the sync folder
+    store: MemoryStore,
+
+    /// The patches in this device's copy of the sync folder
+    patches: BTreeSet<PatchRef>,
+
+    /// Files that have been saved on other devices, but haven't arrived yet
+    queued: Vec<File>,
+
+    /// Files that were lost on the way, and will arrive at the end
+    dropped: Vec<File>,
+}
+
+#[derive(Debug)]
+struct World {
+    devices: BTreeMap<String, Device>,
+}
+
+impl World {
+    fn new(device_ids: &[String]) -> Self {
+        let devices = device_ids
+            .iter()
+            .map(|device_id| {
+                let device = Device {
+                    store: MemoryStore::new(device_id.clone()),
+                    patches: BTreeSet::new(),
+                    queued: Vec::new(),
+                    dropped: Vec::new(),
+                };
+                (device_id.clone(), device)
+            })
+            .collect();
+        Self { devices }
+    }
+
+    fn step(&mut self, index: usize, step: &Step) -> Result<(), Failure> {
+        match step {
+            Step::Start { device, .. }
+            | Step::Tag { device, .. }
+            | Step::SetStart { device, .. }
+            | Step::Note { device, .. }
+            | Step::Attribute { device, .. }
+            | Step::Delete { device, .. }
+            | Step::Undo { device }
+            | Step::Checkpoint { device } => self.run_augr(device, Some((index, step))),
+            Step::Sync { device } => self.run_augr(device, None),
+            Step::Deliver { device, pick } => {
+                if let Some(device) = self.devices.get_mut(device) {
+                    if !device.queued.is_empty() {
+                        let file = device.queued.remove(pick % device.queued.len());
+                        device.receive(file);
+                    }
+                }
+                Ok(())
+            }
+            Step::Drop { device, pick } => {
+                if let Some(device) = self.devices.get_mut(device) {
+                    if !device.queued.is_empty() {
+                        let file = device.queued.remove(pick % device.queued.len());
+                        device.dropped.push(file);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Load the repository of a device, synchronize it, and save its meta,
+    /// like the cli does. Then make the change `edit` asks for, and queue
+    /// every file the device saved for the other devices.
+    fn run_augr(&mut self, device_id: &str, edit: Option<(usize, &Step)>) -> Result<(), Failure> {
+        let store = match self.devices.get(device_id) {
+            Some(device) => device.store.clone(),
+            None => return Ok(()),
+        };
+
+        let mut repo = load(device_id, store.clone())?;
+        let save_errors = |errors: Vec<String>| Failure::SaveChanges {
+            device: device_id.to_string(),
+            errors,
+        };
+        repo.save_meta()
+            .map_err(|e| save_errors(vec![e.to_string()]))?;
+
+        if let Some((index, step)) = edit {
+            if let Some(patch) = edit_patch(&mut repo, device_id, step).map_err(save_errors)? {
+                let patch = patch
+                    .device(device_id.to_string())
+                    .created_at(epoch() + Duration::seconds(index as i64))
+                    .content_addressed();
+                repo.add_patch(patch)
+                    .map_err(|e| save_errors(vec![e.to_string()]))?;
+                repo.save_meta()
+                    .map_err(|e| save_errors(vec![e.to_string()]))?;
+            }
+        }
+
+        self.share(device_id, &store);
+        Ok(())
+    }
+
+    /// Queue the patches `device_id` has saved since it last shared, along
+    /// with its meta, for every other device. A meta replaces any older copy
+    /// of it that hasn't arrived yet, as only the latest version of a file is
+    /// synchronized.
+    fn share(&mut self, device_id: &str, store: &MemoryStore) {
+        let meta = store.get_meta().unwrap_or_default();
+        let mut new_patches = Vec::new();
+        if let Some(device) = self.devices.get_mut(device_id) {
+            let patch_refs = store.patch_refs().unwrap_or_default();
+            for patch_ref in patch_refs.difference(&device.patches) {
+                if let Ok(patch) = store.get_patch(patch_ref) {
+                    new_patches.push(patch);
+                }
+            }
+            device.patches = patch_refs;
+        }
+
+        for (other_id, other) in self.devices.iter_mut() {
+            if other_id == device_id {
+                continue;
+            }
+            let is_old_meta =
+                |file: &File| matches!(file, File::Meta { device, .. } if device == device_id);
+            other.queued.retain(|file| !is_old_meta(file));
+            other.dropped.retain(|file| !is_old_meta(file));
+
+            other.queued.extend(
+                new_patches
+                    .iter()
+                    .cloned()
+                    .map(|patch| File::Patch(Box::new(patch))),
+            );
+            other.queued.push(File::Meta {
+                device: device_id.to_string(),
+                meta: meta.clone(),
+            });
+        }
+    }
+
+    /// Deliver every file that is still queued or was dropped, and flatten
+    /// every device's repository. Each device runs augr once every file has
+    /// arrived, and the metas that saves are delivered as well, so that
+    /// devices are compared as they would be after syncing has finished.
+    fn settle(mut self, steps: usize) -> Result<Outcome, Failure> {
+        let device_ids: Vec<String> = self.devices.keys().cloned().collect();
+        self.deliver_everything();
+        for device_id in device_ids.iter() {
+            self.step(
+                steps,
+                &Step::Sync {
+                    device: device_id.clone(),
+                },
+            )?;
+        }
+        self.deliver_everything();
+
+        let mut outcomes = BTreeMap::new();
+        for (device_id, device) in self.devices.iter() {
+            let repo = load(device_id, device.store.clone())?;
+            outcomes.insert(device_id.clone(), outcome(repo.timesheet()));
+        }
+
+        let mut others = outcomes.values().skip(1);
+        if let Some(first) = outcomes.values().next() {
+            if others.any(|outcome| outcome != first) {
+                return Err(Failure::Diverged { outcomes });
+            }
+        }
+        Ok(outcomes
+            .into_values()
+            .next()
+            .unwrap_or_else(|| Ok(Vec::new())))
+    }
+
+    fn deliver_everything(&mut self) {
+        for device in self.devices.values_mut() {
+            let files: Vec<File> = device
+                .queued
+                .drain(..)
+                .chain(device.dropped.drain(..))
+                .collect();
+            for file in files {
+                device.receive(file);
+            }
+        }
+    }
+}
+
+impl Device {
+    fn receive(&mut self, file: File) {
+        match file {
+            File::Patch(patch) => {
+                // The device may already have the patch from another device
+                let mut store = self.store.clone();
+                if store.add_patch(&patch).is_ok() {
+                    self.patches.insert(*patch.patch_ref());
+                }
+            }
+            File::Meta { device, meta } => {
+                let _ = self.store.for_device(device).save_meta(&meta);
+            }
+        }
+    }
+}
+
+/// Load and synchronize the repository of a device. Patches that haven't
+/// arrived yet are expected to be missing while syncing, so only failing to
+/// load the device's own patches is an error.
+fn load(device_id: &str, store: MemoryStore) -> Result<Repository<MemoryStore>, Failure> {
+    let load_errors = |errors: Vec<String>| Failure::LoadRepository {
+        device: device_id.to_string(),
+        errors,
+    };
+    let mut repo = Repository::from_store(store)
+        .map_err(|errors| load_errors(errors.iter().map(ToString::to_string).collect()))?;
+    repo.try_sync_data()
+        .map_err(|e| load_errors(vec![e.to_string()]))?;
+    Ok(repo)
+}
+
+/// The patch a device would create for `step`, given its repository
+fn edit_patch(
+    repo: &mut Repository<MemoryStore>,
+    device_id: &str,
+    step: &Step,
+) -> Result<Option<Patch>, Vec<String>> {
+    let to_strings = |errors: Vec<RepositoryError<MemoryStoreError>>| {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+    };
+    match step {
+        Step::Undo { .. } => {
+            let history = repo.history().map_err(to_strings)?;
+            let latest = history
+                .iter()
+                .rev()
+                .find(|patch| patch.device.as_deref() == Some(device_id));
+            let inverse = latest.and_then(|patch| repo.timesheet().inverse_patch(patch).ok());
+            return Ok(inverse.filter(|inverse| !inverse.is_empty()));
+        }
+        Step::Checkpoint { .. } => {
+            // Compacting fails while patches that other devices list haven't
+            // arrived yet, which leaves everything as it was
+            if repo.retire_patches().is_err() {
+                return Ok(None);
+            }
+            return Ok(repo.checkpoint().unwrap_or(None));
+        }
+        _ => {}
+    }
+    Ok(timesheet_edit(repo.timesheet(), step))
+}
+
+/// The patch a device would create for a step that only needs its timesheet
+fn timesheet_edit(timesheet: &PatchedTimesheet, step: &Step) -> Option<Patch> {
+    let pick_event = |pick: usize| {
+        let events: Vec<&EventRef> = timesheet
+            .events
+            .iter()
+            .filter(|(_event_ref, event)| !event.is_removed())
+            .map(|(event_ref, _event)| event_ref)
+            .collect();
+        if events.is_empty() {
+            None
+        } else {
+            let event_ref = events[pick % events.len()];
+            Some((event_ref.clone(), &timesheet.events[event_ref]))
+        }
+    };
+
+    match step {
+        Step::Start {
+            event, minute, tag, ..
+        } => Some(Patch::new().create_event(
+            event.clone(),
+            epoch() + Duration::minutes(i64::from(*minute)),
+            vec![tag.clone()],
+        )),
+        Step::Tag { pick, tag, .. } => {
+            let (event_ref, event) = pick_event(*pick)?;
+            let mut patch = Patch::new();
+            patch.insert_add_tag(AddTag {
+                parents: event.latest_patches(),
+                event: event_ref,
+                tag: tag.clone(),
+            });
+            Some(patch)
+        }
+        Step::SetStart { pick, minute, .. } => {
+            let (event_ref, event) = pick_event(*pick)?;
+            let parents = event.latest_patches();
+            let mut patch = Patch::new();
+            for (patch_ref, time) in event.starts() {
+                patch.insert_remove_start(RemoveStart {
+                    parents: Some(parents.clone()),
+                    patch: patch_ref,
+                    event: event_ref.clone(),
+                    time,
+                });
+            }
+            patch.insert_add_start(AddStart {
+                parents,
+                event: event_ref,
+                time: epoch() + Duration::minutes(i64::from(*minute)),
+            });
+            Some(patch)
+        }
+        Step::Note { pick, note, .. } => {
+            let (event_ref, event) = pick_event(*pick)?;
+            let mut patch = Patch::new();
+            patch.insert_add_note(AddNote {
+                parents: event.latest_patches(),
+                event: event_ref,
+                note: note.clone(),
+            });
+            Some(patch)
+        }
+        Step::Attribute {
+            pick, key, value, ..
+        } => {
+            let (event_ref, event) = pick_event(*pick)?;
+            let mut patch = Patch::new();
+            patch.insert_add_attribute(AddAttribute {
+                parents: event.latest_patches(),
+                event: event_ref,
+                key: key.clone(),
+                value: value.clone(),
+            });
+            Some(patch)
+        }
+        Step::Delete { pick, .. } => {
+            let (event_ref, event) = pick_event(*pick)?;
+            let mut patch = Patch::new();
+            patch.insert_remove_event(RemoveEvent {
+                parents: event.latest_patches(),
+                event: event_ref,
+            });
+            Some(patch)
+        }
+        Step::Undo { .. }
+        | Step::Checkpoint { .. }
+        | Step::Sync { .. }
+        | Step::Deliver { .. }
+        | Step::Drop { .. } => None,
+    }
+}
+
+fn outcome(timesheet: &PatchedTimesheet) -> Outcome {
+    let flattened = timesheet.flatten()?;
+    Ok(flattened
+        .segments()
+        .into_iter()
+        .map(|segment| FlatEvent {
+            event_ref: segment.event_ref,
+            start: segment.start_time,
+            tags: segment.tags,
+            notes: segment.notes,
+            attributes: segment.attributes,
+        })
+        .collect())
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Start {
+                device,
+                event,
+                minute,
+                tag,
+            } => write!(
+                f,
+                "{} starts {} at minute {} tagged {}",
+                device, event, minute, tag
+            ),
+            Step::Tag { device, pick, tag } => {
+                write!(f, "{} tags event #{} with {}", device, pick, tag)
+            }
+            Step::SetStart {
+                device,
+                pick,
+                minute,
+            } => write!(
+                f,
+                "{} moves the start of event #{} to minute {}",
+                device, pick, minute
+            ),
+            Step::Note { device, pick, note } => {
+                write!(f, "{} notes {:?} on event #{}", device, note, pick)
+            }
+            Step::Attribute {
+                device,
+                pick,
+                key,
+                value,
+            } => write!(f, "{} sets {}={} on event #{}", device, key, value, pick),
+            Step::Delete { device, pick } => write!(f, "{} deletes event #{}", device, pick),
+            Step::Undo { device } => write!(f, "{} undoes its latest patch", device),
+            Step::Checkpoint { device } => write!(f, "{} compacts its history", device),
+            Step::Sync { device } => write!(f, "{} synchronizes", device),
+            Step::Deliver { device, pick } => write!(f, "{} receives file #{}", device, pick),
+            Step::Drop { device, pick } => write!(f, "{} loses file #{}", device, pick),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>4}. {}", index + 1, step)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Seed {}: {}", self.seed, self.failure)?;
+        writeln!(f, "Smallest schedule that fails:")?;
+        write!(f, "{}", self.schedule)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// A small, seedable random number generator (SplitMix64), so that a
+/// schedule can be generated again from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to, but not including, `n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        let sample = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < probability
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn devices() -> Vec<String> {
+        vec!["laptop".to_string(), "phone".to_string()]
+    }
+
+    #[test]
+    fn schedules_are_reproducible() {
+        let simulation = Simulation::new(devices());
+        assert_eq!(simulation.schedule(7), simulation.schedule(7));
+        assert_ne!(simulation.schedule(7), simulation.schedule(8));
+    }
+
+    #[test]
+    fn minimize_keeps_only_needed_steps() {
+        let simulation = Simulation::new(devices()).steps(40);
+        let schedule = simulation.schedule(1);
+        let is_sync = |step: &Step| matches!(step, Step::Sync { .. });
+        let is_drop = |step: &Step| matches!(step, Step::Drop { .. });
+        assert!(schedule.steps.iter().any(is_sync));
+        assert!(schedule.steps.iter().any(is_drop));
+
+        // Pretend the simulation fails whenever a file is dropped after
+        // syncing
+        let (minimal, ()) = minimize(schedule, (), |schedule| {
+            let first_sync = schedule.steps.iter().position(is_sync)?;
+            schedule.steps[first_sync..]
+                .iter()
+                .find(|s| is_drop(s))
+                .map(|_| ())
+        });
+
+        assert_eq!(minimal.steps.len(), 2);
+        assert!(is_sync(&minimal.steps[0]));
+        assert!(is_drop(&minimal.steps[1]));
+    }
+
+    #[test]
+    fn edits_reach_every_device() {
+        let simulation = Simulation::new(devices());
+        let schedule = Schedule {
+            steps: vec![
+                Step::Start {
+                    device: "laptop".to_string(),
+                    event: "a".to_string(),
+                    minute: 0,
+                    tag: "work".to_string(),
+                },
+                // The meta arrives first, so the patch it lists is missing
+                Step::Deliver {
+                    device: "phone".to_string(),
+                    pick: 1,
+                },
+                Step::Sync {
+                    device: "phone".to_string(),
+                },
+                Step::Deliver {
+                    device: "phone".to_string(),
+                    pick: 0,
+                },
+                Step::Tag {
+                    device: "phone".to_string(),
+                    pick: 0,
+                    tag: "coding".to_string(),
+                },
+            ],
+        };
+
+        let events = simulation.run(&schedule).unwrap().unwrap();
+        assert_eq!(events.len(), 1);
+        let tags: Vec<&str> = events[0].tags.iter().map(String::as_str).collect();
+        assert_eq!(tags, vec!["coding", "work"]);
+    }
+
+    #[test]
+    fn every_kind_of_edit_reaches_every_device() {
+        let simulation = Simulation::new(devices());
+        let laptop = || "laptop".to_string();
+        let phone = || "phone".to_string();
+        let start = |event: &str, minute: u32| Step::Start {
+            device: laptop(),
+            event: event.to_string(),
+            minute,
+            tag: "work".to_string(),
+        };
+        let deliver = |device: String, files: usize| {
+            (0..files).map(move |_| Step::Deliver {
+                device: device.clone(),
+                pick: 0,
+            })
+        };
+        let mut schedule = Schedule {
+            steps: vec![
+                start("a", 0),
+                start("b", 30),
+                Step::Note {
+                    device: laptop(),
+                    pick: 0,
+                    note: "standup".to_string(),
+                },
+                Step::Attribute {
+                    device: laptop(),
+                    pick: 0,
+                    key: "client".to_string(),
+                    value: "acme".to_string(),
+                },
+                Step::Undo { device: laptop() },
+            ],
+        };
+        // Five patches and the laptop's meta
+        schedule.steps.extend(deliver(phone(), 6));
+        schedule.steps.push(Step::Delete {
+            device: phone(),
+            pick: 1,
+        });
+        schedule.steps.extend(deliver(laptop(), 2));
+        schedule.steps.push(Step::Checkpoint { device: laptop() });
+
+        let events = simulation.run(&schedule).unwrap().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_ref, "a");
+        assert_eq!(events[0].notes, vec!["standup".to_string()]);
+        assert!(events[0].attributes.is_empty());
+    }
+}
@@ -1,19 +1,101 @@
+mod canonical;
+pub mod memory_store;
 pub mod meta;
 pub mod patch;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
 pub mod sync_folder_store;
 
-pub use sync_folder_store::{SyncFolderStore, SyncFolderStoreError};
+pub use memory_store::{MemoryStore, MemoryStoreError};
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::{SqliteStore, SqliteStoreError};
+pub use sync_folder_store::{
+    ConflictCopy, MetaFolderReport, PatchFolderReport, SyncFolderStore, SyncFolderStoreError,
+};
 
 use self::meta::Meta;
 use self::patch::Patch;
 use crate::PatchRef;
-use std::error::Error;
+use std::{collections::BTreeMap, error::Error, fmt};
 
 pub trait Store {
     type Error: Error;
 
     fn get_meta(&self) -> Result<Meta, Self::Error>;
     fn save_meta(&mut self, meta: &Meta) -> Result<(), Self::Error>;
+
+    /// Replace the meta with the one `update` makes from the meta that is
+    /// currently saved. Stores that can be shared by several processes should
+    /// keep other processes from saving the meta in between.
+    fn update_meta<F>(&mut self, update: F) -> Result<(), Self::Error>
+    where
+        F: FnOnce(Meta) -> Meta,
+    {
+        let meta = update(self.get_meta()?);
+        self.save_meta(&meta)
+    }
+
     fn get_patch(&self, patch_ref: &PatchRef) -> Result<Patch, Self::Error>;
+
+    /// Whether the store has a patch with this id. The default reads the
+    /// patch, and treats a patch that can't be read as missing; stores that
+    /// can check without reading the patch should do so.
+    fn has_patch(&self, patch_ref: &PatchRef) -> Result<bool, Self::Error> {
+        Ok(self.get_patch(patch_ref).is_ok())
+    }
+
     fn add_patch(&mut self, patch: &Patch) -> Result<(), Self::Error>;
+
+    /// Delete a patch that has been folded into a checkpoint. Removing a patch
+    /// that doesn't exist is not an error. Stores that can't delete patches
+    /// keep the default, which fails with `RemovePatchError::Unsupported`.
+    fn remove_patch(&mut self, patch_ref: &PatchRef) -> Result<(), RemovePatchError<Self::Error>> {
+        let _ = patch_ref;
+        Err(RemovePatchError::Unsupported)
+    }
+}
+
+/// Why a patch couldn't be removed from a store
+#[derive(Debug, Eq, PartialEq)]
+pub enum RemovePatchError<E> {
+    /// The store doesn't support removing patches
+    Unsupported,
+
+    /// The store failed to remove the patch
+    Store(E),
+}
+
+impl<E> From<E> for RemovePatchError<E> {
+    fn from(error: E) -> Self {
+        RemovePatchError::Store(error)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RemovePatchError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemovePatchError::Unsupported => write!(f, "The store can't remove patches"),
+            RemovePatchError::Store(error) => error.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for RemovePatchError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RemovePatchError::Unsupported => None,
+            RemovePatchError::Store(error) => Some(error),
+        }
+    }
+}
+
+/// The metas of other devices, by device id
+pub type OtherMetas<E> = BTreeMap<String, Result<Meta, E>>;
+
+/// A store that is shared by several devices, and can list the metas the
+/// other devices have saved to it
+pub trait SyncStore: Store {
+    /// The meta of every other device, by device id. Metas that can't be read
+    /// are returned as errors, so that the rest can still be synchronized.
+    fn get_other_metas(&self) -> Result<OtherMetas<Self::Error>, Self::Error>;
 }
@@ -0,0 +1,227 @@
+//! The bytes a content-addressed patch's id is hashed from. The encoding is
+//! written out by hand, rather than borrowed from the serializer patches are
+//! stored with, so that ids don't change when that serializer does.
+//!
+//! Numbers are big-endian. Strings are their length as a `u64` followed by
+//! their UTF-8 bytes. Ids are their 16 bytes. Times are seconds since the
+//! Unix epoch as an `i64`, followed by nanoseconds as a `u32`. Options are a
+//! `0` byte, or a `1` byte followed by the value. Sets, lists and maps are
+//! their length as a `u64` followed by their items, in order. Structs are
+//! their fields in the order they are declared.
+
+use super::patch::{
+    AddAttribute, AddNote, AddStart, AddTag, CreateEvent, Patch, RemoveAttribute, RemoveEvent,
+    RemoveNote, RemoveStart, RemoveTag,
+};
+use crate::repository::snapshot::{
+    AttributeRecord, EventSnapshot, NoteRecord, Snapshot, StartRecord, ValueRecord,
+};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+/// The canonical encoding of the patch's format and operations. Each field
+/// that isn't empty is written as its name followed by its value, so adding
+/// a field in a later format doesn't change the encoding of patches that
+/// don't use it. The id and the metadata of the patch are left out.
+pub(crate) fn encode_patch(patch: &Patch) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Some(format) = patch.format {
+        field(&mut out, "format", &format);
+    }
+    set_field(&mut out, "add-start", &patch.add_start);
+    set_field(&mut out, "remove-start", &patch.remove_start);
+    set_field(&mut out, "add-tag", &patch.add_tag);
+    set_field(&mut out, "remove-tag", &patch.remove_tag);
+    set_field(&mut out, "add-note", &patch.add_note);
+    set_field(&mut out, "remove-note", &patch.remove_note);
+    set_field(&mut out, "add-attribute", &patch.add_attribute);
+    set_field(&mut out, "remove-attribute", &patch.remove_attribute);
+    set_field(&mut out, "create-event", &patch.create_event);
+    set_field(&mut out, "remove-event", &patch.remove_event);
+    if let Some(checkpoint) = &patch.checkpoint {
+        field(&mut out, "checkpoint", checkpoint);
+    }
+    out
+}
+
+fn field<T: Encode + ?Sized>(out: &mut Vec<u8>, name: &str, value: &T) {
+    name.encode(out);
+    value.encode(out);
+}
+
+fn set_field<T: Encode>(out: &mut Vec<u8>, name: &str, set: &BTreeSet<T>) {
+    if !set.is_empty() {
+        field(out, name, set);
+    }
+}
+
+trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+impl Encode for u32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(*self as u64).to_be_bytes());
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl Encode for Uuid {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for DateTime<Utc> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.timestamp().to_be_bytes());
+        self.timestamp_subsec_nanos().encode(out);
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+impl<T: Encode> Encode for BTreeSet<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<K: Encode, V: Encode> Encode for BTreeMap<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for (key, value) in self {
+            key.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+/// Encode each of a struct's fields, in order
+macro_rules! encode_fields {
+    ( $type:ty { $( $field:ident ),* } ) => {
+        impl Encode for $type {
+            fn encode(&self, out: &mut Vec<u8>) {
+                $( self.$field.encode(out); )*
+            }
+        }
+    };
+}
+
+encode_fields!(AddStart {
+    parents,
+    event,
+    time
+});
+encode_fields!(RemoveStart {
+    parents,
+    patch,
+    event,
+    time
+});
+encode_fields!(AddTag {
+    parents,
+    event,
+    tag
+});
+encode_fields!(RemoveTag {
+    parents,
+    patch,
+    event,
+    tag
+});
+encode_fields!(AddNote {
+    parents,
+    event,
+    note
+});
+encode_fields!(RemoveNote {
+    parents,
+    patch,
+    event,
+    note
+});
+encode_fields!(AddAttribute {
+    parents,
+    event,
+    key,
+    value
+});
+encode_fields!(RemoveAttribute {
+    parents,
+    patch,
+    event,
+    key,
+    value
+});
+encode_fields!(CreateEvent { event, start, tags });
+encode_fields!(RemoveEvent { parents, event });
+
+encode_fields!(Snapshot {
+    version,
+    patches,
+    heads,
+    events,
+    checkpoints
+});
+encode_fields!(EventSnapshot {
+    removed_by,
+    latest_patches,
+    starts_added,
+    starts_removed,
+    tags_added,
+    tags_removed,
+    notes_added,
+    notes_removed,
+    attributes_added,
+    attributes_removed
+});
+encode_fields!(StartRecord { patch, time });
+encode_fields!(ValueRecord { patch, value });
+encode_fields!(NoteRecord {
+    patch,
+    value,
+    written_at
+});
+encode_fields!(AttributeRecord { patch, key, value });
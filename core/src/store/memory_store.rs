@@ -0,0 +1,204 @@
+use crate::{
+    store::{OtherMetas, RemovePatchError, SyncStore},
+    Meta, Patch, PatchRef, Store,
+};
+use snafu::Snafu;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// Keeps patches and metas in memory. Every store made with
+/// `MemoryStore::for_device` shares the same data, the same way devices share
+/// a sync folder, so several devices can be simulated without touching the
+/// filesystem.
+#[derive(Clone, Debug)]
+pub struct MemoryStore {
+    data: Arc<Mutex<Data>>,
+    device_id: String,
+}
+
+#[derive(Debug, Default)]
+struct Data {
+    patches: BTreeMap<PatchRef, Patch>,
+    metas: BTreeMap<String, Meta>,
+}
+
+#[derive(Debug, Eq, PartialEq, Snafu)]
+pub enum MemoryStoreError {
+    #[snafu(display(
+        "Patch {} does not match its id; it may have been corrupted or tampered with",
+        patch_ref
+    ))]
+    CorruptPatch { patch_ref: PatchRef },
+
+    #[snafu(display(
+        "Patch {} has format {}, which this version of augr doesn't support; upgrade augr to read it",
+        patch_ref,
+        format
+    ))]
+    UnsupportedPatchFormat { patch_ref: PatchRef, format: u32 },
+
+    #[snafu(display("Patch {} does not exist", patch_ref))]
+    MissingPatch { patch_ref: PatchRef },
+
+    #[snafu(display("Patch {} already exists", patch_ref))]
+    PatchExists { patch_ref: PatchRef },
+}
+
+impl MemoryStore {
+    /// An empty store for `device_id`
+    pub fn new(device_id: String) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(Data::default())),
+            device_id,
+        }
+    }
+
+    /// A store for another device that shares this store's data
+    pub fn for_device(&self, device_id: String) -> Self {
+        Self {
+            data: self.data.clone(),
+            device_id,
+        }
+    }
+
+    /// Add a patch without listing it in any meta. Unlike `add_patch`, an
+    /// existing patch with the same id is replaced, so broken or tampered
+    /// patches can be seeded.
+    pub fn patch(self, patch: Patch) -> Self {
+        self.lock().patches.insert(*patch.patch_ref(), patch);
+        self
+    }
+
+    /// Add a patch and list it in the meta of `device_id`, as if that device
+    /// had created it
+    pub fn device_patch(self, device_id: &str, patch: Patch) -> Self {
+        {
+            let mut data = self.lock();
+            data.metas
+                .entry(device_id.to_string())
+                .or_default()
+                .add_patch(*patch.patch_ref());
+            data.patches.insert(*patch.patch_ref(), patch);
+        }
+        self
+    }
+
+    /// Replace the meta of `device_id`
+    pub fn meta(self, device_id: &str, meta: Meta) -> Self {
+        self.lock().metas.insert(device_id.to_string(), meta);
+        self
+    }
+
+    /// The device this store reads and saves the meta of
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Every patch in the store
+    pub fn patch_refs(&self) -> Result<BTreeSet<PatchRef>, MemoryStoreError> {
+        Ok(self.lock().patches.keys().copied().collect())
+    }
+
+    /// Every device with a meta in the store, including this one
+    pub fn device_ids(&self) -> Result<BTreeSet<String>, MemoryStoreError> {
+        Ok(self.lock().metas.keys().cloned().collect())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Data> {
+        // The data is never left half updated, so it is still usable after
+        // another thread panicked while holding the lock
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Store for MemoryStore {
+    type Error = MemoryStoreError;
+
+    fn get_meta(&self) -> Result<Meta, Self::Error> {
+        Ok(self
+            .lock()
+            .metas
+            .get(&self.device_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn save_meta(&mut self, meta: &Meta) -> Result<(), Self::Error> {
+        self.lock()
+            .metas
+            .insert(self.device_id.clone(), meta.clone());
+        Ok(())
+    }
+
+    fn update_meta<F>(&mut self, update: F) -> Result<(), Self::Error>
+    where
+        F: FnOnce(Meta) -> Meta,
+    {
+        // Holding the lock keeps other devices from saving in between
+        let mut data = self.lock();
+        let meta = data.metas.get(&self.device_id).cloned().unwrap_or_default();
+        data.metas.insert(self.device_id.clone(), update(meta));
+        Ok(())
+    }
+
+    fn get_patch(&self, patch_ref: &PatchRef) -> Result<Patch, Self::Error> {
+        let patch =
+            self.lock()
+                .patches
+                .get(patch_ref)
+                .cloned()
+                .ok_or(MemoryStoreError::MissingPatch {
+                    patch_ref: *patch_ref,
+                })?;
+
+        if let Some(format) = patch.unsupported_format() {
+            return UnsupportedPatchFormat {
+                patch_ref: *patch_ref,
+                format,
+            }
+            .fail();
+        }
+
+        if !patch.verify_id(patch_ref) {
+            return CorruptPatch {
+                patch_ref: *patch_ref,
+            }
+            .fail();
+        }
+
+        Ok(patch)
+    }
+
+    fn has_patch(&self, patch_ref: &PatchRef) -> Result<bool, Self::Error> {
+        Ok(self.lock().patches.contains_key(patch_ref))
+    }
+
+    fn add_patch(&mut self, patch: &Patch) -> Result<(), Self::Error> {
+        let patch_ref = *patch.patch_ref();
+        let mut data = self.lock();
+        if data.patches.contains_key(&patch_ref) {
+            return PatchExists { patch_ref }.fail();
+        }
+        data.patches.insert(patch_ref, patch.clone());
+        Ok(())
+    }
+
+    fn remove_patch(&mut self, patch_ref: &PatchRef) -> Result<(), RemovePatchError<Self::Error>> {
+        self.lock().patches.remove(patch_ref);
+        Ok(())
+    }
+}
+
+impl SyncStore for MemoryStore {
+    fn get_other_metas(&self) -> Result<OtherMetas<Self::Error>, Self::Error> {
+        Ok(self
+            .lock()
+            .metas
+            .iter()
+            .filter(|(device_id, _meta)| **device_id != self.device_id)
+            .map(|(device_id, meta)| (device_id.clone(), Ok(meta.clone())))
+            .collect())
+    }
+}
@@ -1,7 +1,9 @@
 use crate::PatchRef;
 use serde::{Deserialize, Serialize};
 
-type Set<T> = std::collections::HashSet<T>;
+/// Patches are kept in an ordered set, so that a meta is always serialized
+/// the same way
+type Set<T> = std::collections::BTreeSet<T>;
 
 #[derive(Default, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -25,6 +27,11 @@ impl Meta {
     pub fn patches(&self) -> impl Iterator<Item = &PatchRef> {
         self.patches.iter()
     }
+
+    /// Add every patch listed in `other` to this meta
+    pub fn merge(&mut self, other: Meta) {
+        self.patches.extend(other.patches);
+    }
 }
 
 #[cfg(test)]
@@ -49,4 +56,17 @@ mod test {
         assert_eq!(toml::de::from_str(toml_str), Ok(expected));
     }
 
+    #[test]
+    fn serialization_is_canonical() {
+        let mut meta = Meta::new();
+        meta.add_patch(Uuid::parse_str("c10350e8-3f30-4d27-b120-8ee079e256d9").unwrap());
+        meta.add_patch(Uuid::parse_str("7a826905-7a3e-430d-9d54-5af08ecb482c").unwrap());
+        meta.add_patch(Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap());
+
+        let toml_str = "patches = [\"7a826905-7a3e-430d-9d54-5af08ecb482c\", \"c10350e8-3f30-4d27-b120-8ee079e256d9\", \"fa5de1d9-aa11-49fa-b064-8128281a7d91\"]\n";
+        let serialized = toml::ser::to_string(&meta).unwrap();
+        assert_eq!(serialized, toml_str);
+        assert_eq!(toml::de::from_str(&serialized), Ok(meta));
+    }
+
 }
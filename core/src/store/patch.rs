@@ -1,18 +1,42 @@
-use crate::Tag;
+use super::canonical::encode_patch;
+use crate::{repository::snapshot::Snapshot, Tag};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::BTreeSet;
 use uuid::Uuid;
 
 pub type PatchRef = Uuid;
+
+/// The format of patches whose id is a hash of their contents. Patches
+/// without a format have a random id.
+pub const CONTENT_ADDRESSED_FORMAT: u32 = 2;
 type EventRef = String;
-type Set<T> = std::collections::HashSet<T>;
+/// Operations are kept in ordered sets, so that a patch is always serialized
+/// the same way no matter what order its operations were added in
+type Set<T> = BTreeSet<T>;
 
 #[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Patch {
     pub id: Uuid,
 
+    /// How the id of this patch was chosen. See `CONTENT_ADDRESSED_FORMAT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<u32>,
+
+    /// The device that created this patch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+
+    /// When this patch was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+
+    /// The version of augr that created this patch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub augr_version: Option<String>,
+
     #[serde(default, skip_serializing_if = "Set::is_empty")]
     pub add_start: Set<AddStart>,
 
@@ -25,11 +49,32 @@ pub struct Patch {
     #[serde(default, skip_serializing_if = "Set::is_empty")]
     pub remove_tag: Set<RemoveTag>,
 
+    #[serde(default, skip_serializing_if = "Set::is_empty")]
+    pub add_note: Set<AddNote>,
+
+    #[serde(default, skip_serializing_if = "Set::is_empty")]
+    pub remove_note: Set<RemoveNote>,
+
+    #[serde(default, skip_serializing_if = "Set::is_empty")]
+    pub add_attribute: Set<AddAttribute>,
+
+    #[serde(default, skip_serializing_if = "Set::is_empty")]
+    pub remove_attribute: Set<RemoveAttribute>,
+
     #[serde(default, skip_serializing_if = "Set::is_empty")]
     pub create_event: Set<CreateEvent>,
+
+    #[serde(default, skip_serializing_if = "Set::is_empty")]
+    pub remove_event: Set<RemoveEvent>,
+
+    /// The state of the timesheet after applying the patches this checkpoint
+    /// folds together. Loading a checkpoint counts as loading every one of
+    /// those patches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Snapshot>,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AddStart {
     #[serde(default)]
@@ -38,7 +83,7 @@ pub struct AddStart {
     pub time: DateTime<Utc>,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct RemoveStart {
     #[serde(default)]
@@ -48,7 +93,7 @@ pub struct RemoveStart {
     pub time: DateTime<Utc>,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AddTag {
     #[serde(default)]
@@ -57,7 +102,7 @@ pub struct AddTag {
     pub tag: Tag,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct RemoveTag {
     #[serde(default)]
@@ -67,7 +112,47 @@ pub struct RemoveTag {
     pub tag: Tag,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AddNote {
+    #[serde(default)]
+    pub parents: BTreeSet<PatchRef>,
+    pub event: EventRef,
+    pub note: String,
+}
+
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoveNote {
+    #[serde(default)]
+    pub parents: Option<BTreeSet<PatchRef>>,
+    pub patch: PatchRef,
+    pub event: EventRef,
+    pub note: String,
+}
+
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AddAttribute {
+    #[serde(default)]
+    pub parents: BTreeSet<PatchRef>,
+    pub event: EventRef,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoveAttribute {
+    #[serde(default)]
+    pub parents: Option<BTreeSet<PatchRef>>,
+    pub patch: PatchRef,
+    pub event: EventRef,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct CreateEvent {
     pub event: EventRef,
@@ -75,26 +160,54 @@ pub struct CreateEvent {
     pub tags: Vec<Tag>,
 }
 
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoveEvent {
+    #[serde(default)]
+    pub parents: BTreeSet<PatchRef>,
+    pub event: EventRef,
+}
+
 impl Patch {
     pub fn new() -> Self {
         Self {
             id: Uuid::new_v4(),
+            format: None,
+            device: None,
+            created_at: None,
+            augr_version: None,
             add_start: Set::new(),
             remove_start: Set::new(),
             add_tag: Set::new(),
             remove_tag: Set::new(),
+            add_note: Set::new(),
+            remove_note: Set::new(),
+            add_attribute: Set::new(),
+            remove_attribute: Set::new(),
             create_event: Set::new(),
+            remove_event: Set::new(),
+            checkpoint: None,
         }
     }
 
     pub fn with_id(id: PatchRef) -> Self {
         Self {
             id,
+            format: None,
+            device: None,
+            created_at: None,
+            augr_version: None,
             add_start: Set::new(),
             remove_start: Set::new(),
             add_tag: Set::new(),
             remove_tag: Set::new(),
+            add_note: Set::new(),
+            remove_note: Set::new(),
+            add_attribute: Set::new(),
+            remove_attribute: Set::new(),
             create_event: Set::new(),
+            remove_event: Set::new(),
+            checkpoint: None,
         }
     }
 
@@ -102,6 +215,99 @@ impl Patch {
         &self.id
     }
 
+    /// Give the patch an id that is the hash of its contents. This should
+    /// be done last, as any change to the patch afterwards won't match the
+    /// id.
+    pub fn content_addressed(mut self) -> Self {
+        self.format = Some(CONTENT_ADDRESSED_FORMAT);
+        self.id = self.content_id();
+        self
+    }
+
+    /// The patch's format, if it is newer than this version of augr
+    /// understands. Such patches may have fields that were dropped when they
+    /// were read, so their ids can't be checked.
+    pub fn unsupported_format(&self) -> Option<u32> {
+        match self.format {
+            Some(format) if format > CONTENT_ADDRESSED_FORMAT => Some(format),
+            _ => None,
+        }
+    }
+
+    /// Whether the id of the patch matches its contents. Patches with random
+    /// ids can't be checked, and are always considered to match.
+    pub fn has_valid_id(&self) -> bool {
+        match self.format {
+            None => true,
+            Some(CONTENT_ADDRESSED_FORMAT) => self.content_id() == self.id,
+            Some(_) => false,
+        }
+    }
+
+    /// Whether the patch can be trusted to be the one `patch_ref` names. Only
+    /// patches with content-addressed ids can be checked.
+    pub fn verify_id(&self, patch_ref: &PatchRef) -> bool {
+        match self.format {
+            None => true,
+            Some(_) => self.id == *patch_ref && self.has_valid_id(),
+        }
+    }
+
+    /// The SHA-256 hash of the patch's format and operations, shortened to
+    /// fit in a `PatchRef`. What is hashed is the patch's canonical encoding
+    /// (see the `canonical` module), which doesn't depend on what order the
+    /// operations were added in, or on how patches are serialized.
+    ///
+    /// The device, time and version of augr that created the patch are left
+    /// out, so the same change gets the same id wherever and whenever it is
+    /// made. They aren't covered by the id, and can't be verified.
+    pub fn content_id(&self) -> PatchRef {
+        let hash = Sha256::digest(&encode_patch(self));
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&hash[..16]);
+        // Mark the id as a custom (version 8) RFC 4122 UUID
+        bytes[6] = (bytes[6] & 0x0f) | 0x80;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Uuid::from_bytes(bytes)
+    }
+
+    /// Whether the patch contains no operations
+    pub fn is_empty(&self) -> bool {
+        self.add_start.is_empty()
+            && self.remove_start.is_empty()
+            && self.add_tag.is_empty()
+            && self.remove_tag.is_empty()
+            && self.add_note.is_empty()
+            && self.remove_note.is_empty()
+            && self.add_attribute.is_empty()
+            && self.remove_attribute.is_empty()
+            && self.create_event.is_empty()
+            && self.remove_event.is_empty()
+            && self.checkpoint.is_none()
+    }
+
+    /// Make this patch a checkpoint that folds together the patches in
+    /// `snapshot`
+    pub fn checkpoint(mut self, snapshot: Snapshot) -> Self {
+        self.checkpoint = Some(snapshot);
+        self
+    }
+
+    pub fn device(mut self, device: String) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn created_at(mut self, created_at: DateTime<Utc>) -> Self {
+        self.created_at = Some(created_at);
+        self
+    }
+
+    pub fn augr_version(mut self, augr_version: String) -> Self {
+        self.augr_version = Some(augr_version);
+        self
+    }
+
     pub fn parents(&self) -> Set<PatchRef> {
         let add_start_parents = self.add_start.iter().flat_map(|x| x.parents.iter());
         let remove_start_parents = self.remove_start.iter().map(|x| &x.patch).chain(
@@ -115,10 +321,28 @@ impl Patch {
                 .flat_map(|x| x.parents.iter().flat_map(|s| s.iter())),
         );
         let add_tag_parents = self.add_tag.iter().flat_map(|x| x.parents.iter());
+        let add_note_parents = self.add_note.iter().flat_map(|x| x.parents.iter());
+        let remove_note_parents = self.remove_note.iter().map(|x| &x.patch).chain(
+            self.remove_note
+                .iter()
+                .flat_map(|x| x.parents.iter().flat_map(|s| s.iter())),
+        );
+        let add_attribute_parents = self.add_attribute.iter().flat_map(|x| x.parents.iter());
+        let remove_attribute_parents = self.remove_attribute.iter().map(|x| &x.patch).chain(
+            self.remove_attribute
+                .iter()
+                .flat_map(|x| x.parents.iter().flat_map(|s| s.iter())),
+        );
+        let remove_event_parents = self.remove_event.iter().flat_map(|x| x.parents.iter());
         add_start_parents
             .chain(remove_start_parents)
             .chain(remove_tag_parents)
             .chain(add_tag_parents)
+            .chain(add_note_parents)
+            .chain(remove_note_parents)
+            .chain(add_attribute_parents)
+            .chain(remove_attribute_parents)
+            .chain(remove_event_parents)
             .cloned()
             .collect()
     }
@@ -169,6 +393,66 @@ impl Patch {
         self
     }
 
+    pub fn add_note(mut self, parent: PatchRef, event: EventRef, note: String) -> Self {
+        self.add_note.insert(AddNote {
+            parents: {
+                let mut s = BTreeSet::new();
+                s.insert(parent);
+                s
+            },
+            event,
+            note,
+        });
+        self
+    }
+
+    pub fn remove_note(mut self, patch: PatchRef, event: EventRef, note: String) -> Self {
+        self.remove_note.insert(RemoveNote {
+            parents: None,
+            patch,
+            event,
+            note,
+        });
+        self
+    }
+
+    pub fn add_attribute(
+        mut self,
+        parent: PatchRef,
+        event: EventRef,
+        key: String,
+        value: String,
+    ) -> Self {
+        self.add_attribute.insert(AddAttribute {
+            parents: {
+                let mut s = BTreeSet::new();
+                s.insert(parent);
+                s
+            },
+            event,
+            key,
+            value,
+        });
+        self
+    }
+
+    pub fn remove_attribute(
+        mut self,
+        patch: PatchRef,
+        event: EventRef,
+        key: String,
+        value: String,
+    ) -> Self {
+        self.remove_attribute.insert(RemoveAttribute {
+            parents: None,
+            patch,
+            event,
+            key,
+            value,
+        });
+        self
+    }
+
     pub fn create_event(
         mut self,
         event: EventRef,
@@ -179,6 +463,18 @@ impl Patch {
         self
     }
 
+    pub fn remove_event(mut self, parent: PatchRef, event: EventRef) -> Self {
+        self.remove_event.insert(RemoveEvent {
+            parents: {
+                let mut s = BTreeSet::new();
+                s.insert(parent);
+                s
+            },
+            event,
+        });
+        self
+    }
+
     pub fn insert_add_start(&mut self, add_start: AddStart) {
         self.add_start.insert(add_start);
     }
@@ -195,9 +491,29 @@ impl Patch {
         self.remove_tag.insert(remove_tag);
     }
 
+    pub fn insert_add_note(&mut self, add_note: AddNote) {
+        self.add_note.insert(add_note);
+    }
+
+    pub fn insert_remove_note(&mut self, remove_note: RemoveNote) {
+        self.remove_note.insert(remove_note);
+    }
+
+    pub fn insert_add_attribute(&mut self, add_attribute: AddAttribute) {
+        self.add_attribute.insert(add_attribute);
+    }
+
+    pub fn insert_remove_attribute(&mut self, remove_attribute: RemoveAttribute) {
+        self.remove_attribute.insert(remove_attribute);
+    }
+
     pub fn insert_create_event(&mut self, create_event: CreateEvent) {
         self.create_event.insert(create_event);
     }
+
+    pub fn insert_remove_event(&mut self, remove_event: RemoveEvent) {
+        self.remove_event.insert(remove_event);
+    }
 }
 
 impl Default for Patch {
@@ -226,11 +542,38 @@ impl RemoveTag {
         self.parents.iter().flat_map(|s| s.iter())
     }
 }
+impl AddNote {
+    pub fn parents(&self) -> impl Iterator<Item = &PatchRef> {
+        self.parents.iter()
+    }
+}
+impl RemoveNote {
+    pub fn parents(&self) -> impl Iterator<Item = &PatchRef> {
+        self.parents.iter().flat_map(|s| s.iter())
+    }
+}
+impl AddAttribute {
+    pub fn parents(&self) -> impl Iterator<Item = &PatchRef> {
+        self.parents.iter()
+    }
+}
+impl RemoveAttribute {
+    pub fn parents(&self) -> impl Iterator<Item = &PatchRef> {
+        self.parents.iter().flat_map(|s| s.iter())
+    }
+}
+impl RemoveEvent {
+    pub fn parents(&self) -> impl Iterator<Item = &PatchRef> {
+        self.parents.iter()
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::repository::snapshot::{EventSnapshot, NoteRecord, StartRecord, ValueRecord};
     use chrono::offset::{TimeZone, Utc};
+    use std::collections::BTreeMap;
 
     macro_rules! s (
         { $stuff:expr } => {
@@ -278,6 +621,22 @@ mod test {
         assert_eq!(toml_str, serialized);
     }
 
+    #[test]
+    fn serialize_patch_with_metadata_toml() {
+        let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
+
+        let patch = Patch::with_id(id)
+            .device(s!("laptop"))
+            .created_at(Utc.ymd(2019, 7, 24).and_hms(14, 0, 0))
+            .augr_version(s!("0.2.1"))
+            .create_event(s!("a"), Utc.ymd(2019, 7, 24).and_hms(14, 0, 0), vec![]);
+
+        let toml_str = "id = \"e39076fe-6b5a-4a7f-b927-7fc1df5ba275\"\ndevice = \"laptop\"\ncreated-at = \"2019-07-24T14:00:00Z\"\naugr-version = \"0.2.1\"\n\n[[create-event]]\nevent = \"a\"\nstart = \"2019-07-24T14:00:00Z\"\ntags = []\n".to_string();
+        let serialized = toml::ser::to_string(&patch).unwrap();
+        assert_eq!(toml_str, serialized);
+        assert_eq!(toml::de::from_str(&serialized), Ok(patch));
+    }
+
     #[test]
     fn read_patch_with_parents() {
         let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
@@ -366,4 +725,270 @@ mod test {
         assert_eq!(toml::de::from_str(toml_str), Ok(expected));
     }
 
+    #[test]
+    fn read_patch_with_notes_toml() {
+        let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
+        let patch0 = Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap();
+        let expected = Patch::with_id(id)
+            .add_note(patch0, s!("a"), s!("reviewed PR #412"))
+            .remove_note(patch0, s!("a"), s!("reviewed PR #411"));
+
+        let toml_str = r#"
+            id = "e39076fe-6b5a-4a7f-b927-7fc1df5ba275"
+
+            [[add-note]]
+            parents = ["fa5de1d9-aa11-49fa-b064-8128281a7d91"]
+            event = "a"
+            note = "reviewed PR #412"
+
+            [[remove-note]]
+            patch = "fa5de1d9-aa11-49fa-b064-8128281a7d91"
+            event = "a"
+            note = "reviewed PR #411"
+        "#;
+        assert_eq!(toml::de::from_str(toml_str), Ok(expected));
+    }
+
+    #[test]
+    fn read_patch_with_attributes_toml() {
+        let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
+        let patch0 = Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap();
+        let expected = Patch::with_id(id)
+            .add_attribute(patch0, s!("a"), s!("client"), s!("acme"))
+            .remove_attribute(patch0, s!("a"), s!("ticket"), s!("ENG-42"));
+
+        let toml_str = r#"
+            id = "e39076fe-6b5a-4a7f-b927-7fc1df5ba275"
+
+            [[add-attribute]]
+            parents = ["fa5de1d9-aa11-49fa-b064-8128281a7d91"]
+            event = "a"
+            key = "client"
+            value = "acme"
+
+            [[remove-attribute]]
+            patch = "fa5de1d9-aa11-49fa-b064-8128281a7d91"
+            event = "a"
+            key = "ticket"
+            value = "ENG-42"
+        "#;
+        assert_eq!(toml::de::from_str(toml_str), Ok(expected));
+    }
+
+    #[test]
+    fn read_patch_with_remove_event_toml() {
+        let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
+        let patch0 = Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap();
+        let expected = Patch::with_id(id).remove_event(patch0, s!("a"));
+
+        let toml_str = r#"
+            id = "e39076fe-6b5a-4a7f-b927-7fc1df5ba275"
+
+            [[remove-event]]
+            parents = ["fa5de1d9-aa11-49fa-b064-8128281a7d91"]
+            event = "a"
+        "#;
+        let patch: Patch = toml::de::from_str(toml_str).unwrap();
+        assert_eq!(patch, expected);
+        assert!(patch.parents().contains(&patch0));
+    }
+
+    #[test]
+    fn content_id_ignores_operation_order() {
+        let event = Utc.ymd(2019, 7, 24).and_hms(14, 0, 0);
+        let patch0 = Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap();
+        let first = Patch::new()
+            .add_tag(patch0, s!("a"), s!("work"))
+            .add_tag(patch0, s!("a"), s!("coding"))
+            .add_start(patch0, s!("a"), event)
+            .content_addressed();
+        let second = Patch::new()
+            .add_start(patch0, s!("a"), event)
+            .add_tag(patch0, s!("a"), s!("coding"))
+            .add_tag(patch0, s!("a"), s!("work"))
+            .content_addressed();
+
+        assert_eq!(first.patch_ref(), second.patch_ref());
+        assert_eq!(first.format, Some(CONTENT_ADDRESSED_FORMAT));
+        // Content-addressed ids are marked as version 8 UUIDs
+        assert_eq!(first.patch_ref().get_version_num(), 8);
+    }
+
+    #[test]
+    fn content_id_survives_round_trip() {
+        let patch = Patch::new()
+            .device(s!("laptop"))
+            .create_event(
+                s!("a"),
+                Utc.ymd(2019, 7, 24).and_hms(14, 0, 0),
+                vec![s!("work")],
+            )
+            .create_event(s!("b"), Utc.ymd(2019, 7, 24).and_hms(15, 0, 0), vec![])
+            .content_addressed();
+
+        let serialized = toml::ser::to_string(&patch).unwrap();
+        let deserialized: Patch = toml::de::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, patch);
+        assert!(deserialized.verify_id(patch.patch_ref()));
+    }
+
+    #[test]
+    fn tampered_patch_fails_verification() {
+        let patch = Patch::new()
+            .create_event(
+                s!("a"),
+                Utc.ymd(2019, 7, 24).and_hms(14, 0, 0),
+                vec![s!("work")],
+            )
+            .content_addressed();
+        let id = *patch.patch_ref();
+        assert!(patch.verify_id(&id));
+
+        let mut tampered = patch.clone();
+        tampered.create_event = tampered
+            .create_event
+            .into_iter()
+            .map(|mut create_event| {
+                create_event.tags.push(s!("fun"));
+                create_event
+            })
+            .collect();
+        assert!(!tampered.verify_id(&id));
+
+        // A valid patch stored under another patch's id
+        let other = Patch::new().content_addressed();
+        assert!(!other.verify_id(&id));
+    }
+
+    #[test]
+    fn content_id_ignores_metadata() {
+        let start = Utc.ymd(2019, 7, 24).and_hms(14, 0, 0);
+        let laptop = Patch::new()
+            .device(s!("laptop"))
+            .created_at(start)
+            .augr_version(s!("0.1.0"))
+            .create_event(s!("a"), start, vec![s!("work")])
+            .content_addressed();
+        let phone = Patch::new()
+            .device(s!("phone"))
+            .created_at(Utc.ymd(2019, 7, 25).and_hms(9, 0, 0))
+            .create_event(s!("a"), start, vec![s!("work")])
+            .content_addressed();
+
+        assert_eq!(laptop.patch_ref(), phone.patch_ref());
+        assert!(phone.verify_id(laptop.patch_ref()));
+    }
+
+    #[test]
+    fn random_ids_are_not_verified() {
+        let patch =
+            Patch::new().create_event(s!("a"), Utc.ymd(2019, 7, 24).and_hms(14, 0, 0), vec![]);
+        assert!(patch.has_valid_id());
+        assert!(patch.verify_id(patch.patch_ref()));
+    }
+
+    #[test]
+    fn content_id_is_pinned() {
+        // These ids must never change, or patches already in sync folders
+        // would no longer match their ids
+        let patch0 = Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap();
+        let start = Utc.ymd(2019, 7, 24).and_hms(14, 0, 0);
+        let patch = Patch::new()
+            .create_event(s!("a"), start, vec![s!("work")])
+            .add_note(patch0, s!("b"), s!("meeting"))
+            .remove_tag(patch0, s!("b"), s!("fun"))
+            .content_addressed();
+        assert_eq!(
+            patch.patch_ref(),
+            &Uuid::parse_str("f1c3801d-fdc3-8f4c-8071-292ee2c2c346").unwrap()
+        );
+
+        let mut event = EventSnapshot::default();
+        event.latest_patches.insert(patch0);
+        event.starts_added.push(StartRecord {
+            patch: patch0,
+            time: start,
+        });
+        event.tags_added.push(ValueRecord {
+            patch: patch0,
+            value: s!("work"),
+        });
+        event.notes_added.push(NoteRecord {
+            patch: patch0,
+            value: s!("meeting"),
+            written_at: Some(start),
+        });
+        let snapshot = Snapshot {
+            version: 3,
+            patches: vec![patch0].into_iter().collect(),
+            heads: vec![patch0].into_iter().collect(),
+            events: vec![(s!("a"), event)].into_iter().collect(),
+            checkpoints: BTreeMap::new(),
+        };
+        let checkpoint = Patch::new().checkpoint(snapshot).content_addressed();
+        assert_eq!(
+            checkpoint.patch_ref(),
+            &Uuid::parse_str("e794515e-59a4-86d0-bf1e-3c9a1bae0a3e").unwrap()
+        );
+    }
+
+    #[test]
+    fn serialization_is_canonical() {
+        let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
+        let patch0 = Uuid::parse_str("fa5de1d9-aa11-49fa-b064-8128281a7d91").unwrap();
+        let patch1 = Uuid::parse_str("0c435b19-4504-440c-abc7-f4e4d6a7d25f").unwrap();
+
+        let first = Patch::with_id(id)
+            .add_tag(patch1, s!("b"), s!("work"))
+            .add_tag(patch0, s!("a"), s!("work"))
+            .add_tag(patch0, s!("a"), s!("coding"));
+        let second = Patch::with_id(id)
+            .add_tag(patch0, s!("a"), s!("coding"))
+            .add_tag(patch0, s!("a"), s!("work"))
+            .add_tag(patch1, s!("b"), s!("work"));
+
+        let toml_str = "id = \"e39076fe-6b5a-4a7f-b927-7fc1df5ba275\"\n\n[[add-tag]]\nparents = [\"0c435b19-4504-440c-abc7-f4e4d6a7d25f\"]\nevent = \"b\"\ntag = \"work\"\n\n[[add-tag]]\nparents = [\"fa5de1d9-aa11-49fa-b064-8128281a7d91\"]\nevent = \"a\"\ntag = \"coding\"\n\n[[add-tag]]\nparents = [\"fa5de1d9-aa11-49fa-b064-8128281a7d91\"]\nevent = \"a\"\ntag = \"work\"\n";
+        assert_eq!(toml::ser::to_string(&first).unwrap(), toml_str);
+        assert_eq!(toml::ser::to_string(&second).unwrap(), toml_str);
+    }
+
+    #[test]
+    fn non_canonical_order_is_read() {
+        let id = Uuid::parse_str("e39076fe-6b5a-4a7f-b927-7fc1df5ba275").unwrap();
+
+        // Written by an older version of augr, which didn't sort operations
+        let toml_str = r#"
+            id = "e39076fe-6b5a-4a7f-b927-7fc1df5ba275"
+
+            [[create-event]]
+            event = "b"
+            start = "2019-07-24T15:00:00Z"
+            tags = ["work"]
+
+            [[create-event]]
+            event = "a"
+            start = "2019-07-24T14:00:00Z"
+            tags = ["work", "coding"]
+        "#;
+        let patch: Patch = toml::de::from_str(toml_str).unwrap();
+
+        let expected = Patch::with_id(id)
+            .create_event(
+                s!("a"),
+                Utc.ymd(2019, 7, 24).and_hms(14, 0, 0),
+                vec![s!("work"), s!("coding")],
+            )
+            .create_event(
+                s!("b"),
+                Utc.ymd(2019, 7, 24).and_hms(15, 0, 0),
+                vec![s!("work")],
+            );
+        assert_eq!(patch, expected);
+
+        // It is written back out in canonical order, and reads the same
+        let serialized = toml::ser::to_string(&patch).unwrap();
+        assert!(serialized.find("event = \"a\"") < serialized.find("event = \"b\""));
+        assert_eq!(toml::de::from_str(&serialized), Ok(patch));
+    }
+
 }
@@ -0,0 +1,341 @@
+use crate::{
+    store::{OtherMetas, RemovePatchError, SyncStore},
+    Meta, Patch, PatchRef, Store,
+};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior, NO_PARAMS};
+use snafu::{ResultExt, Snafu};
+use std::{collections::BTreeSet, path::Path, time::Duration};
+
+/// How long to wait for another process to finish writing to the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps every patch and meta in a single SQLite database. Patches and metas
+/// are stored in the same TOML format as `SyncFolderStore` uses, keyed by
+/// patch and device.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Connection,
+    device_id: String,
+}
+
+#[derive(Debug, Snafu)]
+pub enum SqliteStoreError {
+    #[snafu(display("Unable to open database {}: {}", path, source))]
+    OpenDatabase {
+        source: rusqlite::Error,
+        path: String,
+    },
+
+    #[snafu(display("Unable to deserialize meta {}: {}", device_id, source))]
+    DeserializeMeta {
+        source: toml::de::Error,
+        device_id: String,
+    },
+
+    #[snafu(display("Unable to serialize meta {}: {}", device_id, source))]
+    SerializeMeta {
+        source: toml::ser::Error,
+        device_id: String,
+    },
+
+    #[snafu(display("Unable to deserialize patch {}: {}", patch_ref, source))]
+    DeserializePatch {
+        source: toml::de::Error,
+        patch_ref: PatchRef,
+    },
+
+    #[snafu(display("Unable to serialize patch {}: {}", patch_ref, source))]
+    SerializePatch {
+        source: toml::ser::Error,
+        patch_ref: PatchRef,
+    },
+
+    #[snafu(display(
+        "Patch {} does not match its id; it may have been corrupted or tampered with",
+        patch_ref
+    ))]
+    CorruptPatch { patch_ref: PatchRef },
+
+    #[snafu(display(
+        "Patch {} has format {}, which this version of augr doesn't support; upgrade augr to read it",
+        patch_ref,
+        format
+    ))]
+    UnsupportedPatchFormat { patch_ref: PatchRef, format: u32 },
+
+    #[snafu(display("Patch {} does not exist", patch_ref))]
+    MissingPatch { patch_ref: PatchRef },
+
+    #[snafu(display("Patch {} already exists", patch_ref))]
+    PatchExists { patch_ref: PatchRef },
+
+    #[snafu(display("Database error: {}", source))]
+    Database { source: rusqlite::Error },
+
+    #[snafu(display("The database has a patch with the invalid id {:?}: {}", id, source))]
+    InvalidPatchId {
+        source: uuid::parser::ParseError,
+        id: String,
+    },
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it if it doesn't exist
+    pub fn open(path: &Path, device_id: String) -> Result<Self, SqliteStoreError> {
+        let connection = Connection::open(path).context(OpenDatabase {
+            path: path.display().to_string(),
+        })?;
+        Self::from_connection(connection, device_id)
+    }
+
+    /// Open the database at `path`, failing if it doesn't exist
+    pub fn open_existing(path: &Path, device_id: String) -> Result<Self, SqliteStoreError> {
+        let flags = OpenFlags::default() - OpenFlags::SQLITE_OPEN_CREATE;
+        let connection = Connection::open_with_flags(path, flags).context(OpenDatabase {
+            path: path.display().to_string(),
+        })?;
+        Self::from_connection(connection, device_id)
+    }
+
+    /// Open a database that only exists for as long as the store does
+    pub fn open_in_memory(device_id: String) -> Result<Self, SqliteStoreError> {
+        let connection = Connection::open_in_memory().context(OpenDatabase {
+            path: ":memory:".to_string(),
+        })?;
+        Self::from_connection(connection, device_id)
+    }
+
+    fn from_connection(
+        connection: Connection,
+        device_id: String,
+    ) -> Result<Self, SqliteStoreError> {
+        connection.busy_timeout(BUSY_TIMEOUT).context(Database {})?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS patches (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    contents TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS metas (
+                    device_id TEXT PRIMARY KEY NOT NULL,
+                    contents TEXT NOT NULL
+                );",
+            )
+            .context(Database {})?;
+        Ok(Self {
+            connection,
+            device_id,
+        })
+    }
+
+    /// Every patch in the database
+    pub fn patch_refs(&self) -> Result<BTreeSet<PatchRef>, SqliteStoreError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id FROM patches")
+            .context(Database {})?;
+        let ids = statement
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .context(Database {})?
+            .collect::<Result<Vec<_>, _>>()
+            .context(Database {})?;
+
+        // Rows are only added by `add_patch`, so an invalid id means the
+        // database was changed by something else
+        ids.iter()
+            .map(|id| PatchRef::parse_str(id).context(InvalidPatchId { id }))
+            .collect()
+    }
+
+    /// Every device with a meta in the database, including this one
+    pub fn device_ids(&self) -> Result<BTreeSet<String>, SqliteStoreError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT device_id FROM metas")
+            .context(Database {})?;
+        let device_ids = statement
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .context(Database {})?
+            .collect::<Result<_, _>>()
+            .context(Database {})?;
+        Ok(device_ids)
+    }
+}
+
+fn read_meta(connection: &Connection, device_id: &str) -> Result<Meta, SqliteStoreError> {
+    let contents: Option<String> = connection
+        .query_row(
+            "SELECT contents FROM metas WHERE device_id = ?1",
+            params![device_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .context(Database {})?;
+
+    match contents {
+        Some(contents) => toml::de::from_str(&contents).context(DeserializeMeta { device_id }),
+        None => Ok(Meta::new()),
+    }
+}
+
+fn write_meta(
+    connection: &Connection,
+    device_id: &str,
+    meta: &Meta,
+) -> Result<(), SqliteStoreError> {
+    let contents = toml::ser::to_string(meta).context(SerializeMeta { device_id })?;
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO metas (device_id, contents) VALUES (?1, ?2)",
+            params![device_id, contents],
+        )
+        .context(Database {})?;
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    type Error = SqliteStoreError;
+
+    fn get_meta(&self) -> Result<Meta, Self::Error> {
+        read_meta(&self.connection, &self.device_id)
+    }
+
+    fn save_meta(&mut self, meta: &Meta) -> Result<(), Self::Error> {
+        write_meta(&self.connection, &self.device_id, meta)
+    }
+
+    fn update_meta<F>(&mut self, update: F) -> Result<(), Self::Error>
+    where
+        F: FnOnce(Meta) -> Meta,
+    {
+        // An immediate transaction keeps other processes from writing to the
+        // database until the new meta has been saved
+        let transaction = self
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context(Database {})?;
+        let meta = update(read_meta(&transaction, &self.device_id)?);
+        write_meta(&transaction, &self.device_id, &meta)?;
+        transaction.commit().context(Database {})
+    }
+
+    fn has_patch(&self, patch_ref: &PatchRef) -> Result<bool, Self::Error> {
+        self.connection
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM patches WHERE id = ?1)",
+                params![patch_ref.to_string()],
+                |row| row.get(0),
+            )
+            .context(Database {})
+    }
+
+    fn get_patch(&self, patch_ref: &PatchRef) -> Result<Patch, Self::Error> {
+        let contents: Option<String> = self
+            .connection
+            .query_row(
+                "SELECT contents FROM patches WHERE id = ?1",
+                params![patch_ref.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(Database {})?;
+        let contents = contents.ok_or(SqliteStoreError::MissingPatch {
+            patch_ref: *patch_ref,
+        })?;
+
+        let patch: Patch = toml::de::from_str(&contents).context(DeserializePatch {
+            patch_ref: *patch_ref,
+        })?;
+
+        if let Some(format) = patch.unsupported_format() {
+            return UnsupportedPatchFormat {
+                patch_ref: *patch_ref,
+                format,
+            }
+            .fail();
+        }
+
+        if !patch.verify_id(patch_ref) {
+            return CorruptPatch {
+                patch_ref: *patch_ref,
+            }
+            .fail();
+        }
+
+        Ok(patch)
+    }
+
+    fn add_patch(&mut self, patch: &Patch) -> Result<(), Self::Error> {
+        let patch_ref = *patch.patch_ref();
+        let contents = toml::ser::to_string(patch).context(SerializePatch { patch_ref })?;
+        let inserted = self
+            .connection
+            .execute(
+                "INSERT OR IGNORE INTO patches (id, contents) VALUES (?1, ?2)",
+                params![patch_ref.to_string(), contents],
+            )
+            .context(Database {})?;
+        if inserted == 0 {
+            return PatchExists { patch_ref }.fail();
+        }
+        Ok(())
+    }
+
+    fn remove_patch(&mut self, patch_ref: &PatchRef) -> Result<(), RemovePatchError<Self::Error>> {
+        self.connection
+            .execute(
+                "DELETE FROM patches WHERE id = ?1",
+                params![patch_ref.to_string()],
+            )
+            .context(Database {})?;
+        Ok(())
+    }
+}
+
+impl SyncStore for SqliteStore {
+    fn get_other_metas(&self) -> Result<OtherMetas<Self::Error>, Self::Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT device_id, contents FROM metas WHERE device_id != ?1")
+            .context(Database {})?;
+        let rows = statement
+            .query_map(params![self.device_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .context(Database {})?
+            .collect::<Result<Vec<_>, _>>()
+            .context(Database {})?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(device_id, contents)| {
+                let meta = toml::de::from_str(&contents).context(DeserializeMeta {
+                    device_id: device_id.clone(),
+                });
+                (device_id, meta)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_patch_id_is_an_error() {
+        let store = SqliteStore::open_in_memory("laptop".to_string()).unwrap();
+        store
+            .connection
+            .execute(
+                "INSERT INTO patches (id, contents) VALUES ('not-a-uuid', '')",
+                NO_PARAMS,
+            )
+            .unwrap();
+
+        match store.patch_refs() {
+            Err(SqliteStoreError::InvalidPatchId { id, .. }) => assert_eq!(id, "not-a-uuid"),
+            result => panic!("expected an invalid id, got {:?}", result),
+        }
+    }
+}
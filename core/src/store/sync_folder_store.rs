@@ -1,11 +1,21 @@
-use crate::{Meta, Patch, PatchRef, Store};
+use crate::{
+    store::{OtherMetas, RemovePatchError, SyncStore},
+    Meta, Patch, PatchRef, Store,
+};
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
 use snafu::{ResultExt, Snafu};
 use std::{
-    fs::{create_dir_all, read_to_string, OpenOptions},
-    io::Write,
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs::{create_dir_all, hard_link, read, read_to_string, remove_file, rename, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
 };
 use toml;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct SyncFolderStore {
@@ -14,8 +24,21 @@ pub struct SyncFolderStore {
     root_folder: PathBuf,
     patch_folder: PathBuf,
     device_id: String,
+
+    /// How long to wait for another process to finish updating the meta
+    lock_timeout: Duration,
+
+    /// The file that is locked while the meta is updated. It is kept out of
+    /// the sync folder, so that Syncthing doesn't spread it to other devices.
+    lock_file: Option<PathBuf>,
 }
 
+/// The default for `SyncFolderStore::lock_timeout`
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether the meta has been unlocked
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
 #[derive(Debug, Snafu)]
 pub enum SyncFolderStoreError {
     #[snafu(display("Unable to deserialize meta {}: {}", device_id, source))]
@@ -30,12 +53,25 @@ pub enum SyncFolderStoreError {
         device_id: String,
     },
 
-    #[snafu(display("Unable to deserialize meta {}: {}", patch_ref, source))]
+    #[snafu(display("Unable to deserialize patch {}: {}", patch_ref, source))]
     DeserializePatch {
         source: toml::de::Error,
         patch_ref: String,
     },
 
+    #[snafu(display(
+        "Patch {} does not match its id; it may have been corrupted or tampered with",
+        patch_ref
+    ))]
+    CorruptPatch { patch_ref: PatchRef },
+
+    #[snafu(display(
+        "Patch {} has format {}, which this version of augr doesn't support; upgrade augr to read it",
+        patch_ref,
+        format
+    ))]
+    UnsupportedPatchFormat { patch_ref: PatchRef, format: u32 },
+
     #[snafu(display("Unable to read file {}: {}", path.display(), source))]
     ReadFile {
         source: std::io::Error,
@@ -48,6 +84,30 @@ pub enum SyncFolderStoreError {
         path: PathBuf,
     },
 
+    #[snafu(display("Unable to remove file {}: {}", path.display(), source))]
+    RemoveFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to lock {}: {}", path.display(), source))]
+    LockFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display(
+        "Timed out after {} seconds waiting for another augr process to finish updating the meta of device {} (lock file {})",
+        timeout.as_secs_f32(),
+        device_id,
+        path.display()
+    ))]
+    LockTimeout {
+        device_id: String,
+        path: PathBuf,
+        timeout: Duration,
+    },
+
     #[snafu(display("IO error: {}", source))]
     IOError { source: std::io::Error },
 }
@@ -56,9 +116,11 @@ impl SyncFolderStore {
     pub fn new(root_folder: PathBuf, device_id: String) -> Self {
         Self {
             init: false,
+            lock_file: None,
             device_id,
             patch_folder: root_folder.join("patches"),
             root_folder,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
         }
     }
 
@@ -67,6 +129,67 @@ impl SyncFolderStore {
         self
     }
 
+    /// Set how long to wait for another process to finish updating the meta
+    /// of this device before giving up
+    pub fn lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Set the file that is locked while the meta of this device is updated.
+    /// Every process using the sync folder on this device must use the same
+    /// file, and it shouldn't be in the sync folder. Defaults to a file in
+    /// the user's cache directory.
+    pub fn lock_file(mut self, lock_file: PathBuf) -> Self {
+        self.lock_file = Some(lock_file);
+        self
+    }
+
+    /// Take an exclusive lock on the meta of this device, waiting for other
+    /// processes to release it. The lock is advisory, and is held until the
+    /// returned file is dropped.
+    fn lock_meta(&self) -> Result<File, SyncFolderStoreError> {
+        let path = match &self.lock_file {
+            Some(path) => path.clone(),
+            None => {
+                // The sync folder must exist to find the same lock file from
+                // every process
+                create_dir_all(&self.root_folder).context(LockFile {
+                    path: &self.root_folder,
+                })?;
+                default_lock_file(&self.root_folder, &self.device_id)
+            }
+        };
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).context(LockFile { path: &path })?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(LockFile { path: &path })?;
+
+        let started = Instant::now();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(file),
+                Err(e) if e.kind() != fs2::lock_contended_error().kind() => {
+                    return Err(e).context(LockFile { path });
+                }
+                Err(_) if started.elapsed() >= self.lock_timeout => {
+                    return LockTimeout {
+                        device_id: self.device_id.clone(),
+                        path,
+                        timeout: self.lock_timeout,
+                    }
+                    .fail();
+                }
+                Err(_) => sleep(LOCK_RETRY_INTERVAL),
+            }
+        }
+    }
+
     fn meta_file_path(&self) -> PathBuf {
         self.root_folder
             .join("meta")
@@ -74,38 +197,299 @@ impl SyncFolderStore {
             .with_extension("toml")
     }
 
-    pub fn get_other_metas(
+    /// Replace the meta of this device with `meta`
+    fn write_meta(&self, meta: &Meta) -> Result<(), SyncFolderStoreError> {
+        let contents = toml::ser::to_vec(&meta).context(SerializeMeta {
+            device_id: self.device_id.clone(),
+        })?;
+
+        let path = self.meta_file_path();
+
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                create_dir_all(parent).context(WriteFile { path: parent })?;
+            }
+        }
+
+        write_atomically(&path, true, |file| file.write_all(contents.as_slice()))
+            .context(WriteFile { path })
+    }
+
+    /// The conflict copies Syncthing has made of this device's meta
+    fn own_conflict_copies(&self) -> Result<Vec<PathBuf>, SyncFolderStoreError> {
+        let (mut meta_files, _report) = self.scan_meta_folder()?;
+        Ok(meta_files
+            .remove(&self.device_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|copy| *copy != self.meta_file_path())
+            .collect())
+    }
+
+    /// Every patch in the patch folder
+    pub fn patch_refs(&self) -> Result<BTreeSet<PatchRef>, SyncFolderStoreError> {
+        let (patch_refs, _report) = self.scan_patch_folder()?;
+        Ok(patch_refs)
+    }
+
+    /// List the files in the patch folder that aren't patches: temporary
+    /// files augr left behind, conflict copies that Syncthing created, and
+    /// files that aren't named after a patch
+    pub fn patch_folder_report(&self) -> Result<PatchFolderReport, SyncFolderStoreError> {
+        let (_patch_refs, report) = self.scan_patch_folder()?;
+        Ok(report)
+    }
+
+    /// Sort the files in the patch folder into patches, which are named after
+    /// their id, and everything else
+    fn scan_patch_folder(
         &self,
-    ) -> Result<impl Iterator<Item = Result<Meta, SyncFolderStoreError>>, SyncFolderStoreError>
-    {
-        let meta_folder = self.root_folder.join("meta");
-        let meta_file = self.meta_file_path();
+    ) -> Result<(BTreeSet<PatchRef>, PatchFolderReport), SyncFolderStoreError> {
+        let mut patch_refs = BTreeSet::new();
+        let mut report = PatchFolderReport::default();
+        if !self.patch_folder.exists() {
+            return Ok((patch_refs, report));
+        }
+
+        let mut paths: Vec<PathBuf> = self
+            .patch_folder
+            .read_dir()
+            .context(ReadFile {
+                path: &self.patch_folder,
+            })?
+            .filter_map(|d| d.ok())
+            .map(|dir_entry| dir_entry.path())
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let file_name = path.file_name().and_then(OsStr::to_str).unwrap_or("");
+            match classify_patch_file(file_name) {
+                PatchFile::Patch(patch_ref) => {
+                    patch_refs.insert(patch_ref);
+                }
+                PatchFile::Temporary if is_stale(&path) => report.stale_temporary.push(path),
+                PatchFile::Temporary | PatchFile::Downloading => {}
+                PatchFile::ConflictCopy => report.conflict_copies.push(path),
+                PatchFile::Unrecognised => report.unrecognised.push(path),
+            }
+        }
+
+        Ok((patch_refs, report))
+    }
+
+    /// Every device with a meta in the sync folder, including this one
+    pub fn device_ids(&self) -> Result<BTreeSet<String>, SyncFolderStoreError> {
+        let (meta_files, _report) = self.scan_meta_folder()?;
+        Ok(meta_files.into_keys().collect())
+    }
+
+    /// List the files in the meta folder that aren't metas: conflict copies
+    /// that Syncthing created, and files that are ignored
+    pub fn meta_folder_report(&self) -> Result<MetaFolderReport, SyncFolderStoreError> {
+        let (_meta_files, report) = self.scan_meta_folder()?;
+        Ok(report)
+    }
 
+    /// Group the files in the meta folder by the device they belong to. The
+    /// meta of a device comes before its conflict copies.
+    fn scan_meta_folder(
+        &self,
+    ) -> Result<(BTreeMap<String, Vec<PathBuf>>, MetaFolderReport), SyncFolderStoreError> {
+        let meta_folder = self.root_folder.join("meta");
+        let mut meta_files: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        let mut report = MetaFolderReport::default();
         if !meta_folder.exists() {
-            create_dir_all(&meta_folder).context(IOError {})?;
+            return Ok((meta_files, report));
         }
 
-        let sync_folder_items = meta_folder
+        let mut paths: Vec<PathBuf> = meta_folder
             .read_dir()
-            .context(ReadFile { path: meta_folder })?;
-
-        let iter = sync_folder_items
+            .context(ReadFile { path: &meta_folder })?
             .filter_map(|d| d.ok())
-            .filter(move |dir_entry| dir_entry.path() != meta_file)
-            .map(|dir_entry| {
-                let path = dir_entry.path();
-                let contents = read_to_string(&path).context(ReadFile { path: path.clone() })?;
+            .map(|dir_entry| dir_entry.path())
+            .collect();
+        paths.sort();
+
+        let mut conflict_copies: Vec<(String, PathBuf)> = Vec::new();
+        for path in paths {
+            let file_name = path.file_name().and_then(OsStr::to_str).unwrap_or("");
+            match classify_meta_file(file_name) {
+                MetaFile::Meta(device_id) => meta_files.entry(device_id).or_default().push(path),
+                MetaFile::ConflictCopy(device_id) => conflict_copies.push((device_id, path)),
+                MetaFile::Ignored => report.ignored.push(path),
+            }
+        }
+        for (device_id, path) in conflict_copies {
+            meta_files
+                .entry(device_id.clone())
+                .or_default()
+                .push(path.clone());
+            report
+                .conflict_copies
+                .push(ConflictCopy { device_id, path });
+        }
 
-                let meta = toml::de::from_str(&contents).context(DeserializeMeta {
-                    device_id: path.display().to_string(),
-                })?;
+        Ok((meta_files, report))
+    }
+}
+
+/// Files found in the meta folder besides the metas themselves
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MetaFolderReport {
+    /// Copies of metas that Syncthing made when they were changed on two
+    /// devices at once. Each one is merged into the meta it is a copy of.
+    pub conflict_copies: Vec<ConflictCopy>,
 
-                Ok(meta)
-            });
-        Ok(iter)
+    /// Temporary files and editor backups, which are not read
+    pub ignored: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConflictCopy {
+    pub device_id: String,
+    pub path: PathBuf,
+}
+
+/// Files found in the patch folder besides the patches themselves
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PatchFolderReport {
+    /// Temporary files of augr's that haven't been touched for
+    /// `STALE_AFTER`. They were left behind when augr was stopped partway
+    /// through writing a patch, and are safe to delete.
+    pub stale_temporary: Vec<PathBuf>,
+
+    /// Copies of patches that Syncthing made when they were written on two
+    /// devices at once, which are not read
+    pub conflict_copies: Vec<PathBuf>,
+
+    /// Files that aren't named after a patch, which are not read
+    pub unrecognised: Vec<PathBuf>,
+}
+
+/// How long a temporary file in the patch folder can go untouched before it
+/// is considered left behind, rather than still being written
+pub const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+enum PatchFile {
+    Patch(PatchRef),
+    Temporary,
+    Downloading,
+    ConflictCopy,
+    Unrecognised,
+}
+
+/// Work out what a file in the patch folder is from its name. Patches are
+/// named `<id>.toml`, and augr writes them to `.<id>.toml.<uuid>.tmp` first.
+/// Syncthing's temporary files and conflict copies are named as described
+/// for `classify_meta_file`.
+fn classify_patch_file(file_name: &str) -> PatchFile {
+    if file_name.starts_with(".syncthing.") || file_name.starts_with("~syncthing~") {
+        // Syncthing cleans up its own temporary files
+        return PatchFile::Downloading;
+    }
+    if file_name.starts_with('.') && file_name.ends_with(".tmp") {
+        return PatchFile::Temporary;
+    }
+    let stem = match file_name.strip_suffix(".toml") {
+        Some(stem) => stem,
+        None => return PatchFile::Unrecognised,
+    };
+    if stem.contains(".sync-conflict-") {
+        return PatchFile::ConflictCopy;
+    }
+    match PatchRef::parse_str(stem) {
+        Ok(patch_ref) => PatchFile::Patch(patch_ref),
+        Err(_) => PatchFile::Unrecognised,
+    }
+}
+
+/// Whether the file hasn't been modified for `STALE_AFTER`. Files that can't
+/// be checked, such as ones that have just been removed, aren't stale.
+fn is_stale(path: &Path) -> bool {
+    let age = path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok());
+    match age {
+        Some(age) => age >= STALE_AFTER,
+        None => false,
     }
 }
 
+enum MetaFile {
+    Meta(String),
+    ConflictCopy(String),
+    Ignored,
+}
+
+/// Work out what a file in the meta folder is from its name. Syncthing
+/// writes incoming files to `.syncthing.<name>.tmp` (or `~syncthing~<name>.tmp`
+/// on Windows), and names conflict copies
+/// `<name>.sync-conflict-<date>-<time>-<device>.<ext>`.
+fn classify_meta_file(file_name: &str) -> MetaFile {
+    // Hidden files include Syncthing's and augr's own temporary files, as
+    // well as editor swap files
+    let is_temporary = file_name.starts_with('.')
+        || file_name.starts_with("~syncthing~")
+        || file_name.starts_with('#')
+        || file_name.ends_with('~');
+    let stem = match file_name.strip_suffix(".toml") {
+        Some(stem) if !is_temporary && !stem.is_empty() => stem,
+        _ => return MetaFile::Ignored,
+    };
+    match stem.find(".sync-conflict-") {
+        Some(0) => MetaFile::Ignored,
+        Some(index) => MetaFile::ConflictCopy(stem[..index].to_string()),
+        None => MetaFile::Meta(stem.to_string()),
+    }
+}
+
+/// Read each of `paths` and add the patches they list to `meta`
+fn read_merged_meta(mut meta: Meta, paths: &[PathBuf]) -> Result<Meta, SyncFolderStoreError> {
+    for path in paths {
+        let contents = read_to_string(path).context(ReadFile { path })?;
+        let copy = toml::de::from_str(&contents).context(DeserializeMeta {
+            device_id: path.display().to_string(),
+        })?;
+        meta.merge(copy);
+    }
+    Ok(meta)
+}
+
+/// A lock file in the user's cache directory for a sync folder and device, or
+/// in the temporary directory if the user has no home directory. The sync
+/// folder is part of the name, so that stores of different sync folders don't
+/// wait for each other. The name is a SHA-256 hash of the folder's path, so
+/// that every build of augr picks the same file.
+fn default_lock_file(root_folder: &Path, device_id: &str) -> PathBuf {
+    let root_folder = root_folder
+        .canonicalize()
+        .unwrap_or_else(|_| root_folder.to_path_buf());
+    let hash = Sha256::digest(root_folder.to_string_lossy().as_bytes());
+    let hash: String = hash[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let folder = directories::ProjectDirs::from("xyz", "geemili", "augr")
+        .map(|dirs| dirs.cache_dir().to_path_buf())
+        .unwrap_or_else(std::env::temp_dir);
+    folder.join(format!("{}-{}.lock", device_id, hash))
+}
+
+/// Remove conflict copies that have been merged into a saved meta. Another
+/// process may have removed them already.
+fn remove_conflict_copies(paths: &[PathBuf]) -> Result<(), SyncFolderStoreError> {
+    for path in paths {
+        match remove_file(path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            result => result.context(RemoveFile { path })?,
+        }
+    }
+    Ok(())
+}
+
 impl Store for SyncFolderStore {
     type Error = SyncFolderStoreError;
 
@@ -113,42 +497,59 @@ impl Store for SyncFolderStore {
     fn get_meta(&self) -> Result<Meta, Self::Error> {
         let path = self.meta_file_path();
 
-        if path.exists() || !self.init {
+        let meta = if path.exists() || !self.init {
             let contents = read_to_string(&path).context(ReadFile { path })?;
 
-            let meta = toml::de::from_str(&contents).context(DeserializeMeta {
+            toml::de::from_str(&contents).context(DeserializeMeta {
                 device_id: self.device_id.clone(),
-            })?;
-
-            Ok(meta)
+            })?
         } else {
-            Ok(Meta::new())
-        }
+            Meta::new()
+        };
+
+        // The patches in conflict copies of this device's meta were loaded
+        // on this device at some point, so they are kept
+        read_merged_meta(meta, &self.own_conflict_copies()?)
     }
 
+    /// Conflict copies whose patches are all in the saved meta aren't needed
+    /// anymore, and are removed
     fn save_meta(&mut self, meta: &Meta) -> Result<(), Self::Error> {
-        let contents = toml::ser::to_vec(&meta).context(SerializeMeta {
-            device_id: self.device_id.clone(),
-        })?;
-
-        let path = self.meta_file_path();
-
-        if let Some(parent) = path.parent() {
-            if !parent.exists() {
-                create_dir_all(parent).context(WriteFile { path: parent })?;
+        let conflict_copies = self.own_conflict_copies()?;
+        self.write_meta(meta)?;
+
+        let saved: BTreeSet<&PatchRef> = meta.patches().collect();
+        let mut merged = Vec::new();
+        for path in conflict_copies {
+            let copy = read_merged_meta(Meta::new(), std::slice::from_ref(&path))?;
+            if copy.patches().all(|patch_ref| saved.contains(patch_ref)) {
+                merged.push(path);
             }
         }
+        remove_conflict_copies(&merged)
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path.clone())
-            .context(WriteFile { path: path.clone() })?;
-
-        file.write_all(contents.as_slice())
-            .context(WriteFile { path: path.clone() })?;
+    /// The meta `update` is given includes the conflict copies of this
+    /// device's meta, so they are removed once its result has been saved
+    fn update_meta<F>(&mut self, update: F) -> Result<(), Self::Error>
+    where
+        F: FnOnce(Meta) -> Meta,
+    {
+        let _lock = self.lock_meta()?;
+        // Copies that arrive after this are left for the next update, since
+        // `update` may not see them
+        let conflict_copies = self.own_conflict_copies()?;
+        let meta = update(self.get_meta()?);
+        self.write_meta(&meta)?;
+        remove_conflict_copies(&conflict_copies)
+    }
 
-        Ok(())
+    fn has_patch(&self, patch_ref: &PatchRef) -> Result<bool, Self::Error> {
+        let path = self
+            .patch_folder
+            .join(patch_ref.to_string())
+            .with_extension("toml");
+        Ok(path.exists())
     }
 
     #[cfg_attr(feature = "flame_it", flame)]
@@ -160,10 +561,25 @@ impl Store for SyncFolderStore {
 
         let contents = load_file_contents(&path).context(ReadFile { path })?;
 
-        let patch = toml::de::from_str(&contents).context(DeserializePatch {
+        let patch: Patch = toml::de::from_str(&contents).context(DeserializePatch {
             patch_ref: patch_ref.to_string(),
         })?;
 
+        if let Some(format) = patch.unsupported_format() {
+            return UnsupportedPatchFormat {
+                patch_ref: *patch_ref,
+                format,
+            }
+            .fail();
+        }
+
+        if !patch.verify_id(patch_ref) {
+            return CorruptPatch {
+                patch_ref: *patch_ref,
+            }
+            .fail();
+        }
+
         Ok(patch)
     }
 
@@ -181,16 +597,139 @@ impl Store for SyncFolderStore {
             device_id: self.device_id.clone(),
         })?;
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path.clone())
-            .context(WriteFile { path: path.clone() })?;
+        write_atomically(&path, false, |file| file.write_all(contents.as_slice()))
+            .context(WriteFile { path })
+    }
+
+    fn remove_patch(&mut self, patch_ref: &PatchRef) -> Result<(), RemovePatchError<Self::Error>> {
+        let path = self
+            .patch_folder
+            .join(patch_ref.to_string())
+            .with_extension("toml");
+
+        match remove_file(&path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result.context(RemoveFile { path })?),
+        }
+    }
+}
+
+impl SyncStore for SyncFolderStore {
+    /// Conflict copies made by Syncthing are merged into the meta of the
+    /// device they belong to
+    fn get_other_metas(&self) -> Result<OtherMetas<Self::Error>, Self::Error> {
+        let meta_folder = self.root_folder.join("meta");
+        if !meta_folder.exists() {
+            create_dir_all(&meta_folder).context(IOError {})?;
+        }
+
+        let (mut meta_files, _report) = self.scan_meta_folder()?;
+        meta_files.remove(&self.device_id);
+
+        Ok(meta_files
+            .into_iter()
+            .map(|(device_id, paths)| (device_id, read_merged_meta(Meta::new(), &paths)))
+            .collect())
+    }
+}
+
+/// Write a file so that it either has all of its new contents, or is left as
+/// it was. The contents are written to a temporary file in the same folder,
+/// flushed to disk, and then renamed over `path`, so that a crash never
+/// leaves a half-written file for Syncthing to spread to other devices.
+///
+/// If `overwrite` is false, an existing file at `path` is an error, unless it
+/// already has the new contents. The temporary file is put in place with
+/// `place_new` then, so that a file created by another process in the
+/// meantime isn't replaced.
+fn write_atomically<F>(path: &Path, overwrite: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    write_atomically_with(path, overwrite, write, |from: &Path, to: &Path| {
+        hard_link(from, to)
+    })
+}
+
+/// `write_atomically`, with the way a new file is hard linked passed in, so
+/// that tests can act like a file system without hard links
+fn write_atomically_with<F, L>(path: &Path, overwrite: bool, write: F, link: L) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+    L: FnOnce(&Path, &Path) -> io::Result<()>,
+{
+    let folder = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = folder.join(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|()| {
+            if overwrite {
+                rename(&temp_path, path)
+            } else {
+                place_new(&temp_path, path, link)
+            }
+        });
+    if result.is_err() {
+        let _ = remove_file(&temp_path);
+        return result;
+    }
 
-        file.write_all(contents.as_slice())
-            .context(WriteFile { path: path.clone() })?;
+    // Make sure the rename itself survives a crash
+    #[cfg(unix)]
+    File::open(folder)?.sync_all()?;
 
-        Ok(())
+    Ok(())
+}
+
+/// Move the finished temporary file to `path`, without replacing a file that
+/// is already there. It is hard linked if the file system allows it. Those
+/// that don't, like Android's shared storage, FAT and many network mounts,
+/// get a copy in a newly created file instead, which is removed again if the
+/// copy fails.
+///
+/// A file at `path` with the same contents counts as success, so that a write
+/// that failed after the file was in place can be retried.
+fn place_new<L>(temp_path: &Path, path: &Path, link: L) -> io::Result<()>
+where
+    L: FnOnce(&Path, &Path) -> io::Result<()>,
+{
+    let result = match link(temp_path, path) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => copy_new(temp_path, path),
+        result => result,
+    };
+    match result {
+        Err(ref e) if e.kind() == ErrorKind::AlreadyExists && same_contents(temp_path, path) => {}
+        result => result?,
+    }
+    remove_file(temp_path)
+}
+
+fn copy_new(from: &Path, to: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(to)?;
+    let result = File::open(from)
+        .and_then(|mut from| io::copy(&mut from, &mut file))
+        .and_then(|_| file.sync_all());
+    if result.is_err() {
+        let _ = remove_file(to);
+    }
+    result
+}
+
+fn same_contents(a: &Path, b: &Path) -> bool {
+    match (read(a), read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
     }
 }
 
@@ -198,3 +737,205 @@ impl Store for SyncFolderStore {
 fn load_file_contents(path: &std::path::Path) -> Result<String, std::io::Error> {
     read_to_string(&path)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{read_dir, remove_dir_all};
+
+    struct TempFolder(PathBuf);
+
+    impl TempFolder {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("augr-test-{}", Uuid::new_v4()));
+            create_dir_all(&path).unwrap();
+            TempFolder(path)
+        }
+
+        fn file_names(&self) -> Vec<String> {
+            let mut names: Vec<String> = read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TempFolder {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes part of the contents, then fails as if the process had crashed
+    fn interrupted(file: &mut File) -> io::Result<()> {
+        file.write_all(b"patches = [\"c10350e8")?;
+        Err(io::Error::new(ErrorKind::Interrupted, "interrupted"))
+    }
+
+    #[test]
+    fn interrupted_write_leaves_file_intact() {
+        let folder = TempFolder::new();
+        let path = folder.0.join("laptop.toml");
+        write_atomically(&path, true, |file| file.write_all(b"patches = []\n")).unwrap();
+
+        assert!(write_atomically(&path, true, interrupted).is_err());
+
+        assert_eq!(read_to_string(&path).unwrap(), "patches = []\n");
+        assert_eq!(folder.file_names(), vec!["laptop.toml"]);
+    }
+
+    #[test]
+    fn interrupted_write_creates_nothing() {
+        let folder = TempFolder::new();
+        let path = folder.0.join("patch.toml");
+
+        assert!(write_atomically(&path, false, interrupted).is_err());
+
+        assert!(folder.file_names().is_empty());
+    }
+
+    fn device_of(file_name: &str) -> Option<(bool, String)> {
+        match classify_meta_file(file_name) {
+            MetaFile::Meta(device_id) => Some((false, device_id)),
+            MetaFile::ConflictCopy(device_id) => Some((true, device_id)),
+            MetaFile::Ignored => None,
+        }
+    }
+
+    #[test]
+    fn classify_meta_files() {
+        assert_eq!(device_of("laptop.toml"), Some((false, "laptop".into())));
+        assert_eq!(
+            device_of("laptop.sync-conflict-20190901-120000-ABCDEFG.toml"),
+            Some((true, "laptop".into()))
+        );
+        assert_eq!(device_of(".syncthing.laptop.toml.tmp"), None);
+        assert_eq!(device_of("~syncthing~laptop.toml.tmp"), None);
+        assert_eq!(device_of(".laptop.toml.swp"), None);
+        assert_eq!(device_of("laptop.toml~"), None);
+        assert_eq!(device_of("laptop.toml.bak"), None);
+        assert_eq!(device_of("#laptop.toml#"), None);
+        assert_eq!(device_of(".toml"), None);
+    }
+
+    fn kind_of(file_name: &str) -> &'static str {
+        match classify_patch_file(file_name) {
+            PatchFile::Patch(_) => "patch",
+            PatchFile::Temporary => "temporary",
+            PatchFile::Downloading => "downloading",
+            PatchFile::ConflictCopy => "conflict copy",
+            PatchFile::Unrecognised => "unrecognised",
+        }
+    }
+
+    #[test]
+    fn classify_patch_files() {
+        let id = "c10350e8-5d2f-4a3b-8f6e-2a8a0f3f6a11";
+        assert_eq!(kind_of(&format!("{}.toml", id)), "patch");
+        assert_eq!(
+            kind_of(&format!(".{}.toml.{}.tmp", id, Uuid::new_v4())),
+            "temporary"
+        );
+        assert_eq!(
+            kind_of(&format!(".syncthing.{}.toml.tmp", id)),
+            "downloading"
+        );
+        assert_eq!(
+            kind_of(&format!("~syncthing~{}.toml.tmp", id)),
+            "downloading"
+        );
+        assert_eq!(
+            kind_of(&format!(
+                "{}.sync-conflict-20190901-120000-ABCDEFG.toml",
+                id
+            )),
+            "conflict copy"
+        );
+        assert_eq!(kind_of("notes.toml"), "unrecognised");
+        assert_eq!(kind_of(&format!("{}.toml.bak", id)), "unrecognised");
+    }
+
+    #[test]
+    fn default_lock_file_is_stable() {
+        let path = default_lock_file(Path::new("/nonexistent/augr"), "laptop");
+        assert_eq!(
+            path.file_name().unwrap().to_string_lossy(),
+            "laptop-7bb5b77aa4d94c0e.lock"
+        );
+    }
+
+    #[test]
+    fn existing_file_is_not_overwritten() {
+        let folder = TempFolder::new();
+        let path = folder.0.join("patch.toml");
+        write_atomically(&path, false, |file| file.write_all(b"first")).unwrap();
+
+        let err = write_atomically(&path, false, |file| file.write_all(b"second")).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(&path).unwrap(), "first");
+        assert_eq!(folder.file_names(), vec!["patch.toml"]);
+    }
+
+    #[test]
+    fn file_created_during_write_is_not_overwritten() {
+        let folder = TempFolder::new();
+        let path = folder.0.join("patch.toml");
+
+        // Another process creates the file after it was checked for, while
+        // the contents are being written
+        let err = write_atomically(&path, false, |file| {
+            std::fs::write(&path, b"first")?;
+            file.write_all(b"second")
+        })
+        .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(&path).unwrap(), "first");
+        assert_eq!(folder.file_names(), vec!["patch.toml"]);
+    }
+
+    #[test]
+    fn existing_file_with_same_contents_is_written() {
+        let folder = TempFolder::new();
+        let path = folder.0.join("patch.toml");
+        write_atomically(&path, false, |file| file.write_all(b"first")).unwrap();
+
+        write_atomically(&path, false, |file| file.write_all(b"first")).unwrap();
+
+        assert_eq!(read_to_string(&path).unwrap(), "first");
+        assert_eq!(folder.file_names(), vec!["patch.toml"]);
+    }
+
+    /// Fails like a file system without hard links, such as FAT
+    fn no_hard_links(_from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "operation not permitted",
+        ))
+    }
+
+    #[test]
+    fn new_file_is_copied_without_hard_links() {
+        let folder = TempFolder::new();
+        let path = folder.0.join("patch.toml");
+
+        write_atomically_with(&path, false, |file| file.write_all(b"first"), no_hard_links)
+            .unwrap();
+        let err = write_atomically_with(
+            &path,
+            false,
+            |file| file.write_all(b"second"),
+            no_hard_links,
+        )
+        .unwrap_err();
+        write_atomically_with(&path, false, |file| file.write_all(b"first"), no_hard_links)
+            .unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(&path).unwrap(), "first");
+        assert_eq!(folder.file_names(), vec!["patch.toml"]);
+    }
+}
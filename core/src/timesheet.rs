@@ -1,17 +1,68 @@
-use crate::{repository::timesheet::PatchedTimesheet, EventRef, Tag};
+use crate::{
+    repository::{
+        event::{attributes_of, SupersededStart},
+        timesheet::PatchedTimesheet,
+    },
+    EventRef, Tag,
+};
 use chrono::{DateTime, Duration, Utc};
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// Maps an attribute key to every value it has been given. Concurrent edits
+/// from different devices may leave a key with more than one value.
+pub type Attributes = BTreeMap<String, BTreeSet<AttributeValue>>;
+
+/// The value of an attribute. Patches store values as text, and they are
+/// given a type when they are read, so `billable=true` is a boolean and
+/// `hours=3` is an integer.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum AttributeValue {
+    Bool(bool),
+    Integer(i64),
+    Text(String),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(text: &str) -> Self {
+        match text {
+            "true" => AttributeValue::Bool(true),
+            "false" => AttributeValue::Bool(false),
+            _ => match text.parse::<i64>() {
+                // Values like `007` are kept as text, so they are shown the
+                // way they were written
+                Ok(integer) if integer.to_string() == text => AttributeValue::Integer(integer),
+                _ => AttributeValue::Text(text.to_string()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeValue::Bool(value) => write!(f, "{}", value),
+            AttributeValue::Integer(value) => write!(f, "{}", value),
+            AttributeValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Event {
     start: DateTime<Utc>,
     tags: BTreeSet<Tag>,
+    notes: Vec<String>,
+    attributes: Attributes,
 }
 
 #[derive(Clone, Debug)]
 pub struct Timesheet<'cl> {
     patched_timesheet: &'cl PatchedTimesheet,
     event_starts: BTreeMap<DateTime<Utc>, EventRef>,
+    superseded_starts: BTreeMap<EventRef, Vec<SupersededStart>>,
 }
 
 #[derive(Clone, Debug)]
@@ -19,13 +70,26 @@ pub struct Segment {
     pub event_ref: EventRef,
     pub start_time: DateTime<Utc>,
     pub tags: BTreeSet<Tag>,
+    pub notes: Vec<String>,
+    pub attributes: Attributes,
     pub duration: Duration,
     pub end_time: DateTime<Utc>,
 }
 
 impl Event {
     pub fn new(start: DateTime<Utc>, tags: BTreeSet<Tag>) -> Self {
-        Self { start, tags }
+        Self {
+            start,
+            tags,
+            notes: Vec::new(),
+            attributes: Attributes::new(),
+        }
+    }
+
+    /// Set the notes on the event, in the order they were written
+    pub fn with_notes(mut self, notes: Vec<String>) -> Self {
+        self.notes = notes;
+        self
     }
 
     pub fn start(&self) -> &DateTime<Utc> {
@@ -35,6 +99,29 @@ impl Event {
     pub fn tags(&self) -> &BTreeSet<Tag> {
         &self.tags
     }
+
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    pub fn with_attributes(mut self, attributes: Attributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    pub fn has_attribute(&self, key: &str, value: &str) -> bool {
+        has_attribute(&self.attributes, key, value)
+    }
+}
+
+impl Segment {
+    pub fn has_attribute(&self, key: &str, value: &str) -> bool {
+        has_attribute(&self.attributes, key, value)
+    }
 }
 
 impl<'a, 'b> PartialEq<Timesheet<'b>> for Timesheet<'a> {
@@ -56,6 +143,7 @@ impl<'cl> Timesheet<'cl> {
         Self {
             patched_timesheet,
             event_starts: BTreeMap::new(),
+            superseded_starts: BTreeMap::new(),
         }
     }
 
@@ -70,6 +158,19 @@ impl<'cl> Timesheet<'cl> {
         }
     }
 
+    pub fn add_superseded_starts(&mut self, event_ref: EventRef, starts: Vec<SupersededStart>) {
+        self.superseded_starts
+            .entry(event_ref)
+            .or_default()
+            .extend(starts);
+    }
+
+    /// The start times that were discarded while resolving concurrent edits,
+    /// grouped by event. Always empty for a strictly flattened timesheet.
+    pub fn superseded_starts(&self) -> &BTreeMap<EventRef, Vec<SupersededStart>> {
+        &self.superseded_starts
+    }
+
     pub fn events(&self) -> BTreeMap<DateTime<Utc>, BTreeSet<Tag>> {
         self.event_starts
             .iter()
@@ -97,6 +198,8 @@ impl<'cl> Timesheet<'cl> {
                     event_ref: event_ref.clone(),
                     start_time: *start_time,
                     tags: event.tags().into_iter().map(|(_ref, tag)| tag).collect(),
+                    notes: event.notes().into_iter().map(|(_ref, note)| note).collect(),
+                    attributes: attributes_of(event),
                     duration,
                     end_time: *end_time,
                 }
@@ -104,6 +207,13 @@ impl<'cl> Timesheet<'cl> {
             .collect()
     }
 
+    pub fn attributes_at_time(&self, datetime: &DateTime<Utc>) -> Option<Attributes> {
+        self.event_starts
+            .range::<DateTime<_>, _>(..datetime)
+            .last()
+            .map(|(_time, event_ref)| attributes_of(&self.patched_timesheet.events[event_ref]))
+    }
+
     pub fn tags_at_time<'ts>(&'ts self, datetime: &DateTime<Utc>) -> Option<BTreeSet<Tag>> {
         self.event_starts
             .range::<DateTime<_>, _>(..datetime)
@@ -117,3 +227,13 @@ impl<'cl> Timesheet<'cl> {
             })
     }
 }
+
+/// Whether `key` has been given `value`. The value is typed the same way as
+/// the values in patches, so `billable=true` only matches the boolean.
+pub fn has_attribute(attributes: &Attributes, key: &str, value: &str) -> bool {
+    let value = AttributeValue::from(value);
+    attributes
+        .get(key)
+        .map(|values| values.contains(&value))
+        .unwrap_or(false)
+}
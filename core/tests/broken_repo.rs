@@ -1,65 +1,67 @@
 use augr_core::{
     repository::{
-        event::Error as EventError, timesheet::Error as TimesheetError, Error as RepositoryError,
+        event::{Error as EventError, ResolutionPolicy, SupersededStart},
+        timesheet::Error as TimesheetError,
+        Error as RepositoryError,
     },
+    store::{MemoryStore, MemoryStoreError, RemovePatchError},
     Meta, Patch, PatchRef, Repository, Store,
 };
 use chrono::{DateTime, Utc};
-use snafu::Snafu;
-use std::collections::BTreeMap;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
 use uuid::Uuid;
 
+/// A `MemoryStore` that counts how often each patch is read
 #[derive(Debug)]
 struct MemStore {
-    meta: Meta,
-    patches: BTreeMap<PatchRef, Patch>,
+    store: MemoryStore,
+    reads: Rc<RefCell<BTreeMap<PatchRef, usize>>>,
 }
 
 impl MemStore {
     pub fn new(meta: Meta) -> Self {
         Self {
-            meta,
-            patches: BTreeMap::new(),
+            store: MemoryStore::new("laptop".to_string()).meta("laptop", meta),
+            reads: Rc::new(RefCell::new(BTreeMap::new())),
         }
     }
 
     pub fn patch(mut self, patch: Patch) -> Self {
-        self.patches.insert(patch.patch_ref().clone(), patch);
+        self.store = self.store.patch(patch);
         self
     }
 }
 
-#[derive(Eq, PartialEq, Debug, Snafu)]
-pub enum MemStoreError {
-    #[snafu(display("Meta not found {}", device_id))]
-    MetaNotFound { device_id: String },
-
-    #[snafu(display("Patch not found {}", patch_ref))]
-    PatchNotFound { patch_ref: PatchRef },
-}
-
 impl Store for MemStore {
-    type Error = MemStoreError;
+    type Error = MemoryStoreError;
 
     fn get_meta(&self) -> Result<Meta, Self::Error> {
-        Ok(self.meta.clone())
+        self.store.get_meta()
+    }
+
+    fn save_meta(&mut self, meta: &Meta) -> Result<(), Self::Error> {
+        self.store.save_meta(meta)
     }
 
-    fn save_meta(&mut self, _meta: &Meta) -> Result<(), Self::Error> {
-        unimplemented!()
+    fn add_patch(&mut self, patch: &Patch) -> Result<(), Self::Error> {
+        self.store.add_patch(patch)
     }
 
-    fn add_patch(&mut self, _patch: &Patch) -> Result<(), Self::Error> {
-        unimplemented!()
+    fn remove_patch(&mut self, patch_ref: &PatchRef) -> Result<(), RemovePatchError<Self::Error>> {
+        self.store.remove_patch(patch_ref)
     }
 
     fn get_patch(&self, patch_ref: &PatchRef) -> Result<Patch, Self::Error> {
-        self.patches
-            .get(patch_ref)
-            .map(|x| x.clone())
-            .ok_or(MemStoreError::PatchNotFound {
-                patch_ref: patch_ref.clone(),
-            })
+        *self.reads.borrow_mut().entry(*patch_ref).or_default() += 1;
+        self.store.get_patch(patch_ref)
+    }
+
+    fn has_patch(&self, patch_ref: &PatchRef) -> Result<bool, Self::Error> {
+        self.store.has_patch(patch_ref)
     }
 }
 
@@ -129,13 +131,109 @@ fn unknown_patch_reported() {
     let errors = Repository::from_store(store).unwrap_err();
 
     assert!(errors.contains(&RepositoryError::PatchNotFound {
-        source: MemStoreError::PatchNotFound {
+        source: MemoryStoreError::MissingPatch {
             patch_ref: patch2.clone(),
         },
         patch: patch2.clone(),
     }));
 }
 
+#[test]
+fn dangling_parent_reported() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+
+    let store = MemStore::new(meta![patch3])
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("work")))
+        .patch(p!(patch3).add_tag(*patch2, s!("a"), s!("coding")));
+
+    let errors = Repository::from_store(store).unwrap_err();
+
+    assert!(errors.contains(&RepositoryError::DanglingParents {
+        patch: *patch2,
+        parents: vec![*patch1],
+    }));
+    assert!(errors.contains(&RepositoryError::MissingParentPatches {
+        patch: *patch3,
+        parents: vec![*patch2],
+    }));
+}
+
+#[test]
+fn patch_cycle_reported() {
+    let patch1 = &Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = &Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+    let patch3 = &Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+    let store = MemStore::new(meta![patch3])
+        .patch(p!(patch1).add_tag(*patch2, s!("a"), s!("work")))
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("coding")))
+        .patch(p!(patch3).add_tag(*patch1, s!("a"), s!("rust")));
+
+    let errors = Repository::from_store(store).unwrap_err();
+
+    assert_eq!(
+        errors,
+        vec![
+            RepositoryError::PatchCycle {
+                patches: vec![*patch1, *patch2],
+            },
+            RepositoryError::MissingParentPatches {
+                patch: *patch3,
+                parents: vec![*patch1],
+            },
+        ]
+    );
+}
+
+#[test]
+fn each_patch_read_once() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+    let patch4 = &Uuid::new_v4();
+
+    // patch4 is listed first, so its ancestors have to be found before it
+    // can be applied
+    let store = MemStore::new(meta![patch4, patch3, patch2, patch1])
+        .patch(p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]))
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("food")))
+        .patch(p!(patch3).add_tag(*patch2, s!("a"), s!("sandwich")))
+        .patch(p!(patch4).add_tag(*patch3, s!("a"), s!("break")).add_tag(
+            *patch1,
+            s!("a"),
+            s!("rest"),
+        ));
+    let reads = store.reads.clone();
+
+    let repo = Repository::from_store(store).unwrap();
+
+    assert_eq!(repo.patches_loaded().count(), 4);
+    assert!(reads.borrow().values().all(|count| *count == 1));
+}
+
+#[test]
+fn patches_in_snapshot_are_not_read() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+
+    let first = p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]);
+    let snapshot = Repository::from_store(MemStore::new(meta![patch1]).patch(first.clone()))
+        .unwrap()
+        .snapshot();
+
+    let store = MemStore::new(meta![patch1, patch2])
+        .patch(first)
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("food")));
+    let reads = store.reads.clone();
+
+    let repo = Repository::from_store_with_snapshot(store, snapshot).unwrap();
+
+    assert_eq!(repo.patches_loaded().count(), 2);
+    assert_eq!(reads.borrow().keys().collect::<Vec<_>>(), vec![patch2]);
+}
+
 #[test]
 fn invalid_number_of_start_times() {
     let patch1 = &Uuid::new_v4();
@@ -166,3 +264,365 @@ fn invalid_number_of_start_times() {
         event: s!("b")
     }));
 }
+
+#[test]
+fn removed_event_is_not_flattened() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+
+    let store = MemStore::new(meta![patch3])
+        .patch(
+            p!(patch1)
+                .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch", "food"])
+                .create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), sl!["work"]),
+        )
+        .patch(p!(patch2).remove_start(*patch1, s!("b"), dt!("2019-07-23T13:00:00Z")))
+        .patch(p!(patch3).remove_event(*patch2, s!("b")));
+
+    let repo = Repository::from_store(store).unwrap();
+    let timesheet = repo
+        .timesheet()
+        .flatten()
+        .expect("removed event to be skipped when flattening");
+
+    let mut expected = BTreeMap::new();
+    expected.insert(dt!("2019-07-23T12:00:00Z"), sl!["lunch", "food"]);
+    assert!(timesheet.eq(&expected));
+}
+
+#[test]
+fn concurrent_start_times_resolved_deterministically() {
+    let patch1 = &Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = &Uuid::parse_str("81790c38-96dd-4577-8b85-9f7c8bd6802b").unwrap();
+    let patch3 = &Uuid::parse_str("dad9051e-2e83-446e-b9aa-299bd4a34b37").unwrap();
+
+    let patches = [
+        p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]),
+        p!(patch2)
+            .remove_start(*patch1, s!("a"), dt!("2019-07-23T12:00:00Z"))
+            .add_start(*patch1, s!("a"), dt!("2019-07-23T12:15:00Z")),
+        p!(patch3)
+            .remove_start(*patch1, s!("a"), dt!("2019-07-23T12:00:00Z"))
+            .add_start(*patch1, s!("a"), dt!("2019-07-23T12:30:00Z")),
+    ];
+
+    // Every device must pick the same winner, no matter which order it
+    // received the patches in
+    for meta in [meta![patch2, patch3], meta![patch3, patch2]].iter() {
+        let store = patches
+            .iter()
+            .cloned()
+            .fold(MemStore::new(meta.clone()), |store, patch| {
+                store.patch(patch)
+            });
+        let repo = Repository::from_store(store).unwrap();
+
+        let timesheet = repo
+            .timesheet()
+            .flatten_with(ResolutionPolicy::Deterministic)
+            .expect("conflict to be resolved");
+
+        let mut expected = BTreeMap::new();
+        expected.insert(dt!("2019-07-23T12:30:00Z"), sl!["lunch"]);
+        assert!(timesheet.eq(&expected));
+        assert_eq!(
+            timesheet.superseded_starts()[&s!("a")],
+            vec![SupersededStart {
+                patch: *patch2,
+                time: dt!("2019-07-23T12:15:00Z"),
+                winning_patch: *patch3,
+                winning_time: dt!("2019-07-23T12:30:00Z"),
+            }]
+        );
+    }
+}
+
+/// Loads `patch1`, which creates event "a" with the tag "work", followed by
+/// `broken`, and returns the conflicts reported for `broken`
+fn conflicts_after(broken: Patch) -> Vec<TimesheetError> {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let broken_ref = *broken.patch_ref();
+
+    let store = MemStore::new(meta![broken_ref])
+        .patch(p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["work"]))
+        .patch(broken);
+
+    let errors = Repository::from_store(store).expect_err("patch to produce error");
+    errors
+        .into_iter()
+        .filter_map(|error| match error {
+            RepositoryError::PatchingTimesheet { patch, conflicts } if patch == broken_ref => {
+                Some(conflicts)
+            }
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+#[test]
+fn add_tag_to_unknown_event_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts = conflicts_after(p!(patch2).add_tag(patch1, s!("b"), s!("coding")));
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::UnknownEvent {
+            patch: patch2,
+            event: s!("b")
+        }]
+    );
+}
+
+#[test]
+fn remove_tag_from_unknown_event_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts = conflicts_after(p!(patch2).remove_tag(patch1, s!("b"), s!("work")));
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::UnknownEvent {
+            patch: patch2,
+            event: s!("b")
+        }]
+    );
+}
+
+#[test]
+fn remove_start_not_added_by_patch_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts =
+        conflicts_after(p!(patch2).remove_start(patch1, s!("a"), dt!("2019-07-23T12:30:00Z")));
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::UnknownStart {
+            patch: patch2,
+            event: s!("a"),
+            added_by: patch1,
+            time: dt!("2019-07-23T12:30:00Z"),
+        }]
+    );
+}
+
+#[test]
+fn remove_tag_not_added_by_patch_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts = conflicts_after(p!(patch2).remove_tag(patch1, s!("a"), s!("coding")));
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::UnknownTag {
+            patch: patch2,
+            event: s!("a"),
+            added_by: patch1,
+            tag: s!("coding"),
+        }]
+    );
+}
+
+#[test]
+fn remove_note_not_added_by_patch_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts = conflicts_after(p!(patch2).remove_note(patch1, s!("a"), s!("hello")));
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::UnknownNote {
+            patch: patch2,
+            event: s!("a"),
+            added_by: patch1,
+            note: s!("hello"),
+        }]
+    );
+}
+
+#[test]
+fn remove_attribute_not_added_by_patch_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts =
+        conflicts_after(p!(patch2).remove_attribute(patch1, s!("a"), s!("client"), s!("acme")));
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::UnknownAttribute {
+            patch: patch2,
+            event: s!("a"),
+            added_by: patch1,
+            key: s!("client"),
+            value: s!("acme"),
+        }]
+    );
+}
+
+#[test]
+fn operations_on_unknown_events_reported() {
+    let patch1 = Uuid::parse_str("2a226f4d-60f2-493d-9e9a-d6c71d98b515").unwrap();
+    let patch2 = Uuid::new_v4();
+
+    let conflicts = conflicts_after(
+        p!(patch2)
+            .add_start(patch1, s!("b"), dt!("2019-07-23T12:30:00Z"))
+            .add_note(patch1, s!("c"), s!("hello"))
+            .add_attribute(patch1, s!("d"), s!("client"), s!("acme"))
+            .remove_event(patch1, s!("e")),
+    );
+
+    for event in ["b", "c", "d", "e"].iter() {
+        assert!(conflicts.contains(&TimesheetError::UnknownEvent {
+            patch: patch2,
+            event: s!(event)
+        }));
+    }
+    assert_eq!(conflicts.len(), 4);
+}
+
+#[test]
+fn duplicate_event_in_one_patch_reported() {
+    let patch2 = Uuid::new_v4();
+
+    let conflicts = conflicts_after(
+        p!(patch2)
+            .create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), sl!["work"])
+            .create_event(s!("b"), dt!("2019-07-23T14:00:00Z"), sl!["food"]),
+    );
+
+    assert_eq!(
+        conflicts,
+        vec![TimesheetError::DuplicateEventId { id: s!("b") }]
+    );
+}
+
+#[test]
+fn lenient_load_skips_unreadable_patches() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+
+    let store = MemStore::new(meta![patch2, patch3])
+        .patch(p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]))
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("food")));
+
+    let (repo, report) = Repository::from_store_lenient(store).unwrap();
+
+    assert_eq!(repo.patches_loaded().count(), 2);
+    assert_eq!(report.missing, Some(*patch3).into_iter().collect());
+    assert!(report.quarantined.is_empty());
+    let timesheet = repo.timesheet().flatten().unwrap();
+    let mut expected = BTreeMap::new();
+    expected.insert(dt!("2019-07-23T12:00:00Z"), sl!["food", "lunch"]);
+    assert!(timesheet.eq(&expected));
+}
+
+#[test]
+fn lenient_load_quarantines_patches_waiting_for_parents() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+
+    // patch1 hasn't arrived yet
+    let store = MemStore::new(meta![patch2, patch3])
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("food")))
+        .patch(p!(patch3).create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), sl!["work"]));
+    let memory = store.store.clone();
+
+    let (mut repo, report) = Repository::from_store_lenient(store).unwrap();
+
+    assert_eq!(repo.patches_loaded().collect::<Vec<_>>(), vec![patch3]);
+    assert_eq!(report.missing, Some(*patch1).into_iter().collect());
+    assert_eq!(report.quarantined, Some(*patch2).into_iter().collect());
+
+    // Quarantined patches stay in the meta, so they can be loaded once their
+    // parents arrive
+    repo.save_meta().unwrap();
+    let saved: BTreeSet<Uuid> = memory.get_meta().unwrap().patches().cloned().collect();
+    assert_eq!(saved, vec![*patch2, *patch3].into_iter().collect());
+    let (_repo, report) = Repository::from_store_lenient(memory).unwrap();
+    assert_eq!(report.quarantined, Some(*patch2).into_iter().collect());
+}
+
+#[test]
+fn lenient_load_quarantines_descendants_of_conflicting_patches() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+
+    let store = MemStore::new(meta![patch3])
+        .patch(p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]))
+        .patch(p!(patch2).remove_tag(*patch1, s!("a"), s!("food")))
+        .patch(
+            p!(patch3)
+                .add_tag(*patch1, s!("a"), s!("sandwich"))
+                .add_tag(*patch2, s!("a"), s!("break")),
+        );
+
+    let (repo, report) = Repository::from_store_lenient(store).unwrap();
+
+    assert_eq!(repo.patches_loaded().collect::<Vec<_>>(), vec![patch1]);
+    assert!(report.missing.is_empty());
+    assert_eq!(
+        report.quarantined,
+        vec![*patch2, *patch3].into_iter().collect()
+    );
+    assert!(repo.timesheet().flatten().is_ok());
+}
+
+#[test]
+fn lenient_load_with_snapshot_keeps_the_snapshot() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+    let patch3 = &Uuid::new_v4();
+
+    let first = p!(patch1).create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]);
+    let snapshot = Repository::from_store(MemStore::new(meta![patch1]).patch(first.clone()))
+        .unwrap()
+        .snapshot();
+
+    // patch3 is listed but was never stored
+    let store = MemStore::new(meta![patch1, patch2, patch3])
+        .patch(first)
+        .patch(p!(patch2).add_tag(*patch1, s!("a"), s!("food")));
+    let reads = store.reads.clone();
+
+    let (repo, report) = Repository::from_store_with_snapshot_lenient(store, snapshot).unwrap();
+
+    assert_eq!(repo.patches_loaded().count(), 2);
+    assert_eq!(report.missing, vec![*patch3].into_iter().collect());
+    assert!(report.quarantined.is_empty());
+    assert!(!reads.borrow().contains_key(patch1));
+}
+
+#[test]
+fn checkpoint_is_not_folded_when_its_patch_conflicts() {
+    let patch1 = &Uuid::new_v4();
+    let patch2 = &Uuid::new_v4();
+
+    let mut repo =
+        Repository::from_store(MemStore::new(meta![patch1]).patch(p!(patch1).create_event(
+            s!("a"),
+            dt!("2019-07-23T12:00:00Z"),
+            sl!["lunch"],
+        )))
+        .unwrap();
+    let checkpoint =
+        p!(patch2)
+            .checkpoint(repo.snapshot())
+            .add_tag(*patch1, s!("missing-event"), s!("food"));
+
+    assert!(repo.load_patch(checkpoint).is_err());
+    assert!(repo.folded_patches().is_empty());
+    assert_eq!(repo.patches_loaded().collect::<Vec<_>>(), vec![patch1]);
+}
@@ -0,0 +1,24 @@
+//! Fixtures shared by the integration tests
+
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    path::PathBuf,
+};
+use uuid::Uuid;
+
+/// A folder in the temporary directory, which is removed when dropped
+pub struct TempFolder(pub PathBuf);
+
+impl TempFolder {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("augr-test-{}", Uuid::new_v4()));
+        create_dir_all(&path).unwrap();
+        TempFolder(path)
+    }
+}
+
+impl Drop for TempFolder {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
@@ -1,4 +1,8 @@
-use augr_core::{store::SyncFolderStore, Meta, Patch, Repository, Store, Tag};
+use augr_core::{
+    repository::snapshot::Snapshot,
+    store::{MemoryStore, SyncFolderStore},
+    Meta, Patch, Repository, Store, Tag,
+};
 use chrono::{DateTime, Utc};
 use std::collections::{BTreeMap, BTreeSet};
 use uuid::Uuid;
@@ -78,3 +82,156 @@ fn check_repository_state() {
     assert!(timesheet.is_ok());
     assert!(timesheet.unwrap().eq(&expected_timesheet));
 }
+
+#[test]
+fn event_history_in_causal_order() {
+    let patch1 = Uuid::parse_str("d83f2984-8f59-4a32-9492-f910717b683c").unwrap();
+    let patch2 = Uuid::parse_str("386d2d62-7c3f-4518-9709-d2145261b853").unwrap();
+
+    let repository = Repository::from_store(simple_store()).unwrap();
+
+    let history: Vec<_> = repository
+        .event_history(&s!("a"))
+        .unwrap()
+        .iter()
+        .map(|patch| *patch.patch_ref())
+        .collect();
+    assert_eq!(history, vec![patch1, patch2]);
+
+    let history: Vec<_> = repository
+        .history()
+        .unwrap()
+        .iter()
+        .map(|patch| *patch.patch_ref())
+        .collect();
+    assert_eq!(history, vec![patch1, patch2]);
+}
+
+#[test]
+fn inverse_patch_restores_previous_state() {
+    let patch2 = Uuid::parse_str("386d2d62-7c3f-4518-9709-d2145261b853").unwrap();
+
+    let repository = Repository::from_store(simple_store()).unwrap();
+    let patch = simple_store().get_patch(&patch2).unwrap();
+
+    let mut timesheet = repository.timesheet().clone();
+    let inverse = timesheet.inverse_patch(&patch).unwrap();
+    timesheet.apply_patch(&inverse).unwrap();
+
+    let mut expected_timesheet: BTreeMap<DateTime<Utc>, BTreeSet<Tag>> = BTreeMap::new();
+    expected_timesheet.insert(dt!("2019-07-23T12:00:00Z"), sl!["lunch", "food"]);
+    expected_timesheet.insert(dt!("2019-07-23T13:00:00Z"), sl!["work"]);
+    assert!(timesheet.flatten().unwrap().eq(&expected_timesheet));
+
+    // Everything the patch did has been reverted, so there is nothing left to undo
+    assert!(timesheet.inverse_patch(&patch).unwrap().is_empty());
+}
+
+#[test]
+fn inverse_of_create_event_removes_event() {
+    let patch1 = Uuid::parse_str("d83f2984-8f59-4a32-9492-f910717b683c").unwrap();
+
+    let repository = Repository::from_store(simple_store()).unwrap();
+    let patch = simple_store().get_patch(&patch1).unwrap();
+
+    let mut timesheet = repository.timesheet().clone();
+    let inverse = timesheet.inverse_patch(&patch).unwrap();
+    assert_eq!(inverse.remove_event.len(), 2);
+    timesheet.apply_patch(&inverse).unwrap();
+
+    let expected_timesheet: BTreeMap<DateTime<Utc>, BTreeSet<Tag>> = BTreeMap::new();
+    assert!(timesheet.flatten().unwrap().eq(&expected_timesheet));
+
+    // Deleting an event can't be undone
+    assert!(timesheet.inverse_patch(&inverse).is_err());
+}
+
+fn expected_timesheet() -> BTreeMap<DateTime<Utc>, BTreeSet<Tag>> {
+    let mut expected_timesheet = BTreeMap::new();
+    expected_timesheet.insert(dt!("2019-07-23T12:30:00Z"), sl!["lunch"]);
+    expected_timesheet.insert(dt!("2019-07-23T13:00:00Z"), sl!["work", "awesome-project"]);
+    expected_timesheet
+}
+
+#[test]
+fn snapshot_round_trips_through_toml() {
+    let repository = Repository::from_store(simple_store()).unwrap();
+    let snapshot = repository.snapshot();
+
+    let serialized = toml::to_string(&snapshot).unwrap();
+    let deserialized: Snapshot = toml::from_str(&serialized).unwrap();
+    assert_eq!(deserialized, snapshot);
+
+    let repository = Repository::from_store_with_snapshot(simple_store(), deserialized).unwrap();
+    assert!(repository
+        .timesheet()
+        .flatten()
+        .unwrap()
+        .eq(&expected_timesheet()));
+}
+
+/// A snapshot of a repository that has loaded only `patches`
+fn snapshot_of(patches: Vec<Patch>) -> Snapshot {
+    let mut repository = Repository::from_store(MemoryStore::new(s!("laptop"))).unwrap();
+    for patch in patches {
+        repository.add_patch(patch).unwrap();
+    }
+    repository.snapshot()
+}
+
+#[test]
+fn load_new_patches_on_top_of_snapshot() {
+    let patch1 = Uuid::parse_str("d83f2984-8f59-4a32-9492-f910717b683c").unwrap();
+    let patch2 = Uuid::parse_str("386d2d62-7c3f-4518-9709-d2145261b853").unwrap();
+
+    let snapshot = snapshot_of(vec![simple_store().get_patch(&patch1).unwrap()]);
+
+    let repository = Repository::from_store_with_snapshot(simple_store(), snapshot).unwrap();
+    let loaded: Vec<_> = repository.patches_loaded().cloned().collect();
+    assert_eq!(loaded, vec![patch2, patch1]);
+    assert!(repository
+        .timesheet()
+        .flatten()
+        .unwrap()
+        .eq(&expected_timesheet()));
+}
+
+#[test]
+fn invalid_snapshot_is_rebuilt() {
+    let patch1 = Uuid::parse_str("d83f2984-8f59-4a32-9492-f910717b683c").unwrap();
+
+    // Claims the first patch has been applied, but has none of its events, so
+    // the second patch can't be applied to it
+    let snapshot = snapshot_of(vec![Patch::with_id(patch1)]);
+
+    let repository = Repository::from_store_with_snapshot(simple_store(), snapshot).unwrap();
+    assert!(repository
+        .timesheet()
+        .flatten()
+        .unwrap()
+        .eq(&expected_timesheet()));
+}
+
+#[test]
+fn snapshot_with_removed_patch_is_rebuilt() {
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), sl!["lunch"]);
+    let patch2 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), sl!["work"]);
+    let mut store = MemoryStore::new(s!("laptop"));
+
+    let mut repository = Repository::from_store(store.clone()).unwrap();
+    repository.add_patch(patch1.clone()).unwrap();
+    repository.add_patch(patch2.clone()).unwrap();
+    repository.save_meta().unwrap();
+    let snapshot = repository.snapshot();
+
+    // The store is restored from a backup taken before the second patch
+    store.remove_patch(patch2.patch_ref()).unwrap();
+    store.save_meta(&meta![patch1.patch_ref()]).unwrap();
+
+    let repository = Repository::from_store_with_snapshot(store, snapshot).unwrap();
+    assert_eq!(
+        repository.patches_loaded().collect::<Vec<_>>(),
+        vec![patch1.patch_ref()]
+    );
+    assert_eq!(repository.timesheet().flatten().unwrap().events().len(), 1);
+}
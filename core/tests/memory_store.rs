@@ -0,0 +1,170 @@
+use augr_core::{
+    repository::Error,
+    store::{MemoryStore, MemoryStoreError},
+    Patch, Repository, Store,
+};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+macro_rules! dt {
+    ( $dt:expr ) => {{
+        $dt.parse::<DateTime<Utc>>().expect("Valid datetime")
+    }};
+}
+
+macro_rules! s {
+    ($s:expr) => {
+        $s.to_string()
+    };
+}
+
+/// Load the repository of a device, synchronize it with the other devices,
+/// and save its meta, like the cli does
+fn device(store: &MemoryStore, device: &str) -> Repository<MemoryStore> {
+    let mut repo = Repository::from_store(store.for_device(s!(device))).unwrap();
+    assert!(repo.try_sync_data().unwrap().is_complete());
+    repo.save_meta().unwrap();
+    repo
+}
+
+#[test]
+fn devices_receive_each_others_patches() {
+    let store = MemoryStore::new(s!("laptop"));
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let mut laptop = device(&store, "laptop");
+    laptop.add_patch(patch1.clone()).unwrap();
+    laptop.save_meta().unwrap();
+
+    let patch2 = Patch::new().add_tag(*patch1.patch_ref(), s!("a"), s!("coding"));
+    let mut phone = device(&store, "phone");
+    assert_eq!(phone.patches_loaded().count(), 1);
+    phone.add_patch(patch2.clone()).unwrap();
+    phone.save_meta().unwrap();
+
+    let laptop = device(&store, "laptop");
+    assert_eq!(laptop.patches_loaded().count(), 2);
+    let timesheet = laptop.timesheet().flatten().unwrap();
+    let tags = timesheet.events().values().next().unwrap().clone();
+    assert_eq!(tags, vec![s!("coding"), s!("work")].into_iter().collect());
+
+    assert_eq!(
+        store.device_ids().unwrap(),
+        vec![s!("laptop"), s!("phone")].into_iter().collect()
+    );
+    assert_eq!(
+        store.patch_refs().unwrap(),
+        vec![*patch1.patch_ref(), *patch2.patch_ref()]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn seeded_patches_are_synced() {
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![s!("lunch")]);
+    let store = MemoryStore::new(s!("laptop"))
+        .device_patch("phone", patch1)
+        .device_patch("desktop", patch2);
+
+    // Seeded patches aren't known to the device until it syncs
+    let repo = Repository::from_store(store.clone()).unwrap();
+    assert_eq!(repo.patches_loaded().count(), 0);
+
+    let repo = device(&store, "laptop");
+    assert_eq!(repo.patches_loaded().count(), 2);
+    assert_eq!(store.get_meta().unwrap().patches().count(), 2);
+}
+
+#[test]
+fn sync_is_reported_per_device() {
+    let missing = Uuid::new_v4();
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![]);
+    let patch2 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![]);
+    let dangling = Patch::new().add_tag(missing, s!("b"), s!("lunch"));
+    let store = MemoryStore::new(s!("laptop"))
+        .device_patch("phone", patch1.clone())
+        .device_patch("desktop", patch2.clone())
+        .device_patch("desktop", dangling.clone());
+    let mut phone = store.for_device(s!("phone"));
+    phone
+        .update_meta(|mut meta| {
+            meta.add_patch(missing);
+            meta
+        })
+        .unwrap();
+
+    let mut repo = Repository::from_store(store).unwrap();
+    let report = repo.try_sync_data().unwrap();
+    assert!(!report.is_complete());
+    assert_eq!(
+        report.new_patches(),
+        vec![*patch1.patch_ref(), *patch2.patch_ref()]
+            .into_iter()
+            .collect()
+    );
+
+    let phone = &report.devices["phone"];
+    assert_eq!(phone.new, Some(*patch1.patch_ref()).into_iter().collect());
+    assert_eq!(phone.missing, Some(missing).into_iter().collect());
+    assert!(phone.failed.is_empty());
+    assert!(phone.errors.contains(&Error::PatchNotFound {
+        source: MemoryStoreError::MissingPatch { patch_ref: missing },
+        patch: missing,
+    }));
+
+    // The desktop depends on the same missing patch, so it is reported for
+    // both devices
+    let desktop = &report.devices["desktop"];
+    assert_eq!(desktop.new, Some(*patch2.patch_ref()).into_iter().collect());
+    assert_eq!(desktop.missing, Some(missing).into_iter().collect());
+    assert_eq!(
+        desktop.failed,
+        Some(*dangling.patch_ref()).into_iter().collect()
+    );
+}
+
+#[test]
+fn tampered_patch_is_rejected() {
+    let patch = Patch::new()
+        .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")])
+        .content_addressed();
+    let contents = toml::ser::to_string(&patch).unwrap();
+    let tampered: Patch = toml::de::from_str(&contents.replace("work", "play")).unwrap();
+    let patch_ref = *patch.patch_ref();
+    let store = MemoryStore::new(s!("laptop"))
+        .device_patch("phone", patch)
+        .patch(tampered);
+
+    let mut repo = Repository::from_store(store).unwrap();
+    let errors = repo.try_sync_data().unwrap().into_errors();
+
+    assert_eq!(repo.patches_loaded().count(), 0);
+    assert!(errors.contains(&Error::PatchNotFound {
+        source: MemoryStoreError::CorruptPatch { patch_ref },
+        patch: patch_ref,
+    }));
+}
+
+#[test]
+fn same_change_on_two_devices_is_stored_once() {
+    let store = MemoryStore::new(s!("laptop"));
+    let change = |device: &str| {
+        Patch::new()
+            .device(s!(device))
+            .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")])
+            .content_addressed()
+    };
+
+    // Neither device has seen the other's patch when it makes the change
+    let mut laptop = device(&store, "laptop");
+    let mut phone = device(&store, "phone");
+    laptop.add_patch(change("laptop")).unwrap();
+    laptop.save_meta().unwrap();
+    phone.add_patch(change("phone")).unwrap();
+    phone.save_meta().unwrap();
+
+    let laptop = device(&store, "laptop");
+    assert_eq!(laptop.patches_loaded().count(), 1);
+}
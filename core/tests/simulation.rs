@@ -0,0 +1,25 @@
+use augr_core::simulation::Simulation;
+
+macro_rules! s {
+    ($s:expr) => {
+        $s.to_string()
+    };
+}
+
+#[test]
+fn devices_converge() {
+    let simulation = Simulation::new(vec![s!("laptop"), s!("phone"), s!("desktop")]).steps(60);
+    if let Err(divergence) = simulation.check_seeds(0..200) {
+        panic!("{}", divergence);
+    }
+}
+
+#[test]
+fn devices_converge_when_files_are_often_lost() {
+    let simulation = Simulation::new(vec![s!("laptop"), s!("phone")])
+        .steps(40)
+        .drop_chance(0.5);
+    if let Err(divergence) = simulation.check_seeds(0..200) {
+        panic!("{}", divergence);
+    }
+}
@@ -0,0 +1,287 @@
+//! Behaviour every `Store` should share. Each check is run against every
+//! store implementation.
+
+use augr_core::{
+    store::{patch::CONTENT_ADDRESSED_FORMAT, MemoryStore, SyncFolderStore},
+    Meta, Patch, Repository, Store, SyncStore,
+};
+use chrono::{DateTime, Utc};
+use common::TempFolder;
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+mod common;
+
+macro_rules! dt {
+    ( $dt:expr ) => {{
+        $dt.parse::<DateTime<Utc>>().expect("Valid datetime")
+    }};
+}
+
+macro_rules! s {
+    ($s:expr) => {
+        $s.to_string()
+    };
+}
+
+/// Creates stores for different devices, all sharing the same data
+trait Backend {
+    type Store: SyncStore;
+
+    fn store(&self, device: &str) -> Self::Store;
+}
+
+struct SyncFolderBackend(TempFolder);
+
+impl Backend for SyncFolderBackend {
+    type Store = SyncFolderStore;
+
+    fn store(&self, device: &str) -> SyncFolderStore {
+        SyncFolderStore::new(self.0 .0.clone(), s!(device)).should_init(true)
+    }
+}
+
+struct MemoryBackend(MemoryStore);
+
+impl Backend for MemoryBackend {
+    type Store = MemoryStore;
+
+    fn store(&self, device: &str) -> MemoryStore {
+        self.0.for_device(s!(device))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+struct SqliteBackend(TempFolder);
+
+#[cfg(feature = "sqlite")]
+impl Backend for SqliteBackend {
+    type Store = augr_core::store::SqliteStore;
+
+    fn store(&self, device: &str) -> Self::Store {
+        augr_core::store::SqliteStore::open(&self.0 .0.join("augr.sqlite"), s!(device)).unwrap()
+    }
+}
+
+fn patches(meta: &Meta) -> BTreeSet<Uuid> {
+    meta.patches().cloned().collect()
+}
+
+fn missing_meta_is_empty<B: Backend>(backend: B) {
+    let store = backend.store("laptop");
+    assert_eq!(store.get_meta().unwrap(), Meta::new());
+}
+
+fn meta_round_trips<B: Backend>(backend: B) {
+    let mut store = backend.store("laptop");
+    let mut meta = Meta::new();
+    meta.add_patch(Uuid::new_v4());
+    meta.add_patch(Uuid::new_v4());
+    store.save_meta(&meta).unwrap();
+    assert_eq!(backend.store("laptop").get_meta().unwrap(), meta);
+
+    // Saving a shorter meta replaces the old one
+    let mut shorter = Meta::new();
+    shorter.add_patch(Uuid::new_v4());
+    store.save_meta(&shorter).unwrap();
+    assert_eq!(backend.store("laptop").get_meta().unwrap(), shorter);
+
+    // Other devices have metas of their own
+    assert_eq!(backend.store("phone").get_meta().unwrap(), Meta::new());
+}
+
+fn update_meta_sees_saved_meta<B: Backend>(backend: B) {
+    let patch1 = Uuid::new_v4();
+    let patch2 = Uuid::new_v4();
+    let mut meta = Meta::new();
+    meta.add_patch(patch1);
+    backend.store("laptop").save_meta(&meta).unwrap();
+
+    backend
+        .store("laptop")
+        .update_meta(|mut saved| {
+            saved.add_patch(patch2);
+            saved
+        })
+        .unwrap();
+
+    let meta = backend.store("laptop").get_meta().unwrap();
+    assert_eq!(patches(&meta), vec![patch1, patch2].into_iter().collect());
+}
+
+fn patch_round_trips<B: Backend>(backend: B) {
+    let patch = Patch::new()
+        .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")])
+        .device(s!("laptop"));
+    backend.store("laptop").add_patch(&patch).unwrap();
+
+    // Patches are shared between devices
+    assert_eq!(
+        backend.store("phone").get_patch(patch.patch_ref()).unwrap(),
+        patch
+    );
+}
+
+fn content_addressed_patch_round_trips<B: Backend>(backend: B) {
+    let patch = Patch::new()
+        .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")])
+        .content_addressed();
+    backend.store("laptop").add_patch(&patch).unwrap();
+
+    assert_eq!(
+        backend.store("phone").get_patch(patch.patch_ref()).unwrap(),
+        patch
+    );
+}
+
+fn newer_patch_format_is_unsupported<B: Backend>(backend: B) {
+    let mut patch = Patch::new()
+        .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")])
+        .content_addressed();
+    patch.format = Some(CONTENT_ADDRESSED_FORMAT + 1);
+    backend.store("laptop").add_patch(&patch).unwrap();
+
+    let error = backend
+        .store("phone")
+        .get_patch(patch.patch_ref())
+        .unwrap_err();
+    assert!(error.to_string().contains("doesn't support"), "{}", error);
+}
+
+fn missing_patch_is_an_error<B: Backend>(backend: B) {
+    assert!(backend.store("laptop").get_patch(&Uuid::new_v4()).is_err());
+}
+
+fn patches_are_not_overwritten<B: Backend>(backend: B) {
+    let patch = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![]);
+    let mut store = backend.store("laptop");
+    store.add_patch(&patch).unwrap();
+
+    let imposter = Patch::with_id(*patch.patch_ref()).add_tag(Uuid::new_v4(), s!("a"), s!("x"));
+    assert!(store.add_patch(&imposter).is_err());
+    assert_eq!(store.get_patch(patch.patch_ref()).unwrap(), patch);
+}
+
+fn removed_patches_are_gone<B: Backend>(backend: B) {
+    let patch = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![]);
+    let mut store = backend.store("laptop");
+    store.add_patch(&patch).unwrap();
+
+    store.remove_patch(patch.patch_ref()).unwrap();
+    assert!(store.get_patch(patch.patch_ref()).is_err());
+
+    // Removing a patch that doesn't exist isn't an error
+    store.remove_patch(patch.patch_ref()).unwrap();
+}
+
+fn repository_reloads<B: Backend>(backend: B)
+where
+    <B::Store as Store>::Error: 'static,
+{
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().add_tag(*patch1.patch_ref(), s!("a"), s!("coding"));
+
+    let mut repo = Repository::from_store(backend.store("laptop")).unwrap();
+    repo.add_patch(patch1).unwrap();
+    repo.add_patch(patch2).unwrap();
+    repo.save_meta().unwrap();
+
+    let repo = Repository::from_store(backend.store("laptop")).unwrap();
+    assert_eq!(repo.patches_loaded().count(), 2);
+    let timesheet = repo.timesheet().flatten().unwrap();
+    let tags = timesheet.events().values().next().unwrap().clone();
+    assert_eq!(tags, vec![s!("coding"), s!("work")].into_iter().collect());
+}
+
+fn other_metas_are_synced<B: Backend>(backend: B)
+where
+    <B::Store as Store>::Error: 'static,
+{
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let mut laptop = Repository::from_store(backend.store("laptop")).unwrap();
+    laptop.add_patch(patch1.clone()).unwrap();
+    laptop.save_meta().unwrap();
+
+    let phone = backend.store("phone");
+    let other_metas = phone.get_other_metas().unwrap();
+    assert_eq!(other_metas.keys().collect::<Vec<_>>(), vec!["laptop"]);
+
+    let mut phone = Repository::from_store(phone).unwrap();
+    let report = phone.try_sync_data().unwrap();
+    assert!(report.is_complete());
+    assert_eq!(
+        report.devices["laptop"].new,
+        Some(*patch1.patch_ref()).into_iter().collect()
+    );
+    assert_eq!(phone.patches_loaded().count(), 1);
+}
+
+macro_rules! store_tests {
+    ($name:ident, $backend:expr) => {
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn missing_meta_is_empty() {
+                super::missing_meta_is_empty($backend);
+            }
+
+            #[test]
+            fn meta_round_trips() {
+                super::meta_round_trips($backend);
+            }
+
+            #[test]
+            fn update_meta_sees_saved_meta() {
+                super::update_meta_sees_saved_meta($backend);
+            }
+
+            #[test]
+            fn patch_round_trips() {
+                super::patch_round_trips($backend);
+            }
+
+            #[test]
+            fn content_addressed_patch_round_trips() {
+                super::content_addressed_patch_round_trips($backend);
+            }
+
+            #[test]
+            fn newer_patch_format_is_unsupported() {
+                super::newer_patch_format_is_unsupported($backend);
+            }
+
+            #[test]
+            fn missing_patch_is_an_error() {
+                super::missing_patch_is_an_error($backend);
+            }
+
+            #[test]
+            fn patches_are_not_overwritten() {
+                super::patches_are_not_overwritten($backend);
+            }
+
+            #[test]
+            fn removed_patches_are_gone() {
+                super::removed_patches_are_gone($backend);
+            }
+
+            #[test]
+            fn repository_reloads() {
+                super::repository_reloads($backend);
+            }
+
+            #[test]
+            fn other_metas_are_synced() {
+                super::other_metas_are_synced($backend);
+            }
+        }
+    };
+}
+
+store_tests!(sync_folder, SyncFolderBackend(TempFolder::new()));
+
+store_tests!(memory, MemoryBackend(MemoryStore::new(s!("laptop"))));
+
+#[cfg(feature = "sqlite")]
+store_tests!(sqlite, SqliteBackend(TempFolder::new()));
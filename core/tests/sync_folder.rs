@@ -0,0 +1,457 @@
+use augr_core::{
+    repository::Error,
+    store::{SyncFolderStore, SyncFolderStoreError},
+    Meta, Patch, Repository, Store,
+};
+use chrono::{DateTime, Utc};
+use common::TempFolder;
+use fs2::FileExt;
+use std::{
+    collections::BTreeSet,
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, write, File},
+    thread,
+    time::Duration,
+};
+use uuid::Uuid;
+
+mod common;
+
+macro_rules! dt {
+    ( $dt:expr ) => {{
+        $dt.parse::<DateTime<Utc>>().expect("Valid datetime")
+    }};
+}
+
+macro_rules! s {
+    ($s:expr) => {
+        $s.to_string()
+    };
+}
+
+/// A temporary folder used as a sync folder
+impl TempFolder {
+    /// Load the repository of a device, synchronize it with the other
+    /// devices, and save its meta, like the cli does
+    fn device(&self, device: &str) -> Repository<SyncFolderStore> {
+        let store = SyncFolderStore::new(self.0.clone(), s!(device)).should_init(true);
+        let mut repo = Repository::from_store(store).unwrap();
+        assert!(repo.try_sync_data().unwrap().is_complete());
+        repo.save_meta().unwrap();
+        repo
+    }
+
+    fn patch_exists(&self, patch: &Patch) -> bool {
+        self.0
+            .join("patches")
+            .join(patch.patch_ref().to_string())
+            .with_extension("toml")
+            .exists()
+    }
+}
+
+#[test]
+fn checkpoint_folds_acknowledged_patches() {
+    let folder = TempFolder::new();
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().add_tag(*patch1.patch_ref(), s!("a"), s!("coding"));
+    let patch3 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![s!("lunch")]);
+
+    let mut laptop = folder.device("laptop");
+    laptop.add_patch(patch1.clone()).unwrap();
+    laptop.add_patch(patch2.clone()).unwrap();
+    laptop.save_meta().unwrap();
+
+    // The phone acknowledges the first two patches, but not the third
+    folder.device("phone");
+    let mut laptop = folder.device("laptop");
+    laptop.add_patch(patch3.clone()).unwrap();
+    laptop.save_meta().unwrap();
+
+    let checkpoint = laptop.checkpoint().unwrap().expect("patches to fold");
+    let folded = checkpoint.checkpoint.as_ref().unwrap().patches().clone();
+    let expected: BTreeSet<_> = vec![*patch1.patch_ref(), *patch2.patch_ref()]
+        .into_iter()
+        .collect();
+    assert_eq!(folded, expected);
+
+    laptop.add_patch(checkpoint.clone()).unwrap();
+    laptop.save_meta().unwrap();
+    let meta = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .get_meta()
+        .unwrap();
+    assert!(meta.patches().all(|patch_ref| !folded.contains(patch_ref)));
+
+    // Nothing new has been acknowledged since the checkpoint was made
+    assert_eq!(laptop.checkpoint().unwrap(), None);
+
+    // The phone hasn't seen the checkpoint yet, so the folded patches have to
+    // stay around
+    assert!(laptop.retirable_patches().unwrap().is_empty());
+
+    folder.device("phone");
+    let mut laptop = folder.device("laptop");
+    assert_eq!(laptop.retire_patches().unwrap(), expected);
+    assert!(!folder.patch_exists(&patch1));
+    assert!(!folder.patch_exists(&patch2));
+    assert!(folder.patch_exists(&patch3));
+    assert!(folder.patch_exists(&checkpoint));
+
+    // Every device, including a new one, can still load the whole timesheet
+    let expected_events = laptop.timesheet().flatten().unwrap().events();
+    assert_eq!(expected_events.len(), 2);
+    for device in ["laptop", "phone", "desktop"].iter() {
+        let repo = folder.device(device);
+        assert_eq!(
+            repo.timesheet().flatten().unwrap().events(),
+            expected_events
+        );
+    }
+
+    // Patches can still build on the patches that were folded
+    let mut phone = folder.device("phone");
+    phone
+        .add_patch(Patch::new().remove_tag(*patch2.patch_ref(), s!("a"), s!("coding")))
+        .unwrap();
+    phone.save_meta().unwrap();
+    let desktop = folder.device("desktop");
+    let events = desktop.timesheet().flatten().unwrap().events();
+    assert_eq!(
+        events[&dt!("2019-07-23T12:00:00Z")],
+        vec![s!("work")].into_iter().collect::<BTreeSet<_>>()
+    );
+}
+
+#[test]
+fn meta_only_lists_heads() {
+    let folder = TempFolder::new();
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().add_tag(*patch1.patch_ref(), s!("a"), s!("coding"));
+    let patch3 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![s!("lunch")]);
+
+    let mut laptop = folder.device("laptop");
+    for patch in [&patch1, &patch2, &patch3].iter() {
+        laptop.add_patch((*patch).clone()).unwrap();
+    }
+    laptop.save_meta().unwrap();
+
+    let meta = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .get_meta()
+        .unwrap();
+    let heads: BTreeSet<_> = meta.patches().cloned().collect();
+    let expected: BTreeSet<_> = vec![*patch2.patch_ref(), *patch3.patch_ref()]
+        .into_iter()
+        .collect();
+    assert_eq!(heads, expected);
+
+    // The ancestors of the heads are still loaded
+    let phone = folder.device("phone");
+    assert_eq!(phone.patches_loaded().count(), 3);
+    assert_eq!(phone.timesheet().flatten().unwrap().events().len(), 2);
+}
+
+#[test]
+fn meta_listing_every_patch_is_readable() {
+    let folder = TempFolder::new();
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().add_tag(*patch1.patch_ref(), s!("a"), s!("coding"));
+
+    // Metas written by older versions of augr list every patch
+    let mut store = SyncFolderStore::new(folder.0.clone(), s!("laptop")).should_init(true);
+    let mut meta = Meta::new();
+    for patch in [&patch1, &patch2].iter() {
+        store.add_patch(patch).unwrap();
+        meta.add_patch(*patch.patch_ref());
+    }
+    store.save_meta(&meta).unwrap();
+
+    let laptop = folder.device("laptop");
+    assert_eq!(laptop.patches_loaded().count(), 2);
+    let meta = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .get_meta()
+        .unwrap();
+    assert_eq!(meta.patches().collect::<Vec<_>>(), vec![patch2.patch_ref()]);
+}
+
+#[test]
+fn leftover_temporary_files_are_ignored() {
+    let folder = TempFolder::new();
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let mut laptop = folder.device("laptop");
+    laptop.add_patch(patch1).unwrap();
+    laptop.save_meta().unwrap();
+
+    // A device that crashed while writing leaves partial temporary files
+    // behind, which must not be mistaken for metas or patches
+    write(
+        folder
+            .0
+            .join("meta")
+            .join(format!(".phone.toml.{}.tmp", Uuid::new_v4())),
+        "patches = [\"c10350e8",
+    )
+    .unwrap();
+    write(
+        folder
+            .0
+            .join("patches")
+            .join(format!(".{}.toml.{}.tmp", Uuid::new_v4(), Uuid::new_v4())),
+        "id = \"c10350e8",
+    )
+    .unwrap();
+
+    let desktop = folder.device("desktop");
+    assert_eq!(desktop.patches_loaded().count(), 1);
+    assert_eq!(desktop.timesheet().flatten().unwrap().events().len(), 1);
+}
+
+#[test]
+fn syncthing_artefacts_in_meta_folder() {
+    let folder = TempFolder::new();
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![s!("lunch")]);
+    let patch3 = Patch::new().create_event(s!("c"), dt!("2019-07-23T14:00:00Z"), vec![s!("work")]);
+    let mut laptop = folder.device("laptop");
+    laptop.add_patch(patch1.clone()).unwrap();
+    laptop.add_patch(patch2.clone()).unwrap();
+    laptop.add_patch(patch3.clone()).unwrap();
+
+    // Syncthing keeps both versions of a meta that changed on two devices at
+    // once, and leaves temporary files around while syncing
+    let meta_folder = folder.0.join("meta");
+    write(
+        meta_folder.join("laptop.toml"),
+        format!("patches = [\"{}\"]", patch1.patch_ref()),
+    )
+    .unwrap();
+    write(
+        meta_folder.join("laptop.sync-conflict-20190723-150000-ABCDEFG.toml"),
+        format!("patches = [\"{}\"]", patch2.patch_ref()),
+    )
+    .unwrap();
+    write(
+        meta_folder.join("phone.sync-conflict-20190723-150000-ABCDEFG.toml"),
+        format!("patches = [\"{}\"]", patch3.patch_ref()),
+    )
+    .unwrap();
+    write(
+        meta_folder.join(".syncthing.phone.toml.tmp"),
+        "patches = [\"c1",
+    )
+    .unwrap();
+    write(meta_folder.join("laptop.toml~"), "patches = [\"c1").unwrap();
+
+    let report = SyncFolderStore::new(folder.0.clone(), s!("desktop"))
+        .meta_folder_report()
+        .unwrap();
+    let conflicts: Vec<_> = report
+        .conflict_copies
+        .iter()
+        .map(|copy| copy.device_id.as_str())
+        .collect();
+    assert_eq!(conflicts, vec!["laptop", "phone"]);
+    assert_eq!(report.ignored.len(), 2);
+
+    // Every patch listed in a conflict copy is loaded
+    let desktop = folder.device("desktop");
+    assert_eq!(desktop.patches_loaded().count(), 3);
+
+    // The conflict copy of the laptop's own meta is merged into it
+    let meta = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .get_meta()
+        .unwrap();
+    let patches: BTreeSet<_> = meta.patches().cloned().collect();
+    let expected: BTreeSet<_> = vec![*patch1.patch_ref(), *patch2.patch_ref()]
+        .into_iter()
+        .collect();
+    assert_eq!(patches, expected);
+
+    // Once the laptop has saved its meta, its conflict copy has been merged
+    // and is removed. The phone's copy is left for the phone.
+    folder.device("laptop");
+    let report = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .meta_folder_report()
+        .unwrap();
+    let conflicts: Vec<_> = report
+        .conflict_copies
+        .iter()
+        .map(|copy| copy.device_id.as_str())
+        .collect();
+    assert_eq!(conflicts, vec!["phone"]);
+    let laptop = folder.device("laptop");
+    assert_eq!(laptop.patches_loaded().count(), 3);
+}
+
+#[test]
+fn conflict_copy_with_unsaved_patches_is_kept() {
+    let folder = TempFolder::new();
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![s!("lunch")]);
+    let copy = folder
+        .0
+        .join("meta")
+        .join("laptop.sync-conflict-20190723-150000-ABCDEFG.toml");
+    create_dir_all(folder.0.join("meta")).unwrap();
+    write(&copy, format!("patches = [\"{}\"]", patch2.patch_ref())).unwrap();
+
+    // Saving a meta that leaves out the copy's patches would lose them
+    let mut store = SyncFolderStore::new(folder.0.clone(), s!("laptop")).should_init(true);
+    let mut meta = Meta::new();
+    meta.add_patch(*patch1.patch_ref());
+    store.save_meta(&meta).unwrap();
+    assert!(copy.exists());
+
+    meta.add_patch(*patch2.patch_ref());
+    store.save_meta(&meta).unwrap();
+    assert!(!copy.exists());
+}
+
+#[test]
+fn concurrent_saves_keep_each_others_patches() {
+    let folder = TempFolder::new();
+    folder.device("laptop");
+
+    // Both processes load the repository before either adds a patch
+    let mut first = folder.device("laptop");
+    let mut second = folder.device("laptop");
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new().create_event(s!("b"), dt!("2019-07-23T13:00:00Z"), vec![s!("lunch")]);
+    first.add_patch(patch1.clone()).unwrap();
+    first.save_meta().unwrap();
+    second.add_patch(patch2.clone()).unwrap();
+    second.save_meta().unwrap();
+
+    let meta = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .get_meta()
+        .unwrap();
+    let patches: BTreeSet<_> = meta.patches().cloned().collect();
+    let expected: BTreeSet<_> = vec![*patch1.patch_ref(), *patch2.patch_ref()]
+        .into_iter()
+        .collect();
+    assert_eq!(patches, expected);
+}
+
+#[test]
+fn concurrent_processes_on_one_device() {
+    let folder = TempFolder::new();
+    folder.device("laptop");
+
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let root = folder.0.clone();
+            thread::spawn(move || {
+                let store = SyncFolderStore::new(root, s!("laptop")).should_init(true);
+                let mut repo = Repository::from_store(store).unwrap();
+                let patch = Patch::new().create_event(
+                    format!("event{}", i),
+                    dt!("2019-07-23T12:00:00Z") + chrono::Duration::hours(i),
+                    vec![s!("work")],
+                );
+                repo.add_patch(patch).unwrap();
+                repo.save_meta().unwrap();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let laptop = folder.device("laptop");
+    assert_eq!(laptop.patches_loaded().count(), 8);
+}
+
+#[test]
+fn held_lock_times_out() {
+    let folder = TempFolder::new();
+    let lock_path = std::env::temp_dir().join(format!("augr-test-{}.lock", Uuid::new_v4()));
+    let lock = File::create(&lock_path).unwrap();
+    lock.lock_exclusive().unwrap();
+
+    let store = SyncFolderStore::new(folder.0.clone(), s!("laptop"))
+        .should_init(true)
+        .lock_timeout(Duration::from_millis(100))
+        .lock_file(lock_path.clone());
+    let mut laptop = Repository::from_store(store).unwrap();
+    match laptop.save_meta() {
+        Err(Error::SaveMeta {
+            source: SyncFolderStoreError::LockTimeout { .. },
+        }) => {}
+        other => panic!("expected lock timeout, got {:?}", other),
+    }
+
+    lock.unlock().unwrap();
+    laptop.save_meta().unwrap();
+    let _ = remove_file(lock_path);
+}
+
+#[test]
+fn lock_file_is_not_synced() {
+    let folder = TempFolder::new();
+    folder.device("laptop");
+
+    let mut names: Vec<_> = read_dir(&folder.0)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["meta"]);
+}
+
+#[test]
+fn tampered_patch_is_rejected() {
+    let folder = TempFolder::new();
+
+    let patch = Patch::new()
+        .create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")])
+        .content_addressed();
+    let mut store = SyncFolderStore::new(folder.0.clone(), s!("laptop")).should_init(true);
+    store.add_patch(&patch).unwrap();
+
+    let path = folder
+        .0
+        .join("patches")
+        .join(patch.patch_ref().to_string())
+        .with_extension("toml");
+    let contents = read_to_string(&path).unwrap();
+    write(&path, contents.replace("work", "play")).unwrap();
+
+    match store.get_patch(patch.patch_ref()) {
+        Err(SyncFolderStoreError::CorruptPatch { patch_ref }) => {
+            assert_eq!(patch_ref, *patch.patch_ref())
+        }
+        other => panic!("expected corrupt patch, got {:?}", other),
+    }
+}
+
+#[test]
+fn random_and_content_addressed_patches_mix() {
+    let folder = TempFolder::new();
+
+    let patch1 = Patch::new().create_event(s!("a"), dt!("2019-07-23T12:00:00Z"), vec![s!("work")]);
+    let patch2 = Patch::new()
+        .add_tag(*patch1.patch_ref(), s!("a"), s!("coding"))
+        .content_addressed();
+    let patch3 = Patch::new().add_tag(*patch2.patch_ref(), s!("a"), s!("rust"));
+
+    let mut laptop = folder.device("laptop");
+    laptop.add_patch(patch1).unwrap();
+    laptop.add_patch(patch2).unwrap();
+    laptop.add_patch(patch3).unwrap();
+    laptop.save_meta().unwrap();
+
+    let phone = folder.device("phone");
+    assert_eq!(phone.patches_loaded().count(), 3);
+    let timesheet = phone.timesheet().flatten().unwrap();
+    let tags = timesheet.events().values().next().unwrap().clone();
+    assert_eq!(
+        tags,
+        vec![s!("coding"), s!("rust"), s!("work")]
+            .into_iter()
+            .collect()
+    );
+}